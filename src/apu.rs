@@ -4,9 +4,40 @@ use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 
+use crate::cdl::cdl;
+
 mod dmc;
 use self::dmc::{init_DMC, DMCEvent, DMCWave};
 use dmc::DMCRegister;
+mod mmc5;
+use self::mmc5::Mmc5Audio;
+mod namco163;
+mod sunsoft5b;
+mod vrc6;
+use self::namco163::Namco163Audio;
+use self::sunsoft5b::Sunsoft5bAudio;
+use self::vrc6::Vrc6Audio;
+
+// カートリッジの拡張音源
+// レジスタはマッパーと同じところにあるので、$4020~への書き込みは両方に送る
+pub trait ExpansionAudio {
+    fn write(&mut self, addr: u16, value: u8);
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+    // 1/4フレーム(240Hz)ごとに呼ばれる
+    fn quarter_frame(&mut self) {}
+}
+
+fn create_expansion(sdl_context: &sdl2::Sdl, mapper: u8) -> Option<Box<dyn ExpansionAudio>> {
+    match mapper {
+        5 => Some(Box::new(Mmc5Audio::new(sdl_context))),
+        19 => Some(Box::new(Namco163Audio::new(sdl_context))),
+        24 | 26 => Some(Box::new(Vrc6Audio::new(sdl_context, mapper))),
+        69 => Some(Box::new(Sunsoft5bAudio::new(sdl_context))),
+        _ => None,
+    }
+}
 
 pub struct NesAPU {
     ch1_register: Ch1Register,
@@ -44,6 +75,8 @@ pub struct NesAPU {
     dmc_sender: Sender<DMCEvent>,
     dmc_receiver: Receiver<ChannelEvent>,
     dmc_sample_byte_count: u32,
+
+    expansion: Option<Box<dyn ExpansionAudio>>,
 }
 
 const NES_CPU_CLOCK: f32 = 1_789_772.5; //1.78MHz
@@ -91,9 +124,25 @@ impl NesAPU {
             dmc_sender: dmc_sender,
             dmc_receiver: dmc_receiver,
             dmc_sample_byte_count: 0,
+
+            expansion: None,
         }
     }
 
+    pub fn set_expansion(&mut self, sdl_context: &sdl2::Sdl, mapper: u8) {
+        self.expansion = create_expansion(sdl_context, mapper);
+    }
+
+    pub fn write_expansion(&mut self, addr: u16, value: u8) {
+        if let Some(expansion) = self.expansion.as_mut() {
+            expansion.write(addr, value);
+        }
+    }
+
+    pub fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        self.expansion.as_mut().and_then(|e| e.read(addr))
+    }
+
     pub fn write_1ch(&mut self, addr: u16, value: u8) {
         self.ch1_register.write(addr, value);
 
@@ -318,6 +367,19 @@ impl NesAPU {
         //最後のレジスタに書かれているときはリセット
         if addr == 0x4013 {
             self.dmc_sender.send(DMCEvent::Reset()).unwrap();
+            self.log_dmc_sample();
+        }
+    }
+
+    // サンプルの読み込みは音声のスレッドでしているので、CDLにはCPU側で鳴らし始めたときに範囲ごと記録する
+    fn log_dmc_sample(&self) {
+        let start = self.dmc_register.sample_start_addr as u16 * 0x40 + 0xC000;
+        let length = self.dmc_register.sample_byte_count as u16 * 0x10 + 1;
+        for i in 0..length {
+            // $FFFFの次は$8000
+            let addr = start.wrapping_add(i);
+            let addr = if addr < start { addr | 0x8000 } else { addr };
+            unsafe { cdl().log_pcm(addr) };
         }
     }
 
@@ -375,6 +437,9 @@ impl NesAPU {
                 self.status.contains(StatusRegister::ENABLE_DMC),
             ))
             .unwrap();
+        if self.status.contains(StatusRegister::ENABLE_DMC) {
+            self.log_dmc_sample();
+        }
     }
 
     pub fn write_frame_counter(&mut self, value: u8) {
@@ -454,6 +519,11 @@ impl NesAPU {
 
             self.receive_events();
 
+            // 拡張音源はフレームカウンタのモードに関係なく240Hz
+            if let Some(expansion) = self.expansion.as_mut() {
+                expansion.quarter_frame();
+            }
+
             match self.frame_counter.mode() {
                 4 => {
                     // - - - f   60Hz
@@ -5,7 +5,7 @@ use std::{
 
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
-use crate::MAPPER;
+use crate::mapper::mapper;
 
 use super::{ChannelEvent, NES_CPU_CLOCK};
 
@@ -136,7 +136,7 @@ impl AudioCallback for DMCWave {
                 if self.org_sample_byte_count & 0x0007 == 0 {
                     if self.org_sample_byte_count != 0 {
                         unsafe {
-                            self.data = MAPPER.read_prg_rom(self.org_sample_start_addr);
+                            self.data = mapper().read_prg_rom(self.org_sample_start_addr);
                         };
                         if self.org_sample_start_addr == 0xFFFF {
                             self.org_sample_start_addr = 0x8000;
@@ -0,0 +1,193 @@
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+use super::{
+    init_square, Ch1Register, ChannelEvent, EnvelopeData, ExpansionAudio, LengthCounterData,
+    SquareEvent, SquareNote, SquareWave,
+};
+
+// MMC5の拡張音源 矩形波2つとPCM
+// 矩形波は本体と同じだがスイープがなく、エンベロープと長さカウンタは240Hzで動く
+pub struct Mmc5Audio {
+    pulse_registers: [Ch1Register; 2],
+    #[allow(dead_code)] //持っていないと音が止まる
+    pulse_devices: [AudioDevice<SquareWave>; 2],
+    pulse_senders: [Sender<SquareEvent>; 2],
+    pulse_receivers: [Receiver<ChannelEvent>; 2],
+    pulse_length_counters: [u32; 2],
+
+    #[allow(dead_code)]
+    pcm_device: AudioDevice<PcmWave>,
+    pcm_sender: Sender<u8>,
+    pcm_read_mode: bool, //$5010 読み込みモードは対応していない
+}
+
+impl Mmc5Audio {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+        let (device1, sender1, receiver1) = init_square(sdl_context);
+        let (device2, sender2, receiver2) = init_square(sdl_context);
+        let (pcm_device, pcm_sender) = init_pcm(sdl_context);
+        Mmc5Audio {
+            pulse_registers: [Ch1Register::new(), Ch1Register::new()],
+            pulse_devices: [device1, device2],
+            pulse_senders: [sender1, sender2],
+            pulse_receivers: [receiver1, receiver2],
+            pulse_length_counters: [0, 0],
+            pcm_device,
+            pcm_sender,
+            pcm_read_mode: false,
+        }
+    }
+
+    // $5000~$5003, $5004~$5007 本体の$4000~$4003と同じ並び
+    fn write_pulse(&mut self, ch: usize, addr: u16) {
+        let register = &self.pulse_registers[ch];
+        let sender = &self.pulse_senders[ch];
+
+        if addr == 0x4000 {
+            sender
+                .send(SquareEvent::Note(SquareNote {
+                    duty: register.duty,
+                }))
+                .unwrap();
+            sender
+                .send(SquareEvent::Envelope(EnvelopeData::new(
+                    register.volume,
+                    register.envelope_flag,
+                    !register.key_off_counter_flag,
+                )))
+                .unwrap();
+        }
+
+        if addr == 0x4000 || addr == 0x4003 {
+            sender
+                .send(SquareEvent::LengthCounter(LengthCounterData::new(
+                    register.key_off_count,
+                    register.key_off_counter_flag,
+                )))
+                .unwrap();
+        }
+
+        if addr == 0x4002 || addr == 0x4003 {
+            sender
+                .send(SquareEvent::ChangeFrequency(register.frequency))
+                .unwrap();
+        }
+
+        if addr == 0x4003 {
+            sender.send(SquareEvent::Reset()).unwrap();
+        }
+    }
+
+    fn receive_events(&mut self) {
+        for ch in 0..2 {
+            loop {
+                let res = self.pulse_receivers[ch].recv_timeout(Duration::from_millis(0));
+                match res {
+                    Ok(ChannelEvent::LengthCounter(counter)) => {
+                        self.pulse_length_counters[ch] = counter;
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5000 | 0x5002 | 0x5003 | 0x5004 | 0x5006 | 0x5007 => {
+                let ch = (addr as usize - 0x5000) / 4;
+                let addr = 0x4000 + (addr & 0x03);
+                self.pulse_registers[ch].write(addr, value);
+                self.write_pulse(ch, addr);
+            }
+            0x5010 => self.pcm_read_mode = value & 0x01 != 0,
+            // 書き込みモードでは0は無視される
+            0x5011 if !self.pcm_read_mode && value != 0 => {
+                self.pcm_sender.send(value).unwrap();
+            }
+            0x5015 => {
+                self.pulse_senders[0]
+                    .send(SquareEvent::Enable(value & 0x01 != 0))
+                    .unwrap();
+                self.pulse_senders[1]
+                    .send(SquareEvent::Enable(value & 0x02 != 0))
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        if addr != 0x5015 {
+            return None;
+        }
+        self.receive_events();
+        let mut res = 0;
+        for ch in 0..2 {
+            if self.pulse_length_counters[ch] != 0 {
+                res |= 1 << ch;
+            }
+        }
+        Some(res)
+    }
+
+    fn quarter_frame(&mut self) {
+        self.receive_events();
+        for sender in self.pulse_senders.iter() {
+            sender.send(SquareEvent::EnvelopeTick()).unwrap();
+            sender.send(SquareEvent::LengthCounterTick()).unwrap();
+        }
+    }
+}
+
+// $5011に書かれた値を出す 0x80が真ん中
+pub struct PcmWave {
+    receiver: Receiver<u8>,
+    level: f32,
+}
+
+impl AudioCallback for PcmWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        for x in out.iter_mut() {
+            loop {
+                let res = self.receiver.recv_timeout(Duration::from_millis(0));
+                match res {
+                    Ok(value) => self.level = (value as f32 - 128.0) / 128.0,
+                    Err(_) => break,
+                }
+            }
+            *x = self.level;
+        }
+    }
+}
+
+fn init_pcm(sdl_context: &sdl2::Sdl) -> (AudioDevice<PcmWave>, Sender<u8>) {
+    let audio_subsystem = sdl_context.audio().unwrap();
+
+    let (sender, receiver) = channel::<u8>();
+
+    let desire_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: None,
+    };
+
+    let device = audio_subsystem
+        .open_playback(None, &desire_spec, |_spec| PcmWave {
+            receiver,
+            level: 0.0,
+        })
+        .unwrap();
+
+    device.resume();
+    (device, sender)
+}
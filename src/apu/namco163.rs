@@ -0,0 +1,134 @@
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+use super::{ExpansionAudio, NES_CPU_CLOCK};
+use crate::mapper::N163_RAM;
+
+// Namco 163の拡張音源 内部RAMの4bitの波形を最大8チャンネルで鳴らす
+// 内部RAMはマッパーと共有(N163_RAM) 位相もそこに書き戻すので、$4800から読むと進んでいるのが見える
+pub struct Namco163Audio {
+    #[allow(dead_code)] //持っていないと音が止まる
+    device: AudioDevice<Namco163Wave>,
+    sender: Sender<bool>,
+}
+
+impl Namco163Audio {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+        let (device, sender) = init_namco163(sdl_context);
+        Namco163Audio { device, sender }
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn write(&mut self, addr: u16, value: u8) {
+        // $E000のbit6で音源を止める
+        if let 0xE000..=0xE7FF = addr {
+            self.sender.send(value & 0x40 == 0).unwrap();
+        }
+    }
+}
+
+// 1チャンネルの更新にかかるCPUサイクル
+const CYCLES_PER_CHANNEL: f32 = 15.0;
+
+struct Namco163Wave {
+    freq: f32,
+    receiver: Receiver<bool>,
+    enabled: bool,
+
+    // 15サイクルごとに1チャンネルずつ更新して、そのチャンネルの音だけを出す
+    cycles: f32,
+    current: usize, //今更新しているチャンネル 0が$78~$7F
+    output: f32,
+}
+
+// $7Fのbit4~6 + 1
+fn channel_count(ram: &[u8; 128]) -> usize {
+    ((ram[0x7F] >> 4) & 0x07) as usize + 1
+}
+
+// チャンネルの位相を進めて、今の波形の値を返す
+fn update_channel(ram: &mut [u8; 128], ch: usize) -> f32 {
+    let base = 0x78 - ch * 8;
+    let freq = ram[base] as u32 | (ram[base + 2] as u32) << 8 | (ram[base + 4] as u32 & 0x03) << 16;
+    let length = 256 - (ram[base + 4] & 0xFC) as u32;
+    let mut phase =
+        ram[base + 1] as u32 | (ram[base + 3] as u32) << 8 | (ram[base + 5] as u32) << 16;
+
+    phase = (phase + freq) % (length << 16);
+    ram[base + 1] = phase as u8;
+    ram[base + 3] = (phase >> 8) as u8;
+    ram[base + 5] = (phase >> 16) as u8;
+
+    // 4bitのサンプルが1byteに2つ 下位4bitが先
+    let index = ((phase >> 16) + ram[base + 6] as u32) & 0xFF;
+    let sample = (ram[index as usize >> 1] >> ((index & 0x01) * 4)) & 0x0F;
+    let volume = ram[base + 7] & 0x0F;
+    // 1チャンネルだけのときに本体の矩形波と同じくらいの大きさ
+    (sample as f32 - 8.0) * volume as f32 / 120.0
+}
+
+impl AudioCallback for Namco163Wave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        // CPUのスレッドとはここで1回だけ取り合う
+        let mut ram = N163_RAM.lock().unwrap();
+        for x in out.iter_mut() {
+            while let Ok(b) = self.receiver.recv_timeout(Duration::from_millis(0)) {
+                self.enabled = b;
+            }
+
+            // 1サンプルの間に切り替わったチャンネルの音を平均する
+            // チャンネルが多いほど1つあたりの音は小さく、切り替えの音も聞こえるようになる
+            self.cycles += NES_CPU_CLOCK / self.freq;
+            let mut sum = 0.0;
+            let mut count = 0;
+            while self.cycles >= CYCLES_PER_CHANNEL {
+                self.cycles -= CYCLES_PER_CHANNEL;
+                let channels = channel_count(&ram);
+                self.current = (self.current + 1) % channels;
+                self.output = update_channel(&mut ram, self.current);
+                sum += self.output;
+                count += 1;
+            }
+            let output = if count == 0 {
+                self.output
+            } else {
+                sum / count as f32
+            };
+
+            *x = if self.enabled { output } else { 0.0 };
+        }
+    }
+}
+
+fn init_namco163(sdl_context: &sdl2::Sdl) -> (AudioDevice<Namco163Wave>, Sender<bool>) {
+    let audio_subsystem = sdl_context.audio().unwrap();
+
+    let (sender, receiver) = channel::<bool>();
+
+    let desire_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: None,
+    };
+
+    let device = audio_subsystem
+        .open_playback(None, &desire_spec, |spec| Namco163Wave {
+            freq: spec.freq as f32,
+            receiver,
+            enabled: true,
+            cycles: 0.0,
+            current: 0,
+            output: 0.0,
+        })
+        .unwrap();
+
+    device.resume();
+    (device, sender)
+}
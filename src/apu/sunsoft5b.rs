@@ -0,0 +1,228 @@
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+use super::{ExpansionAudio, NES_CPU_CLOCK};
+
+// Sunsoft 5Bの拡張音源 (AY-3-8910とほぼ同じ)
+// 矩形波3つにノイズとエンベロープを混ぜられる $C000にレジスタ番号、$E000に値を書く
+pub struct Sunsoft5bAudio {
+    #[allow(dead_code)] //持っていないと音が止まる
+    device: AudioDevice<Sunsoft5bWave>,
+    sender: Sender<(u8, u8)>,
+    register: u8,
+}
+
+impl Sunsoft5bAudio {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+        let (device, sender) = init_sunsoft5b(sdl_context);
+        Sunsoft5bAudio {
+            device,
+            sender,
+            register: 0,
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xC000..=0xDFFF => self.register = value & 0x0F,
+            0xE000..=0xFFFF => self.sender.send((self.register, value)).unwrap(),
+            _ => {}
+        }
+    }
+}
+
+// 音量は1段階3dB (エンベロープは5bitで1.5dB)
+fn amplitude(level: u8) -> f32 {
+    if level == 0 {
+        return 0.0;
+    }
+    10f32.powf(-((31 - level) as f32) * 1.5 / 20.0)
+}
+
+struct Envelope5b {
+    period: u16,
+    step: u8, //0~31
+    attack: bool,
+    alternate: bool,
+    hold: bool,
+    holding: bool,
+    phase: f32,
+}
+
+impl Envelope5b {
+    fn new() -> Self {
+        Envelope5b {
+            period: 0,
+            step: 0,
+            attack: false,
+            alternate: false,
+            hold: true,
+            holding: true,
+            phase: 0.0,
+        }
+    }
+
+    // $0D CAHH
+    fn set_shape(&mut self, value: u8) {
+        let cont = value & 0x08 != 0;
+        self.attack = value & 0x04 != 0;
+        // 続けないときは1周したら0で止まる
+        self.alternate = if cont { value & 0x02 != 0 } else { self.attack };
+        self.hold = !cont || value & 0x01 != 0;
+        self.holding = false;
+        self.step = 0;
+        self.phase = 0.0;
+    }
+
+    fn level(&self) -> u8 {
+        if self.attack {
+            self.step
+        } else {
+            31 - self.step
+        }
+    }
+
+    fn tick(&mut self, freq: f32) {
+        if self.holding {
+            return;
+        }
+        self.phase += NES_CPU_CLOCK / (16.0 * self.period.max(1) as f32) / freq;
+        while self.phase >= 1.0 && !self.holding {
+            self.phase -= 1.0;
+            if self.step < 31 {
+                self.step += 1;
+                continue;
+            }
+            // 1周した
+            if self.alternate {
+                self.attack = !self.attack;
+            }
+            if self.hold {
+                self.holding = true;
+            } else {
+                self.step = 0;
+            }
+        }
+    }
+}
+
+struct Sunsoft5bWave {
+    freq: f32,
+    receiver: Receiver<(u8, u8)>,
+
+    tone_period: [u16; 3],
+    tone_phase: [f32; 3],
+    noise_period: u8,
+    noise_phase: f32,
+    noise: u32, //17bitのLFSR
+    mixer: u8,  //$07 0で有効
+    volume: [u8; 3],
+    envelope: Envelope5b,
+}
+
+impl Sunsoft5bWave {
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            0x0..=0x5 => {
+                let ch = reg as usize / 2;
+                self.tone_period[ch] = if reg & 0x01 == 0 {
+                    (self.tone_period[ch] & 0x0F00) | value as u16
+                } else {
+                    (self.tone_period[ch] & 0x00FF) | (value as u16 & 0x0F) << 8
+                };
+            }
+            0x6 => self.noise_period = value & 0x1F,
+            0x7 => self.mixer = value,
+            0x8..=0xA => self.volume[reg as usize - 0x8] = value & 0x1F,
+            0xB => self.envelope.period = (self.envelope.period & 0xFF00) | value as u16,
+            0xC => self.envelope.period = (self.envelope.period & 0x00FF) | (value as u16) << 8,
+            0xD => self.envelope.set_shape(value),
+            _ => {}
+        }
+    }
+}
+
+impl AudioCallback for Sunsoft5bWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        for x in out.iter_mut() {
+            loop {
+                let res = self.receiver.recv_timeout(Duration::from_millis(0));
+                match res {
+                    Ok((reg, value)) => self.write(reg, value),
+                    Err(_) => break,
+                }
+            }
+
+            self.noise_phase +=
+                NES_CPU_CLOCK / (32.0 * self.noise_period.max(1) as f32) / self.freq;
+            while self.noise_phase >= 1.0 {
+                self.noise_phase -= 1.0;
+                let bit = (self.noise ^ (self.noise >> 3)) & 0x01;
+                self.noise = (self.noise >> 1) | bit << 16;
+            }
+            let noise = self.noise & 0x01 != 0;
+            self.envelope.tick(self.freq);
+
+            let mut sample = 0.0;
+            for ch in 0..3 {
+                let period = self.tone_period[ch].max(1) as f32;
+                self.tone_phase[ch] =
+                    (self.tone_phase[ch] + NES_CPU_CLOCK / (32.0 * period) / self.freq) % 1.0;
+                let tone = self.tone_phase[ch] < 0.5;
+
+                // 無効にしたものは常に1として扱う
+                let tone_on = tone || self.mixer & (0x01 << ch) != 0;
+                let noise_on = noise || self.mixer & (0x08 << ch) != 0;
+
+                let level = if self.volume[ch] & 0x10 != 0 {
+                    self.envelope.level()
+                } else if self.volume[ch] & 0x0F == 0 {
+                    0
+                } else {
+                    (self.volume[ch] & 0x0F) * 2 + 1
+                };
+                let amp = amplitude(level);
+                sample += if tone_on && noise_on { amp } else { -amp };
+            }
+            *x = sample;
+        }
+    }
+}
+
+fn init_sunsoft5b(sdl_context: &sdl2::Sdl) -> (AudioDevice<Sunsoft5bWave>, Sender<(u8, u8)>) {
+    let audio_subsystem = sdl_context.audio().unwrap();
+
+    let (sender, receiver) = channel::<(u8, u8)>();
+
+    let desire_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: None,
+    };
+
+    let device = audio_subsystem
+        .open_playback(None, &desire_spec, |spec| Sunsoft5bWave {
+            freq: spec.freq as f32,
+            receiver,
+            tone_period: [0; 3],
+            tone_phase: [0.0; 3],
+            noise_period: 0,
+            noise_phase: 0.0,
+            noise: 1,
+            mixer: 0xFF,
+            volume: [0; 3],
+            envelope: Envelope5b::new(),
+        })
+        .unwrap();
+
+    device.resume();
+    (device, sender)
+}
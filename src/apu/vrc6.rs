@@ -0,0 +1,213 @@
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+use super::{ExpansionAudio, NES_CPU_CLOCK};
+
+// VRC6の拡張音源 矩形波2つとノコギリ波
+// VRC6のDACは矩形波(0~15)2つとノコギリ波(0~31)を同じ重みで足している
+// 矩形波の15を本体の矩形波の15と同じ±1にして、ノコギリ波の31はその倍の振れ幅(±31/15)にする
+pub struct Vrc6Audio {
+    #[allow(dead_code)] //持っていないと音が止まる
+    device: AudioDevice<Vrc6Wave>,
+    sender: Sender<(u16, u8)>,
+    swap_a0_a1: bool, //マッパー26
+}
+
+impl Vrc6Audio {
+    pub fn new(sdl_context: &sdl2::Sdl, mapper: u8) -> Self {
+        let (device, sender) = init_vrc6(sdl_context);
+        Vrc6Audio {
+            device,
+            sender,
+            swap_a0_a1: mapper == 26,
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn write(&mut self, addr: u16, value: u8) {
+        let addr = if self.swap_a0_a1 {
+            (addr & 0xF000) | (addr & 0x01) << 1 | (addr & 0x02) >> 1
+        } else {
+            addr & 0xF003
+        };
+        if let 0x9000..=0x9003 | 0xA000..=0xA002 | 0xB000..=0xB002 = addr {
+            self.sender.send((addr, value)).unwrap();
+        }
+    }
+}
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    constant: bool, //デューティを無視してずっと鳴らす
+    period: u16,
+    enabled: bool,
+    phase: f32,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            constant: false,
+            period: 0,
+            enabled: false,
+            phase: 0.0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.volume = value & 0x0F;
+                self.duty = (value >> 4) & 0x07;
+                self.constant = value & 0x80 != 0;
+            }
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (value as u16 & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.phase = 0.0;
+                }
+            }
+        }
+    }
+
+    fn sample(&mut self, freq: f32) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        // 16ステップのうちduty+1ステップだけ高い
+        let step = (self.phase * 16.0) as u8;
+        let high = self.constant || step <= self.duty;
+        self.phase =
+            (self.phase + NES_CPU_CLOCK / (16.0 * (self.period as f32 + 1.0)) / freq) % 1.0;
+
+        let volume = self.volume as f32 / 15.0;
+        if high {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    phase: f32,
+}
+
+impl Vrc6Saw {
+    fn new() -> Self {
+        Vrc6Saw {
+            rate: 0,
+            period: 0,
+            enabled: false,
+            phase: 0.0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (value as u16 & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.phase = 0.0;
+                }
+            }
+        }
+    }
+
+    fn sample(&mut self, freq: f32) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        // 2クロックごとにアキュムレータにrateを足し、7回目で0に戻る
+        let step = (self.phase * 7.0) as u32;
+        let accumulator = (self.rate as u32 * step) & 0xFF;
+        self.phase =
+            (self.phase + NES_CPU_CLOCK / (14.0 * (self.period as f32 + 1.0)) / freq) % 1.0;
+
+        // 上位5bitが出力 矩形波と同じく1段階が2/15
+        ((accumulator >> 3) as f32 - 15.5) * 2.0 / 15.0
+    }
+}
+
+struct Vrc6Wave {
+    freq: f32,
+    receiver: Receiver<(u16, u8)>,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    halt: bool, //$9003 bit0
+}
+
+impl AudioCallback for Vrc6Wave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        for x in out.iter_mut() {
+            loop {
+                let res = self.receiver.recv_timeout(Duration::from_millis(0));
+                match res {
+                    Ok((0x9003, value)) => self.halt = value & 0x01 != 0,
+                    Ok((addr, value)) => {
+                        let reg = addr & 0x03;
+                        match addr & 0xF000 {
+                            0x9000 => self.pulse1.write(reg, value),
+                            0xA000 => self.pulse2.write(reg, value),
+                            _ => self.saw.write(reg, value),
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            *x = if self.halt {
+                0.0
+            } else {
+                self.pulse1.sample(self.freq)
+                    + self.pulse2.sample(self.freq)
+                    + self.saw.sample(self.freq)
+            };
+        }
+    }
+}
+
+fn init_vrc6(sdl_context: &sdl2::Sdl) -> (AudioDevice<Vrc6Wave>, Sender<(u16, u8)>) {
+    let audio_subsystem = sdl_context.audio().unwrap();
+
+    let (sender, receiver) = channel::<(u16, u8)>();
+
+    let desire_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: None,
+    };
+
+    let device = audio_subsystem
+        .open_playback(None, &desire_spec, |spec| Vrc6Wave {
+            freq: spec.freq as f32,
+            receiver,
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            halt: false,
+        })
+        .unwrap();
+
+    device.resume();
+    (device, sender)
+}
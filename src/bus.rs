@@ -1,10 +1,14 @@
 use crate::apu::NesAPU;
+use crate::cdl::cdl;
 use crate::frame::Frame;
 use crate::joypad::Joypad;
+use crate::mapper::mapper;
 use crate::ppu::NesPPU;
-use crate::MAPPER;
 use log::{debug, info};
 
+// フレームの終わりに呼ばれる 画面を出してキー入力を読む
+type GameLoopCallback<'call> = Box<dyn FnMut(&mut NesPPU, &mut Joypad, &Frame) + 'call>;
+
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     // prg_rom: Vec<u8>,
@@ -15,13 +19,17 @@ pub struct Bus<'call> {
     apu: NesAPU,
     cycles: usize,
 
-    game_loop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad, &Frame) + 'call>,
+    // デバッガ用 読み書きを監視するアドレス
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit: Option<WatchHit>,
+
+    game_loop_callback: GameLoopCallback<'call>,
 }
 
 impl<'a> Bus<'a> {
     pub fn new<'call, F>(apu: NesAPU, game_loop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad, &Frame) + 'call,
+        F: FnMut(&mut NesPPU, &mut Joypad, &Frame) + 'call,
     {
         let ppu = NesPPU::new();
         Bus {
@@ -32,6 +40,8 @@ impl<'a> Bus<'a> {
             // joypad2: Joypad::new(),
             apu: apu,
             cycles: 0,
+            watchpoints: vec![],
+            watch_hit: None,
             game_loop_callback: Box::from(game_loop_callback),
         }
     }
@@ -39,14 +49,81 @@ impl<'a> Bus<'a> {
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3, &mut self.frame);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+        // NMIが無効のときもフレームは進むので、NMIではなくフレームの終わりで呼び出す
+        // マッパーがPPUのアドレスとCPUのサイクルの前後関係を見られるように1サイクルずつ進める
+        let mut frame_end = false;
+        for _ in 0..cycles {
+            frame_end |= self.ppu.tick(3, &mut self.frame);
+            unsafe { mapper().cpu_clock() };
+        }
 
         self.apu.tick(cycles);
 
-        if !nmi_before && nmi_after {
-            (self.game_loop_callback)(&self.ppu, &mut self.joypad1, &self.frame);
+        if frame_end {
+            (self.game_loop_callback)(&mut self.ppu, &mut self.joypad1, &self.frame);
+        }
+    }
+
+    #[allow(dead_code)] //testromで画面を見るときだけ使う
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // デバッガやトレースの表示用に読む ウォッチポイントやCDLには記録しない
+    // PPU/APUやマッパーのレジスタは読むだけで状態が変わるものがあるので0にする
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b_0000_0111_1111_1111) as usize],
+            0x6000..=0x7FFF => unsafe { mapper().read_prg_ram(addr) },
+            PRG_ROM..=PRG_ROM_END => unsafe { mapper().read_prg_rom(addr) },
+            _ => 0,
+        }
+    }
+
+    // デバッガからの書き込み RAMとPRG-RAMだけを直接書き換える I/OやROMには書けない
+    pub fn poke(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                self.cpu_vram[(addr & 0b_0000_0111_1111_1111) as usize] = data;
+                true
+            }
+            0x6000..=0x7FFF => unsafe { mapper().poke_prg_ram(addr, data) },
+            _ => false,
+        }
+    }
+
+    fn write_ppu_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x2000 => self.ppu.write_to_ctrl(data),
+            0x2001 => self.ppu.write_to_mask(data),
+            0x2002 => self.ppu.write_to_status(data), //read only?
+            0x2003 => self.ppu.write_to_oam_addr(data),
+            0x2004 => self.ppu.write_to_oam_data(data),
+            0x2005 => self.ppu.write_to_scroll(data),
+            0x2006 => self.ppu.write_to_ppu_addr(data),
+            0x2007 => self.ppu.write_to_data(data),
+            _ => unreachable!(),
+        }
+    }
+
+    fn watch(&mut self, addr: u16, kind: WatchKind, data: u8) {
+        if self.watchpoints.is_empty() || self.watch_hit.is_some() {
+            return;
+        }
+        let hit = self
+            .watchpoints
+            .iter()
+            .any(|w| w.kind == kind && w.start <= addr && addr <= w.end);
+        if hit {
+            self.watch_hit = Some(WatchHit { addr, kind, data });
         }
     }
 
@@ -66,6 +143,27 @@ impl<'a> Bus<'a> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Execute, // PCで判定するのでBusでは見ない
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub data: u8,
+}
+
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 // const PPU_REGISTERS: u16 = 0x2000;
@@ -81,7 +179,7 @@ pub trait Mem {
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let data = match addr {
             RAM..=RAM_MIRRORS_END => {
                 //0x0000 ~ 0x1fff
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
@@ -107,10 +205,19 @@ impl Mem for Bus<'_> {
             0x4016 => self.joypad1.read(),
             0x4017 => 0,
 
-            0x6000..=0x7FFF => unsafe { MAPPER.read_prg_ram(addr) },
+            // 拡張音源とマッパーのレジスタ
+            0x4020..=0x5FFF => match self.apu.read_expansion(addr) {
+                Some(data) => data,
+                None => unsafe { mapper().read_register(addr) },
+            },
+
+            0x6000..=0x7FFF => unsafe { mapper().read_prg_ram(addr) },
 
             PRG_ROM..=PRG_ROM_END => {
-                unsafe { MAPPER.read_prg_rom(addr) }
+                unsafe {
+                    cdl().log_data(addr);
+                    mapper().read_prg_rom(addr)
+                }
                 // self.read_prg_rom(addr)
             }
 
@@ -118,27 +225,26 @@ impl Mem for Bus<'_> {
                 println!("Ignoring mem access at {:X}", addr);
                 0
             }
-        }
+        };
+        self.watch(addr, WatchKind::Read, data);
+        data
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.watch(addr, WatchKind::Write, data);
+        if (0x2000..=PPU_REGISTERS_MIRRORS_END).contains(&addr) {
+            unsafe { mapper().snoop_ppu_register(addr & 0x2007, data) };
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
 
-            0x2000 => self.ppu.write_to_ctrl(data),
-            0x2001 => self.ppu.write_to_mask(data),
-            0x2002 => self.ppu.write_to_status(data), //read only?
-            0x2003 => self.ppu.write_to_oam_addr(data),
-            0x2004 => self.ppu.write_to_oam_data(data),
-            0x2005 => self.ppu.write_to_scroll(data),
-            0x2006 => self.ppu.write_to_ppu_addr(data),
-            0x2007 => self.ppu.write_to_data(data),
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
+            0x2000..=PPU_REGISTERS_MIRRORS_END => {
+                // $2008~$3FFFはミラー マッパーにはもう覗かせたのでPPUにだけ書く
                 let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_write(mirror_down_addr, data);
+                self.write_ppu_register(mirror_down_addr, data);
             }
 
             0x4000..=0x4003 => {
@@ -190,11 +296,18 @@ impl Mem for Bus<'_> {
                 info!("WRITE ACCESS 0x4017. {:02X}", data);
             }
 
-            0x6000..=0x7FFF => unsafe { MAPPER.write_prg_ram(addr, data) },
+            0x4020..=0x5FFF => {
+                self.apu.write_expansion(addr, data);
+                unsafe { mapper().write_register(addr, data) };
+            }
 
-            PRG_ROM..=PRG_ROM_END => unsafe {
-                MAPPER.write(addr, data);
-            },
+            0x6000..=0x7FFF => unsafe { mapper().write_prg_ram(addr, data) },
+
+            // 拡張音源のレジスタはマッパーのレジスタと同じところにある
+            PRG_ROM..=PRG_ROM_END => {
+                self.apu.write_expansion(addr, data);
+                unsafe { mapper().write(addr, data) };
+            }
 
             _ => {
                 println!("Ignoring mem write-access at {:X}", addr)
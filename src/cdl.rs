@@ -0,0 +1,176 @@
+use std::fs;
+use std::path::Path;
+use std::ptr::addr_of_mut;
+
+use log::info;
+use once_cell::sync::Lazy;
+
+use crate::cpu::{AddressingMode, OpCode};
+use crate::mapper::mapper;
+use crate::rom::Rom;
+
+// Code/Data Logger
+// ゲームの実行中にROMのどのバイトがコード/データとして使われたかを記録して、FCEUX互換の.cdlファイルに保存する
+// .cdlファイルはPRG-ROMと同じ大きさのフラグの後ろにCHR-ROMと同じ大きさのフラグが続く
+// アドレスはマッパーのバンクを通してROMファイル上の位置に直して記録する
+
+// PRG-ROMのフラグ
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+pub const PRG_BANK_MASK: u8 = 0x0C; //どのアドレスから読まれたか 0:$8000 1:$A000 2:$C000 3:$E000
+pub const PRG_INDIRECT_CODE: u8 = 0x10; //JMP ($nnnn)の飛び先
+pub const PRG_INDIRECT_DATA: u8 = 0x20; //LDA ($nn),Y などで読まれた
+pub const PRG_PCM: u8 = 0x40; //DMCのサンプル
+
+// CHR-ROMのフラグ
+pub const CHR_RENDERED: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02; //$2007から読まれた
+
+static mut CDL: Lazy<CodeDataLogger> = Lazy::new(CodeDataLogger::new);
+
+pub unsafe fn cdl() -> &'static mut CodeDataLogger {
+    &mut *addr_of_mut!(CDL)
+}
+
+pub struct CodeDataLogger {
+    enabled: bool,
+    path: String,
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+
+    // 実行中の命令自身(オペコードとオペランド)の読み込みはデータとして記録しない
+    fetch_addr: u16,
+    fetch_len: u16,
+    indirect_data: bool,
+    indirect_code: bool,
+}
+
+impl CodeDataLogger {
+    pub fn new() -> Self {
+        CodeDataLogger {
+            enabled: false,
+            path: String::new(),
+            prg: vec![],
+            chr: vec![],
+            fetch_addr: 0,
+            fetch_len: 0,
+            indirect_data: false,
+            indirect_code: false,
+        }
+    }
+
+    // <rom>.cdl に記録する 既にファイルがあれば続きから記録する
+    pub fn start(&mut self, rom_path: &str, rom: &Rom) {
+        self.path = String::from(rom_path) + ".cdl";
+        self.prg = vec![0; rom.prg_rom.len()];
+        // CHR-RAMの場合は記録しない(FCEUXと同じ)
+        self.chr = if rom.is_chr_ram {
+            vec![]
+        } else {
+            vec![0; rom.chr_rom.len()]
+        };
+
+        if Path::new(self.path.as_str()).is_file() {
+            let raw = fs::read(&self.path).expect("unable to read cdl");
+            if raw.len() == self.prg.len() + self.chr.len() {
+                let (prg, chr) = raw.split_at(self.prg.len());
+                self.prg.copy_from_slice(prg);
+                self.chr.copy_from_slice(chr);
+            } else {
+                info!("{} does not match the rom size, starting over", self.path);
+            }
+        }
+
+        self.enabled = true;
+        info!("CDL: {}", self.path);
+    }
+
+    pub fn save(&self) {
+        if !self.enabled {
+            return;
+        }
+        let mut raw = self.prg.clone();
+        raw.extend_from_slice(&self.chr);
+        fs::write(&self.path, raw).expect("unable to write cdl");
+    }
+
+    // オペコードを読む直前に呼ぶ
+    pub fn fetch(&mut self, addr: u16) {
+        self.fetch_addr = addr;
+        self.fetch_len = 3; //まだ命令の長さがわからないので最長の3バイト
+    }
+
+    // 命令を実行する直前に呼ぶ addrはオペコードのアドレス
+    pub fn log_code(&mut self, addr: u16, op: &OpCode) {
+        if !self.enabled {
+            return;
+        }
+        self.fetch_addr = addr;
+        self.fetch_len = op.bytes;
+
+        let mut flags = PRG_CODE;
+        if self.indirect_code {
+            flags |= PRG_INDIRECT_CODE;
+            self.indirect_code = false;
+        }
+        for i in 0..op.bytes {
+            self.mark_prg(addr.wrapping_add(i), flags);
+            flags &= !PRG_INDIRECT_CODE; //オペランドには付けない
+        }
+
+        self.indirect_data = matches!(
+            op.addressing_mode,
+            AddressingMode::Indirect_X | AddressingMode::Indirect_Y
+        );
+        // JMP ($nnnn)
+        self.indirect_code = op.code == 0x6C;
+    }
+
+    pub fn log_data(&mut self, addr: u16) {
+        if !self.enabled || addr.wrapping_sub(self.fetch_addr) < self.fetch_len {
+            return;
+        }
+        let flags = if self.indirect_data {
+            PRG_DATA | PRG_INDIRECT_DATA
+        } else {
+            PRG_DATA
+        };
+        self.mark_prg(addr, flags);
+    }
+
+    pub fn log_pcm(&mut self, addr: u16) {
+        if !self.enabled {
+            return;
+        }
+        self.mark_prg(addr, PRG_DATA | PRG_PCM);
+    }
+
+    pub fn log_chr_rendered(&mut self, addr: u16) {
+        self.mark_chr(addr, CHR_RENDERED);
+    }
+
+    pub fn log_chr_read(&mut self, addr: u16) {
+        self.mark_chr(addr, CHR_READ);
+    }
+
+    fn mark_prg(&mut self, addr: u16, flags: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let offset = unsafe { mapper().prg_rom_addr(addr) };
+        if let Some(f) = self.prg.get_mut(offset) {
+            let window = (((addr >> 13) & 0x03) as u8) << 2;
+            *f = (*f & !PRG_BANK_MASK) | flags | window;
+        }
+    }
+
+    fn mark_chr(&mut self, addr: u16, flags: u8) {
+        if !self.enabled || self.chr.is_empty() {
+            return;
+        }
+        let offset = unsafe { mapper().chr_rom_addr(addr) };
+        if let Some(f) = self.chr.get_mut(offset) {
+            *f |= flags;
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use crate::{
+    cdl::cdl,
+    mapper::mapper,
     opscodes::{call, CPU_OPS_CODES},
-    MAPPER,
+    symbols::symbols,
 };
-use log::{debug, trace};
+use log::debug;
 
 use crate::bus::{Bus, Mem};
 // use crate::ppu::AddrRegister;
@@ -64,6 +66,12 @@ impl OpCode {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
 const FLAG_CARRY: u8 = 1 << 0;
 const FLAG_ZERO: u8 = 1 << 1;
 const FLAG_INTERRRUPT: u8 = 1 << 2;
@@ -86,9 +94,13 @@ pub struct CPU<'a> {
     // pub memory: [u8; 0x10000], // 0xFFFF
     pub bus: Bus<'a>,
     pub add_cycles: u8,
-}
 
-pub static mut IN_TRACE: bool = false;
+    // この命令の直前に受け付けた割り込み
+    pub interrupt: Option<Interrupt>,
+
+    // callback(デバッガ)の中でPCが書き換えられた
+    pc_overridden: bool,
+}
 
 impl Mem for CPU<'_> {
     //指定したアドレス(addr)から1バイト(8bit)のデータを読む関数
@@ -114,9 +126,17 @@ impl<'a> CPU<'a> {
             // memory: [0x00; 0x10000],
             bus: bus,
             add_cycles: 0,
+            interrupt: None,
+            pc_overridden: false,
         }
     }
 
+    // デバッガからPCを変える 今の命令は実行せずに新しいPCから実行し直す
+    pub fn set_program_counter(&mut self, pc: u16) {
+        self.program_counter = pc;
+        self.pc_overridden = true;
+    }
+
     fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Implied => {
@@ -263,49 +283,66 @@ impl<'a> CPU<'a> {
         F: FnMut(&mut CPU),
     {
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt_nmi();
-            }
+            self.step_with_callback(&mut callback);
+        }
+    }
 
-            // apuのirqを優先
-            if self.bus.poll_apu_irq() {
-                self.call_irq();
-            } else if unsafe { MAPPER.is_irq() } {
-                //ここで呼び出すIRQはAPUではなくPPUのものだが、IRQを呼び出す処理は同じ(FFFE固定)
-                self.call_irq();
-            }
+    // 割り込みの確認と1命令分の実行を行う
+    // callbackは命令の実行直前に呼ばれる
+    pub fn step_with_callback<F>(&mut self, callback: &mut F)
+    where
+        F: FnMut(&mut CPU),
+    {
+        self.interrupt = None;
 
-            let opscode = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-
-            // let op = self.find_ops(opscode);
-            let op = CPU_OPS_CODES.get(&opscode);
-            match op {
-                Some(op) => {
-                    self.add_cycles = 0;
-                    callback(self);
-                    call(self, &op);
-
-                    match op.cycle_calc_mode {
-                        CycleCalcMode::None => {
-                            self.add_cycles = 0;
-                        }
-                        CycleCalcMode::Page => {
-                            if self.add_cycles > 1 {
-                                panic!("Unexpected add_cycles")
-                            }
-                        }
-                        _ => {}
-                    }
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt_nmi();
+        }
 
-                    self.bus.tick(op.cycles + self.add_cycles);
-                    // if program_counter_state == self.program_counter {
-                    //     self.program_counter += (op.len - 1) as u16
-                    // }
+        // apuのirqを優先
+        if self.bus.poll_apu_irq() {
+            self.call_irq();
+        } else if unsafe { mapper().is_irq() } {
+            //ここで呼び出すIRQはAPUではなくPPUのものだが、IRQを呼び出す処理は同じ(FFFE固定)
+            self.call_irq();
+        }
+
+        unsafe { cdl().fetch(self.program_counter) };
+        let opscode = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+
+        // let op = self.find_ops(opscode);
+        let op = CPU_OPS_CODES.get(&opscode);
+        match op {
+            Some(op) => {
+                self.add_cycles = 0;
+                self.pc_overridden = false;
+                callback(self);
+
+                // callback(デバッガ)でPCが書き換えられたときは、この命令は実行せずに新しいPCから実行し直す
+                if self.pc_overridden {
+                    return;
                 }
-                _ => {
-                    // panic!("no implementation")
+                unsafe { cdl().log_code(self.program_counter - 1, op) };
+                call(self, op);
+
+                match op.cycle_calc_mode {
+                    CycleCalcMode::None => {
+                        self.add_cycles = 0;
+                    }
+                    CycleCalcMode::Page if self.add_cycles > 1 => {
+                        panic!("Unexpected add_cycles")
+                    }
+                    _ => {}
                 }
+
+                self.bus.tick(op.cycles + self.add_cycles);
+                // if program_counter_state == self.program_counter {
+                //     self.program_counter += (op.len - 1) as u16
+                // }
+            }
+            _ => {
+                // panic!("no implementation")
             }
         }
     }
@@ -322,6 +359,7 @@ impl<'a> CPU<'a> {
         self.status = self.status | FLAG_INTERRRUPT;
         self.bus.tick(2);
         self.program_counter = self.mem_read_u16(0xFFFA);
+        self.interrupt = Some(Interrupt::Nmi);
     }
 
     fn call_irq(&mut self) {
@@ -332,11 +370,13 @@ impl<'a> CPU<'a> {
 
         // プログラム カウンターとプロセッサ ステータスがスタックにプッシュされ、
         self._push_u16(self.program_counter);
-        self._push(self.status);
+        self._push((self.status & !FLAG_BREAK) | FLAG_BREAK2);
 
-        // $FFFE/F の IRQ 割り込みベクトルが PC にロードされ、ステータスのブレーク フラグが 1 に設定されます。
+        // マッパーのIRQは応答するまで出続けるので、割り込み禁止にしてから飛ぶ
+        self.status |= FLAG_INTERRRUPT;
+        // $FFFE/F の IRQ 割り込みベクトルが PC にロードされる
         self.program_counter = self.mem_read_u16(0xFFFE);
-        self.status = self.status | FLAG_BREAK;
+        self.interrupt = Some(Interrupt::Irq);
     }
 
     pub fn shs(&mut self, mode: &AddressingMode) {
@@ -616,10 +656,18 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_x);
     }
 
+    // リードモディファイライト命令は、計算した値を書く前に読んだ値をそのまま1回書き込む
+    // MMC1はこの2回続けての書き込みの2回目を無視する
+    fn write_modified(&mut self, addr: u16, old: u8, value: u8) {
+        self.mem_write(addr, old);
+        self.mem_write(addr, value);
+    }
+
     pub fn inc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr).wrapping_add(1);
-        self.mem_write(addr, value);
+        let old = self.mem_read(addr);
+        let value = old.wrapping_add(1);
+        self.write_modified(addr, old, value);
         self.update_zero_and_negative_flags(value);
     }
 
@@ -635,8 +683,9 @@ impl<'a> CPU<'a> {
 
     pub fn dec(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr).wrapping_sub(1);
-        self.mem_write(addr, value);
+        let old = self.mem_read(addr);
+        let value = old.wrapping_sub(1);
+        self.write_modified(addr, old, value);
         self.update_zero_and_negative_flags(value);
     }
 
@@ -787,11 +836,11 @@ impl<'a> CPU<'a> {
             (self.register_a, carry)
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let carry = value & 0x01;
-            let value = value / 2;
+            let old = self.mem_read(addr);
+            let carry = old & 0x01;
+            let value = old / 2;
             let value = value | ((self.status & FLAG_CARRY) << 7);
-            self.mem_write(addr, value);
+            self.write_modified(addr, old, value);
             (value, carry)
         };
 
@@ -810,10 +859,10 @@ impl<'a> CPU<'a> {
             (self.register_a, carry)
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let (value, carry) = value.overflowing_mul(2);
+            let old = self.mem_read(addr);
+            let (value, carry) = old.overflowing_mul(2);
             let value = value | (self.status & FLAG_CARRY);
-            self.mem_write(addr, value);
+            self.write_modified(addr, old, value);
             (value, carry)
         };
 
@@ -832,10 +881,10 @@ impl<'a> CPU<'a> {
             (self.register_a, carry)
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let carry = value & 0x01;
-            let value = value / 2;
-            self.mem_write(addr, value);
+            let old = self.mem_read(addr);
+            let carry = old & 0x01;
+            let value = old / 2;
+            self.write_modified(addr, old, value);
             (value, carry)
         };
 
@@ -854,9 +903,9 @@ impl<'a> CPU<'a> {
             (value, carry)
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let (value, carry) = value.overflowing_mul(2);
-            self.mem_write(addr, value);
+            let old = self.mem_read(addr);
+            let (value, carry) = old.overflowing_mul(2);
+            self.write_modified(addr, old, value);
             (value, carry)
         };
 
@@ -963,16 +1012,15 @@ impl<'a> CPU<'a> {
     }
 }
 
-pub fn trace(cpu: &mut CPU) -> String {
-    unsafe { IN_TRACE = true };
-
+// nestestのログと同じ形式で今の命令を表示する (trace_logger, debuggerから使う)
+pub fn trace(cpu: &CPU) -> String {
     let program_counter = cpu.program_counter - 1;
     let pc = format!("{:<04X}", program_counter);
-    let op = cpu.mem_read(program_counter);
+    let op = peek(cpu, program_counter);
     let ops = CPU_OPS_CODES.get(&op).unwrap();
     let mut args: Vec<u8> = vec![];
     for n in 1..ops.bytes {
-        let arg = cpu.mem_read(program_counter + n);
+        let arg = peek(cpu, program_counter + n);
         args.push(arg);
     }
 
@@ -989,10 +1037,6 @@ pub fn trace(cpu: &mut CPU) -> String {
         status
     );
 
-    trace!("{}", log);
-
-    unsafe { IN_TRACE = false };
-
     log
 }
 
@@ -1035,55 +1079,82 @@ fn address(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
         }
 
         // LDA $44 => a5 44
-        AddressingMode::ZeroPage => {
-            format!("${:<02X}", args[0])
-        }
+        AddressingMode::ZeroPage => symbol_or(args[0] as u16, format!("${:<02X}", args[0])),
 
         // LDA $4400 => ad 00 44
-        AddressingMode::Absolute => {
-            format!("${:<02X}{:<02X}", args[1], args[0])
-        }
+        AddressingMode::Absolute => symbol_or(
+            u16::from_le_bytes([args[0], args[1]]),
+            format!("${:<02X}{:<02X}", args[1], args[0]),
+        ),
 
         // LDA $44,X => b5 44
         AddressingMode::ZeroPage_X => {
-            format!("${:<02X},X", args[0])
+            format!(
+                "{},X",
+                symbol_or(args[0] as u16, format!("${:<02X}", args[0]))
+            )
         }
 
         // LDX $44,Y => b6 44
         AddressingMode::ZeroPage_Y => {
-            format!("${:<02X},Y", args[0])
+            format!(
+                "{},Y",
+                symbol_or(args[0] as u16, format!("${:<02X}", args[0]))
+            )
         }
 
         // LDA $4400,X => bd 00 44
         AddressingMode::Absolute_X => {
-            format!("${:<02X}{:<02X},X", args[1], args[0])
+            format!(
+                "{},X",
+                symbol_or(
+                    u16::from_le_bytes([args[0], args[1]]),
+                    format!("${:<02X}{:<02X}", args[1], args[0])
+                )
+            )
         }
 
         // LDA $4400,Y => b9 00 44
         AddressingMode::Absolute_Y => {
-            format!("${:<02X}{:<02X},Y", args[1], args[0])
+            format!(
+                "{},Y",
+                symbol_or(
+                    u16::from_le_bytes([args[0], args[1]]),
+                    format!("${:<02X}{:<02X}", args[1], args[0])
+                )
+            )
         }
         // JMP
         AddressingMode::Indirect => {
-            format!("(${:<02X}{:<02X})", args[1], args[0])
+            format!(
+                "({})",
+                symbol_or(
+                    u16::from_le_bytes([args[0], args[1]]),
+                    format!("${:<02X}{:<02X}", args[1], args[0])
+                )
+            )
         }
 
         // LDA ($44,X) => a1 44
         AddressingMode::Indirect_X => {
-            format!("(${:<02X},X)", args[0])
+            format!(
+                "({},X)",
+                symbol_or(args[0] as u16, format!("${:<02X}", args[0]))
+            )
         }
 
         // LDA ($44),Y => b1 44
         AddressingMode::Indirect_Y => {
-            format!("(${:<02X}),Y", args[0])
+            format!(
+                "({}),Y",
+                symbol_or(args[0] as u16, format!("${:<02X}", args[0]))
+            )
         }
 
         // BCC $0490 => 90 04
         AddressingMode::Relative => {
-            format!(
-                "${:<04X}",
-                (program_counter as i32 + (args[0] as i8) as i32) as u16 + 2
-            )
+            let addr = (program_counter as i32 + (args[0] as i8) as i32) as u16 + 2;
+            symbol_or(addr, format!("${:<04X}", addr))
         }
 
         AddressingMode::NoneAddressing => {
@@ -1092,15 +1163,15 @@ fn address(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
     }
 }
 
-fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
+fn memory_access(cpu: &CPU, ops: &OpCode, args: &[u8]) -> String {
     if ops.mnemonic.starts_with("J") {
         if ops.addressing_mode == AddressingMode::Indirect {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
 
-            let value = cpu.mem_read_u16(addr);
-            return format!("= {:<04X}", value);
+            let value = peek_u16(cpu, addr);
+            return format!("= {}", symbol_or(value, format!("{:<04X}", value)));
         }
         return format!("");
     }
@@ -1110,7 +1181,7 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
-            let value = cpu.mem_read(addr);
+            let value = peek(cpu, addr);
             format!("= {:<02X}", value)
         }
 
@@ -1119,8 +1190,9 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let lo = args[0] as u16;
             let base = hi << 8 | lo;
             let addr = base.wrapping_add(cpu.register_x as u16);
-            let value = cpu.mem_read(addr);
-            format!("@ {:<04X} = {:<02X}", addr, value)
+            let value = peek(cpu, addr);
+            let addr = symbol_or(addr, format!("{:<04X}", addr));
+            format!("@ {} = {:<02X}", addr, value)
         }
 
         AddressingMode::Absolute_Y => {
@@ -1128,41 +1200,44 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let lo = args[0] as u16;
             let base = hi << 8 | lo;
             let addr = base.wrapping_add(cpu.register_y as u16);
-            let value = cpu.mem_read(addr);
-            format!("@ {:<04X} = {:<02X}", addr, value)
+            let value = peek(cpu, addr);
+            let addr = symbol_or(addr, format!("{:<04X}", addr));
+            format!("@ {} = {:<02X}", addr, value)
         }
 
         AddressingMode::ZeroPage => {
-            let value = cpu.mem_read(args[0] as u16);
+            let value = peek(cpu, args[0] as u16);
             format!("= {:<02X}", value)
         }
 
         AddressingMode::ZeroPage_X => {
             let addr = args[0].wrapping_add(cpu.register_x) as u16;
-            let value = cpu.mem_read(addr);
+            let value = peek(cpu, addr);
             format!("@ {:<02X} = {:<02X}", addr, value)
         }
 
         AddressingMode::ZeroPage_Y => {
             let addr = args[0].wrapping_add(cpu.register_y) as u16;
-            let value = cpu.mem_read(addr);
+            let value = peek(cpu, addr);
             format!("@ {:<02X} = {:<02X}", addr, value)
         }
 
         AddressingMode::Indirect_X => {
             let base = args[0];
             let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-            let addr = cpu.mem_read_u16(ptr as u16);
-            let value = cpu.mem_read(addr);
-            format!("@ {:<02X} = {:<04X} = {:<02X}", ptr, addr, value)
+            let addr = peek_u16(cpu, ptr as u16);
+            let value = peek(cpu, addr);
+            let addr = symbol_or(addr, format!("{:<04X}", addr));
+            format!("@ {:<02X} = {} = {:<02X}", ptr, addr, value)
         }
 
         AddressingMode::Indirect_Y => {
             let base = args[0];
-            let deref_base = cpu.mem_read_u16(base as u16);
+            let deref_base = peek_u16(cpu, base as u16);
             let deref = deref_base.wrapping_add(cpu.register_y as u16);
-            let value = cpu.mem_read(deref);
-            format!("= {:<04X} @ {:<04X} = {:<02X}", deref_base, deref, value)
+            let value = peek(cpu, deref);
+            let deref = symbol_or(deref, format!("{:<04X}", deref));
+            format!("= {:<04X} @ {} = {:<02X}", deref_base, deref, value)
         }
 
         _ => {
@@ -1171,6 +1246,30 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
     }
 }
 
+// シンボルファイルにラベルがあればラベル、なければ16進数のまま
+fn symbol_or(addr: u16, hex: String) -> String {
+    match unsafe { symbols().name(addr) } {
+        Some(name) => String::from(name),
+        None => hex,
+    }
+}
+
+// トレースやデバッガの表示のために読むとき、副作用のあるレジスタは読まない
+pub fn peek(cpu: &CPU, addr: u16) -> u8 {
+    cpu.bus.peek(addr)
+}
+
+// デバッガから書き換えるとき、RAMとPRG-RAMのほかは書けない
+pub fn poke(cpu: &mut CPU, addr: u16, data: u8) -> bool {
+    cpu.bus.poke(addr, data)
+}
+
+fn peek_u16(cpu: &CPU, addr: u16) -> u16 {
+    let lo = peek(cpu, addr) as u16;
+    let hi = peek(cpu, addr.wrapping_add(1)) as u16;
+    (hi << 8) | lo
+}
+
 fn cpu2str(cpu: &CPU) -> String {
     format!(
         "A:{:<02X} X:{:<02X} Y:{:<02X} P:{:<02X} SP:{:<02X}",
@@ -0,0 +1,490 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use crate::bus::{WatchKind, Watchpoint};
+use crate::cpu::{peek, trace, Interrupt, CPU};
+use crate::mapper::mapper;
+use crate::symbols::symbols;
+
+// ターミナルで操作するデバッガ
+// cpu.run_with_callbackのcallbackから毎命令on_instructionを呼び出して使う
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: RunMode,
+    break_on_nmi: bool,
+    break_on_irq: bool,
+
+    // 画面側(F12キー)から一時停止を要求するためのフラグ
+    break_request: Rc<Cell<bool>>,
+    // qで終わるときもEscapeと同じように記録を書き出してから終わる
+    quit_request: Rc<Cell<bool>>,
+
+    last_opscode: u8,
+    last_scanline: usize,
+    last_command: String,
+}
+
+struct Breakpoint {
+    addr: u16,
+    // バンクを指定したラベルで置いたときは、そのバンクが見えているときだけ止まる
+    rom_offset: Option<usize>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunMode {
+    Continue,
+    Step,
+    StepOver(u16, u8), //戻り先のPCとJSRしたときのSP
+    StepOut(u8),       //step outしたときのSP
+    Scanline(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Register {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// A==10 や PC>=C000 のような条件式
+#[derive(Debug, Clone)]
+struct Condition {
+    register: Register,
+    op: CompareOp,
+    value: u16,
+}
+
+const OP_JSR: u8 = 0x20;
+const OP_RTI: u8 = 0x40;
+const OP_RTS: u8 = 0x60;
+
+const HELP: &str = "\
+c, continue            実行を再開
+s, step                1命令実行 (step into)
+n, next                JSRの場合は戻ってくるまで実行 (step over)
+finish, out            今のサブルーチンから戻るまで実行 (step out)
+b, break ADDR [if COND && ...]
+                       ブレークポイントを設定 (例: b C5F5 if A==10 && X!=0)
+                       ADDRにはシンボルファイルのラベルも使える (例: b NMI)
+w, watch r|w|x ADDR[-ADDR]
+                       読み込み(r)/書き込み(w)/実行(x)を監視
+l, list                ブレークポイントとウォッチポイントの一覧
+d, delete N            N番目のブレークポイントを削除
+dw N                   N番目のウォッチポイントを削除
+scanline N             スキャンラインNまで実行
+nmi on|off             NMIで停止する
+irq on|off             IRQで停止する
+r, regs                現在の命令とレジスタを表示
+x ADDR [LEN]           メモリを表示
+q, quit                終了
+(空行)                 直前のコマンドを繰り返す";
+
+impl Debugger {
+    pub fn new(quit_request: Rc<Cell<bool>>) -> Self {
+        Debugger {
+            breakpoints: vec![],
+            // 起動直後は止まった状態から始める
+            mode: RunMode::Step,
+            break_on_nmi: false,
+            break_on_irq: false,
+            break_request: Rc::new(Cell::new(false)),
+            quit_request,
+            last_opscode: 0,
+            last_scanline: 0,
+            last_command: String::from("s"),
+        }
+    }
+
+    pub fn break_request(&self) -> Rc<Cell<bool>> {
+        self.break_request.clone()
+    }
+
+    pub fn on_instruction(&mut self, cpu: &mut CPU) {
+        //callbackが呼ばれた時点でPCはopscodeの次を指している
+        let pc = cpu.program_counter.wrapping_sub(1);
+
+        if let Some(reason) = self.break_reason(cpu, pc) {
+            println!("{}", reason);
+            self.mode = RunMode::Continue;
+            self.repl(cpu);
+        }
+
+        self.last_opscode = peek(cpu, pc);
+        self.last_scanline = cpu.bus.ppu().scanline();
+
+        // 前の命令で発生した分はもう見たので捨てる
+        cpu.bus.watch_hit = None;
+    }
+
+    fn break_reason(&mut self, cpu: &mut CPU, pc: u16) -> Option<String> {
+        if self.break_request.replace(false) {
+            return Some(format!("break at {}", location(pc)));
+        }
+
+        if let Some(hit) = &cpu.bus.watch_hit {
+            return Some(format!(
+                "watch {:?} ${:04X} = ${:02X}",
+                hit.kind, hit.addr, hit.data
+            ));
+        }
+
+        match cpu.interrupt {
+            Some(Interrupt::Nmi) if self.break_on_nmi => return Some(String::from("NMI")),
+            Some(Interrupt::Irq) if self.break_on_irq => return Some(String::from("IRQ")),
+            _ => {}
+        }
+
+        for (i, b) in self.breakpoints.iter().enumerate() {
+            if b.addr == pc && b.in_bank() && b.conditions.iter().all(|c| c.eval(cpu)) {
+                return Some(format!("breakpoint {} at {}", i, location(pc)));
+            }
+        }
+
+        for (i, w) in cpu.bus.watchpoints.iter().enumerate() {
+            if w.kind == WatchKind::Execute && w.start <= pc && pc <= w.end {
+                return Some(format!("watch {} Execute ${:04X}", i, pc));
+            }
+        }
+
+        let stop = match self.mode {
+            RunMode::Continue => false,
+            RunMode::Step => true,
+            RunMode::StepOver(ret, sp) => pc == ret && cpu.stack_pointer == sp,
+            RunMode::StepOut(sp) => {
+                (self.last_opscode == OP_RTS || self.last_opscode == OP_RTI)
+                    && cpu.stack_pointer > sp
+            }
+            RunMode::Scanline(line) => {
+                let scanline = cpu.bus.ppu().scanline();
+                scanline == line && self.last_scanline != line
+            }
+        };
+        if stop {
+            return Some(format!("stopped at {}", location(pc)));
+        }
+        None
+    }
+
+    fn repl(&mut self, cpu: &mut CPU) {
+        println!("{}", trace(cpu));
+
+        let stdin = io::stdin();
+        loop {
+            print!("(dbg) ");
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                // 入力が閉じられたら最後まで実行する
+                self.breakpoints.clear();
+                cpu.bus.watchpoints.clear();
+                return;
+            }
+
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            }
+            self.last_command = line.clone();
+
+            match self.command(cpu, &line) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => println!("error: {}", e),
+            }
+        }
+    }
+
+    // 実行を再開する場合はtrue
+    fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<bool, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let pc = cpu.program_counter.wrapping_sub(1);
+
+        match args[0] {
+            "c" | "continue" => {
+                self.mode = RunMode::Continue;
+                Ok(true)
+            }
+            "s" | "step" => {
+                self.mode = RunMode::Step;
+                Ok(true)
+            }
+            "n" | "next" => {
+                self.mode = if peek(cpu, pc) == OP_JSR {
+                    RunMode::StepOver(pc.wrapping_add(3), cpu.stack_pointer)
+                } else {
+                    RunMode::Step
+                };
+                Ok(true)
+            }
+            "finish" | "out" => {
+                self.mode = RunMode::StepOut(cpu.stack_pointer);
+                Ok(true)
+            }
+            "scanline" => {
+                let line = args.get(1).ok_or("scanline N")?;
+                let line = line.parse().map_err(|_| format!("bad scanline {}", line))?;
+                self.mode = RunMode::Scanline(line);
+                Ok(true)
+            }
+            "b" | "break" => {
+                let (addr, rom_offset) =
+                    parse_location(args.get(1).ok_or("break ADDR [if COND]")?)?;
+                let conditions = match args.get(2) {
+                    Some(&"if") => parse_conditions(&args[3..].join(" "))?,
+                    Some(s) => return Err(format!("unexpected {}", s)),
+                    None => vec![],
+                };
+                self.breakpoints.push(Breakpoint {
+                    addr,
+                    rom_offset,
+                    conditions,
+                });
+                println!(
+                    "breakpoint {} at {}",
+                    self.breakpoints.len() - 1,
+                    location(addr)
+                );
+                Ok(false)
+            }
+            "w" | "watch" => {
+                let kind = match args.get(1) {
+                    Some(&"r") => WatchKind::Read,
+                    Some(&"w") => WatchKind::Write,
+                    Some(&"x") => WatchKind::Execute,
+                    _ => return Err(String::from("watch r|w|x ADDR[-ADDR]")),
+                };
+                let range = args.get(2).ok_or("watch r|w|x ADDR[-ADDR]")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((s, e)) => (parse_addr(s)?, parse_addr(e)?),
+                    None => (parse_addr(range)?, parse_addr(range)?),
+                };
+                cpu.bus.watchpoints.push(Watchpoint { start, end, kind });
+                println!(
+                    "watchpoint {} {:?} ${:04X}-${:04X}",
+                    cpu.bus.watchpoints.len() - 1,
+                    kind,
+                    start,
+                    end
+                );
+                Ok(false)
+            }
+            "l" | "list" => {
+                for (i, b) in self.breakpoints.iter().enumerate() {
+                    let conditions: Vec<String> =
+                        b.conditions.iter().map(|c| c.to_string()).collect();
+                    if conditions.is_empty() {
+                        println!("b{} {}", i, location(b.addr));
+                    } else {
+                        println!("b{} {} if {}", i, location(b.addr), conditions.join(" && "));
+                    }
+                }
+                for (i, w) in cpu.bus.watchpoints.iter().enumerate() {
+                    println!("w{} {:?} ${:04X}-${:04X}", i, w.kind, w.start, w.end);
+                }
+                println!(
+                    "nmi: {}, irq: {}",
+                    on_off(self.break_on_nmi),
+                    on_off(self.break_on_irq)
+                );
+                Ok(false)
+            }
+            "d" | "delete" => {
+                let n = parse_index(args.get(1), self.breakpoints.len())?;
+                self.breakpoints.remove(n);
+                Ok(false)
+            }
+            "dw" => {
+                let n = parse_index(args.get(1), cpu.bus.watchpoints.len())?;
+                cpu.bus.watchpoints.remove(n);
+                Ok(false)
+            }
+            "nmi" => {
+                self.break_on_nmi = parse_on_off(args.get(1))?;
+                Ok(false)
+            }
+            "irq" => {
+                self.break_on_irq = parse_on_off(args.get(1))?;
+                Ok(false)
+            }
+            "r" | "regs" => {
+                println!("{}", trace(cpu));
+                println!("PC:{:04X} scanline:{}", pc, cpu.bus.ppu().scanline());
+                Ok(false)
+            }
+            "x" => {
+                let addr = parse_addr(args.get(1).ok_or("x ADDR [LEN]")?)?;
+                let len = match args.get(2) {
+                    Some(l) => parse_addr(l)? as usize,
+                    None => 0x40,
+                };
+                dump_memory(cpu, addr, len);
+                Ok(false)
+            }
+            "q" | "quit" => {
+                self.quit_request.set(true);
+                Ok(true)
+            }
+            "h" | "help" | "?" => {
+                println!("{}", HELP);
+                Ok(false)
+            }
+            other => Err(format!("unknown command {} (h for help)", other)),
+        }
+    }
+}
+
+impl Breakpoint {
+    fn in_bank(&self) -> bool {
+        match self.rom_offset {
+            Some(offset) => offset == unsafe { mapper().prg_rom_addr(self.addr) },
+            None => true,
+        }
+    }
+}
+
+impl Condition {
+    fn parse(s: &str) -> Result<Condition, String> {
+        // 2文字の演算子を先に探す
+        let ops = [
+            ("==", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("<", CompareOp::Lt),
+            (">", CompareOp::Gt),
+        ];
+        for (token, op) in ops.iter() {
+            if let Some((lhs, rhs)) = s.split_once(token) {
+                let register = match lhs.trim().to_uppercase().as_str() {
+                    "A" => Register::A,
+                    "X" => Register::X,
+                    "Y" => Register::Y,
+                    "P" => Register::P,
+                    "SP" => Register::SP,
+                    "PC" => Register::PC,
+                    r => return Err(format!("unknown register {}", r)),
+                };
+                return Ok(Condition {
+                    register,
+                    op: *op,
+                    value: parse_addr(rhs.trim())?,
+                });
+            }
+        }
+        Err(format!("bad condition {}", s))
+    }
+
+    fn eval(&self, cpu: &CPU) -> bool {
+        let value = match self.register {
+            Register::A => cpu.register_a as u16,
+            Register::X => cpu.register_x as u16,
+            Register::Y => cpu.register_y as u16,
+            Register::P => cpu.status as u16,
+            Register::SP => cpu.stack_pointer as u16,
+            Register::PC => cpu.program_counter.wrapping_sub(1),
+        };
+        match self.op {
+            CompareOp::Eq => value == self.value,
+            CompareOp::Ne => value != self.value,
+            CompareOp::Lt => value < self.value,
+            CompareOp::Le => value <= self.value,
+            CompareOp::Gt => value > self.value,
+            CompareOp::Ge => value >= self.value,
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let op = match self.op {
+            CompareOp::Eq => "==",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        };
+        write!(f, "{:?}{}${:X}", self.register, op, self.value)
+    }
+}
+
+fn parse_conditions(s: &str) -> Result<Vec<Condition>, String> {
+    s.split("&&").map(|c| Condition::parse(c.trim())).collect()
+}
+
+// アドレスや値は16進数 ($C000, 0xC000, C000 のどれでもよい) かシンボルファイルのラベル
+fn parse_addr(s: &str) -> Result<u16, String> {
+    parse_location(s).map(|(addr, _)| addr)
+}
+
+// バンクを指定したラベルのときはPRG-ROM上の位置も返す
+fn parse_location(s: &str) -> Result<(u16, Option<usize>), String> {
+    if let Some(location) = unsafe { symbols().find(s) } {
+        return Ok(location);
+    }
+    let hex = s
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    u16::from_str_radix(hex, 16)
+        .map(|addr| (addr, None))
+        .map_err(|_| format!("bad address {}", s))
+}
+
+// $C5F5 (Label)
+fn location(addr: u16) -> String {
+    match unsafe { symbols().name(addr) } {
+        Some(name) => format!("${:04X} ({})", addr, name),
+        None => format!("${:04X}", addr),
+    }
+}
+
+fn parse_index(s: Option<&&str>, len: usize) -> Result<usize, String> {
+    let s = s.ok_or("need a number")?;
+    match s.parse::<usize>() {
+        Ok(n) if n < len => Ok(n),
+        _ => Err(format!("no such entry {}", s)),
+    }
+}
+
+fn parse_on_off(s: Option<&&str>) -> Result<bool, String> {
+    match s {
+        Some(&"on") => Ok(true),
+        Some(&"off") => Ok(false),
+        _ => Err(String::from("on|off")),
+    }
+}
+
+fn on_off(b: bool) -> &'static str {
+    if b {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn dump_memory(cpu: &mut CPU, addr: u16, len: usize) {
+    for row in (0..len).step_by(16) {
+        let start = addr.wrapping_add(row as u16);
+        let bytes: Vec<String> = (0..16.min(len - row))
+            .map(|i| format!("{:02X}", peek(cpu, start.wrapping_add(i as u16))))
+            .collect();
+        println!("{:04X}: {}", start, bytes.join(" "));
+    }
+}
@@ -0,0 +1,426 @@
+// .nesファイルのPRG-ROMをバンクごとにca65で読める形式へ逆アセンブルする
+// cargo run --bin disasm -- rom/mario.nes [--cdl rom/mario.nes.cdl] [--sym labels.txt] > mario.s
+// --symは.nl, .dbg, label=addr のどれでもよい (ROMの隣にある.nlファイルは指定しなくても読む)
+//
+// リセット/NMI/IRQのベクタからコードの流れを追って、たどり着けたところをコード、それ以外をデータとする
+// CDL(Code/Data Log)があれば実際に実行された場所もコードとして扱う
+//
+// 出力はバンクごとに .segment "BANKnn" を分けているので、リンカの設定でバンクを配置する
+#![allow(dead_code)]
+
+//モジュールのインポートはメインに書かなきゃいけない
+mod apu;
+mod bus;
+mod cartrige;
+mod cdl;
+mod cpu;
+mod frame;
+mod joypad;
+mod mapper;
+mod opscodes;
+mod palette;
+mod ppu;
+mod ppu_bus;
+mod render;
+mod rom;
+mod symbols;
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use once_cell::sync::Lazy;
+
+use crate::cdl::{PRG_BANK_MASK, PRG_CODE, PRG_DATA};
+use crate::cpu::{AddressingMode, OpCode};
+use crate::mapper::{create_mapper, Mapper, PrgLayout};
+use crate::opscodes::CPU_OPS_CODES;
+use crate::rom::Rom;
+use crate::symbols::Symbols;
+
+static mut MAPPER: Lazy<Box<dyn Mapper>> = Lazy::new(|| create_mapper(Rom::empty()));
+
+// バイトごとの解析結果
+const UNKNOWN: u8 = 0;
+const OPCODE: u8 = 1;
+const OPERAND: u8 = 2;
+const DATA: u8 = 3;
+
+struct Bank {
+    offset: usize, //prg_rom上の位置
+    size: usize,
+    base: u16, //CPUから見えるアドレス
+    fixed: bool,
+    // NROM-128のように16kBが$8000と$C000の両方に見える
+    mirrored: bool,
+}
+
+struct Disassembler {
+    prg: Vec<u8>,
+    banks: Vec<Bank>,
+    marks: Vec<u8>,
+    cdl: Vec<u8>,
+    labels: BTreeMap<usize, String>,
+    symbols: Symbols,
+}
+
+fn main() {
+    let mut rom_path: Option<String> = None;
+    let mut cdl_path: Option<String> = None;
+    let mut sym_paths: Vec<String> = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cdl" => cdl_path = args.next(),
+            "--sym" => sym_paths.extend(args.next()),
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    let rom_path = match rom_path {
+        Some(p) => p,
+        None => {
+            eprintln!("usage: disasm <rom.nes> [--cdl file.cdl] [--sym labels.txt]");
+            std::process::exit(2);
+        }
+    };
+
+    let raw = fs::read(&rom_path).expect("no file found");
+    let rom = Rom::new(&raw).expect("load error");
+
+    let cdl = match cdl_path {
+        Some(p) => fs::read(p).expect("unable to read cdl"),
+        None => vec![],
+    };
+    let mut symbols = Symbols::new();
+    symbols.load_nl_files(&rom_path);
+    for path in sym_paths.iter() {
+        symbols.load(path).unwrap();
+    }
+
+    let layout = create_mapper(Rom::new(&raw).expect("load error")).prg_layout();
+    let mut disassembler = Disassembler::new(&rom, &layout, cdl, symbols);
+    disassembler.analyze();
+    print!("{}", disassembler.listing(&rom_path, rom.mapper));
+}
+
+// バンクの大きさと配置はマッパーが知っている
+fn bank_layout(rom: &Rom, layout: &PrgLayout) -> Vec<Bank> {
+    let bank_size = layout.bank_size;
+    let count = (rom.prg_rom.len() / bank_size).max(1);
+
+    (0..count)
+        .map(|i| {
+            let (base, fixed) = match layout.fixed.iter().find(|(bank, _)| *bank == i) {
+                Some((_, base)) => (*base, true),
+                None => (layout.switch_base, false),
+            };
+            Bank {
+                offset: i * bank_size,
+                size: bank_size,
+                base,
+                fixed,
+                mirrored: layout.mirrored,
+            }
+        })
+        .collect()
+}
+
+impl Disassembler {
+    fn new(rom: &Rom, layout: &PrgLayout, cdl: Vec<u8>, symbols: Symbols) -> Self {
+        let mut banks = bank_layout(rom, layout);
+
+        // CDLに記録されたアドレス($8000/$A000/$C000/$E000のどこから読まれたか)で切り替えバンクの位置を決める
+        if !cdl.is_empty() {
+            for bank in banks.iter_mut().filter(|b| !b.fixed) {
+                let end = (bank.offset + bank.size).min(cdl.len());
+                let seen = cdl[bank.offset.min(end)..end]
+                    .iter()
+                    .find(|f| *f & (PRG_CODE | PRG_DATA) != 0);
+                if let Some(flags) = seen {
+                    let window = 0x8000 + ((flags & PRG_BANK_MASK) as u16 >> 2) * 0x2000;
+                    bank.base = window & !(bank.size as u16 - 1);
+                }
+            }
+        }
+
+        Disassembler {
+            prg: rom.prg_rom.clone(),
+            banks,
+            marks: vec![UNKNOWN; rom.prg_rom.len()],
+            cdl,
+            labels: BTreeMap::new(),
+            symbols,
+        }
+    }
+
+    // CPUのアドレスをprg_rom上の位置にする
+    // fromのバンクから見えない切り替えバンクの中を指している場合はNone
+    fn resolve(&self, from: usize, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let current = &self.banks[from];
+        let in_bank = |bank: &Bank| {
+            let addr = if bank.mirrored { addr | 0xC000 } else { addr };
+            let start = bank.base as usize;
+            let a = addr as usize;
+            if start <= a && a < start + bank.size {
+                Some(bank.offset + a - start)
+            } else {
+                None
+            }
+        };
+
+        if let Some(offset) = in_bank(current) {
+            return Some(offset);
+        }
+        self.banks.iter().filter(|b| b.fixed).find_map(in_bank)
+    }
+
+    fn bank_of(&self, offset: usize) -> usize {
+        self.banks
+            .iter()
+            .position(|b| b.offset <= offset && offset < b.offset + b.size)
+            .unwrap()
+    }
+
+    fn address_of(&self, offset: usize) -> u16 {
+        let bank = &self.banks[self.bank_of(offset)];
+        bank.base + (offset - bank.offset) as u16
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        (self.prg[offset + 1] as u16) << 8 | self.prg[offset] as u16
+    }
+
+    fn add_label(&mut self, offset: usize) {
+        if self.labels.contains_key(&offset) {
+            return;
+        }
+        let bank = self.bank_of(offset);
+        let addr = self.address_of(offset);
+        let name = match self.symbol_at(offset) {
+            Some(name) => name,
+            None if self.banks[bank].fixed => format!("L{:04X}", addr),
+            None => format!("B{:02}_{:04X}", bank, addr),
+        };
+        self.labels.insert(offset, name);
+    }
+
+    // バンクを指定したラベル、固定バンクならバンクを指定していないラベルも使う
+    fn symbol_at(&self, offset: usize) -> Option<String> {
+        if let Some(name) = self.symbols.name_at_rom(offset) {
+            return Some(String::from(name));
+        }
+        if self.banks[self.bank_of(offset)].fixed {
+            let addr = self.address_of(offset);
+            return self.symbols.name_global(addr).map(String::from);
+        }
+        None
+    }
+
+    fn analyze(&mut self) {
+        let mut queue: Vec<usize> = vec![];
+
+        // ベクタは最後のバンクにある
+        let last = self.banks.len() - 1;
+        for (vector, name) in [(0xFFFA, "NMI"), (0xFFFC, "RESET"), (0xFFFE, "IRQ")] {
+            if let Some(v) = self.resolve(last, vector) {
+                if v + 1 >= self.prg.len() {
+                    continue;
+                }
+                if let Some(offset) = self.resolve(last, self.read_u16(v)) {
+                    queue.push(offset);
+                    self.add_label(offset);
+                    if self.symbol_at(offset).is_none() && self.symbols.find(name).is_none() {
+                        self.labels.insert(offset, name.to_string());
+                    }
+                }
+                for i in 0..2 {
+                    self.marks[v + i] = DATA;
+                }
+            }
+        }
+
+        // CDLで実行済みになっているところも入り口にする
+        for (offset, flags) in self.cdl.iter().enumerate().take(self.prg.len()) {
+            if flags & PRG_CODE != 0 && (offset == 0 || self.cdl[offset - 1] & PRG_CODE == 0) {
+                queue.push(offset);
+            }
+        }
+
+        while let Some(offset) = queue.pop() {
+            self.trace_code(offset, &mut queue);
+        }
+
+        // 残りはデータ
+        for (offset, mark) in self.marks.iter_mut().enumerate() {
+            if *mark == UNKNOWN && self.cdl.get(offset).is_some_and(|f| f & PRG_DATA != 0) {
+                *mark = DATA;
+            }
+        }
+    }
+
+    // offsetから命令をたどってコードとしてマークする
+    fn trace_code(&mut self, start: usize, queue: &mut Vec<usize>) {
+        let bank = self.bank_of(start);
+        let bank_end = self.banks[bank].offset + self.banks[bank].size;
+        let mut offset = start;
+
+        loop {
+            if offset >= bank_end || self.marks[offset] != UNKNOWN {
+                return;
+            }
+            let op = match CPU_OPS_CODES.get(&self.prg[offset]) {
+                Some(op) => op,
+                None => return,
+            };
+            let len = op.bytes as usize;
+            // 非公式命令やバンクをまたぐ命令にたどり着いたらデータとみなして止める
+            if op.mnemonic.starts_with('*') || offset + len > bank_end {
+                return;
+            }
+            if (1..len).any(|i| self.marks[offset + i] != UNKNOWN) {
+                return;
+            }
+
+            self.marks[offset] = OPCODE;
+            for i in 1..len {
+                self.marks[offset + i] = OPERAND;
+            }
+
+            let addr = self.address_of(offset);
+            if let Some(target) = branch_target(op, addr, &self.prg[offset + 1..offset + len]) {
+                if let Some(t) = self.resolve(bank, target) {
+                    if op.mnemonic != "JMP" || op.addressing_mode != AddressingMode::Indirect {
+                        queue.push(t);
+                        self.add_label(t);
+                    }
+                }
+            }
+
+            match op.mnemonic.as_str() {
+                "JMP" | "RTS" | "RTI" | "BRK" => return,
+                _ => {}
+            }
+            offset += len;
+        }
+    }
+
+    fn listing(&self, rom_path: &str, mapper: u8) -> String {
+        let mut out = String::new();
+        out += &format!("; disassembly of {} (mapper {})\n", rom_path, mapper);
+        out += ".setcpu \"6502\"\n";
+
+        for (i, bank) in self.banks.iter().enumerate() {
+            out += &format!(
+                "\n; bank {} ${:04X}-${:04X}{}\n",
+                i,
+                bank.base,
+                bank.base as usize + bank.size - 1,
+                if bank.fixed { " (fixed)" } else { "" }
+            );
+            out += &format!(".segment \"BANK{:02}\"\n", i);
+
+            let mut offset = bank.offset;
+            let end = bank.offset + bank.size;
+            while offset < end {
+                if let Some(label) = self.labels.get(&offset) {
+                    out += &format!("{}:\n", label);
+                }
+
+                if self.marks[offset] == OPCODE {
+                    let op = CPU_OPS_CODES.get(&self.prg[offset]).unwrap();
+                    let len = op.bytes as usize;
+                    let args = &self.prg[offset + 1..offset + len];
+                    out += &format!(
+                        "        {:<24}; ${:04X}\n",
+                        self.instruction(i, op, self.address_of(offset), args),
+                        self.address_of(offset)
+                    );
+                    offset += len;
+                    continue;
+                }
+
+                // ラベルかコードの手前まで.byteで並べる(1行8バイトまで)
+                let mut bytes = vec![];
+                while offset < end && bytes.len() < 8 && self.marks[offset] != OPCODE {
+                    if !bytes.is_empty() && self.labels.contains_key(&offset) {
+                        break;
+                    }
+                    bytes.push(format!("${:02X}", self.prg[offset]));
+                    offset += 1;
+                }
+                out += &format!("        .byte {}\n", bytes.join(","));
+            }
+        }
+        out
+    }
+
+    fn operand_name(&self, bank: usize, addr: u16) -> String {
+        if let Some(offset) = self.resolve(bank, addr) {
+            // 命令の途中を指すラベルは出力されないので使えない
+            if let Some(label) = self
+                .labels
+                .get(&offset)
+                .filter(|_| self.marks[offset] != OPERAND)
+            {
+                return label.clone();
+            }
+        }
+        if addr < 0x8000 {
+            if let Some(name) = self.symbols.name_global(addr) {
+                return String::from(name);
+            }
+        }
+        format!("${:04X}", addr)
+    }
+
+    fn instruction(&self, bank: usize, op: &OpCode, addr: u16, args: &[u8]) -> String {
+        let mnemonic = op.mnemonic.to_lowercase();
+        let abs = || (args[1] as u16) << 8 | args[0] as u16;
+        // ca65はゼロページに収まるアドレスを勝手にゼロページの命令にするので、a:で絶対アドレスを強制する
+        let force_abs = |a: u16| if a < 0x100 { "a:" } else { "" };
+
+        let operand = match op.addressing_mode {
+            AddressingMode::Implied | AddressingMode::NoneAddressing => String::new(),
+            AddressingMode::Accumulator => String::from("a"),
+            AddressingMode::Immediate => format!("#${:02X}", args[0]),
+            AddressingMode::ZeroPage => format!("${:02X}", args[0]),
+            AddressingMode::ZeroPage_X => format!("${:02X},x", args[0]),
+            AddressingMode::ZeroPage_Y => format!("${:02X},y", args[0]),
+            AddressingMode::Absolute => {
+                format!("{}{}", force_abs(abs()), self.operand_name(bank, abs()))
+            }
+            AddressingMode::Absolute_X => {
+                format!("{}{},x", force_abs(abs()), self.operand_name(bank, abs()))
+            }
+            AddressingMode::Absolute_Y => {
+                format!("{}{},y", force_abs(abs()), self.operand_name(bank, abs()))
+            }
+            AddressingMode::Indirect => format!("({})", self.operand_name(bank, abs())),
+            AddressingMode::Indirect_X => format!("(${:02X},x)", args[0]),
+            AddressingMode::Indirect_Y => format!("(${:02X}),y", args[0]),
+            AddressingMode::Relative => {
+                let target = branch_target(op, addr, args).unwrap();
+                self.operand_name(bank, target)
+            }
+        };
+
+        if operand.is_empty() {
+            mnemonic
+        } else {
+            format!("{} {}", mnemonic, operand)
+        }
+    }
+}
+
+// 分岐・ジャンプ先のアドレス
+fn branch_target(op: &OpCode, addr: u16, args: &[u8]) -> Option<u16> {
+    match (op.mnemonic.as_str(), &op.addressing_mode) {
+        (_, AddressingMode::Relative) => Some((addr as i32 + 2 + (args[0] as i8) as i32) as u16),
+        ("JMP", _) | ("JSR", _) => Some((args[1] as u16) << 8 | args[0] as u16),
+        _ => None,
+    }
+}
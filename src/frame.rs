@@ -22,6 +22,12 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    // PPUが出力する9bitのピクセルを今のパレットで色にする
+    pub fn set_pixel_index(&mut self, x: usize, y: usize, pixel: u16) {
+        let rgb = unsafe { palette::palette().rgb(pixel) };
+        self.set_pixel(x, y, rgb);
+    }
 }
 
 pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::{debug, info};
+
+use crate::cpu::{peek, poke, CPU};
+
+// GDBのリモートシリアルプロトコル(RSP)のサーバ
+// cpu.run_with_callbackのcallbackから毎命令on_instructionを呼び出して使う
+//
+// レジスタの並び(gパケット)は a, x, y, p, sp が1バイトずつ、pc が2バイト(リトルエンディアン)
+// target.xmlでも同じ並びを返すので、6502を知らないクライアントでも扱える
+pub struct GdbStub {
+    stream: Option<TcpStream>,
+    breakpoints: HashSet<u16>,
+    stepping: bool,
+    // 再開直後の命令では同じアドレスのブレークポイントで止まらないようにする
+    resuming: bool,
+    instructions: usize,
+}
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.famicon.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8" regnum="1"/>
+    <reg name="y" bitsize="8" regnum="2"/>
+    <reg name="p" bitsize="8" regnum="3"/>
+    <reg name="sp" bitsize="8" regnum="4"/>
+    <reg name="pc" bitsize="16" regnum="5" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+// 毎命令ソケットを見るのは重いので、Ctrl-Cの確認はこの命令数ごとにする
+const INTERRUPT_CHECK_INTERVAL: usize = 0x1000;
+
+const SIGTRAP: &str = "S05";
+const SIGINT: &str = "S02";
+
+impl GdbStub {
+    // クライアントが接続してくるまで待つ
+    pub fn listen(port: u16) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", port)).expect("unable to bind gdb port");
+        info!("waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, addr) = listener.accept().expect("unable to accept gdb");
+        info!("gdb connected from {}", addr);
+        stream.set_nodelay(true).unwrap();
+
+        GdbStub {
+            stream: Some(stream),
+            breakpoints: HashSet::new(),
+            // 接続直後は止まった状態から始める
+            stepping: true,
+            resuming: false,
+            instructions: 0,
+        }
+    }
+
+    pub fn on_instruction(&mut self, cpu: &mut CPU) {
+        if self.stream.is_none() {
+            return;
+        }
+
+        //callbackが呼ばれた時点でPCはopscodeの次を指している
+        let pc = cpu.program_counter.wrapping_sub(1);
+
+        let mut signal = None;
+        if self.stepping || (!self.resuming && self.breakpoints.contains(&pc)) {
+            signal = Some(SIGTRAP);
+        } else {
+            self.instructions += 1;
+            if self.instructions % INTERRUPT_CHECK_INTERVAL == 0 && self.poll_interrupt() {
+                signal = Some(SIGINT);
+            }
+        }
+        self.resuming = false;
+
+        if let Some(signal) = signal {
+            self.stepping = false;
+            self.send_packet(signal);
+            self.serve(cpu);
+        }
+    }
+
+    // クライアントがCtrl-C(0x03)を送ってきたか
+    fn poll_interrupt(&mut self) -> bool {
+        let stream = self.stream.as_mut().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut buf = [0; 1];
+        let res = stream.read(&mut buf);
+        stream.set_nonblocking(false).unwrap();
+        matches!(res, Ok(1) if buf[0] == 0x03)
+    }
+
+    // 実行を再開するパケットが来るまでパケットを処理する
+    fn serve(&mut self, cpu: &mut CPU) {
+        loop {
+            let packet = match self.read_packet() {
+                Some(p) => p,
+                None => {
+                    info!("gdb disconnected");
+                    self.detach();
+                    return;
+                }
+            };
+            debug!("gdb <- {}", packet);
+
+            match self.handle_packet(cpu, &packet) {
+                Some(reply) => self.send_packet(&reply),
+                None => return,
+            }
+        }
+    }
+
+    // 実行を再開するときはNone
+    fn handle_packet(&mut self, cpu: &mut CPU, packet: &str) -> Option<String> {
+        if packet.is_empty() {
+            return Some(String::new());
+        }
+        let cmd_len = packet.chars().next().map_or(0, char::len_utf8);
+        let (cmd, body) = packet.split_at(cmd_len);
+        let reply = match cmd {
+            "?" => String::from(SIGTRAP),
+            "g" => read_registers(cpu),
+            "G" => {
+                write_registers(cpu, body);
+                String::from("OK")
+            }
+            "p" => match usize::from_str_radix(body, 16) {
+                Ok(n) if n <= 5 => read_registers(cpu)[n * 2..(n * 2 + reg_len(n) * 2)].to_string(),
+                _ => String::from("E01"),
+            },
+            "P" => match body.split_once('=') {
+                Some((n, value)) => {
+                    let n = usize::from_str_radix(n, 16).unwrap_or(usize::MAX);
+                    if write_register(cpu, n, value) {
+                        String::from("OK")
+                    } else {
+                        String::from("E01")
+                    }
+                }
+                None => String::from("E01"),
+            },
+            "m" => match parse_addr_len(body) {
+                Some((addr, len)) => (0..len)
+                    .map(|i| format!("{:02x}", peek(cpu, addr.wrapping_add(i))))
+                    .collect(),
+                None => String::from("E01"),
+            },
+            "M" => match body.split_once(':') {
+                Some((addr_len, data)) => match parse_addr_len(addr_len) {
+                    Some((addr, len)) => {
+                        // ROMやI/Oのレジスタは書き換えられないのでエラーにする
+                        let bytes = decode_hex(data);
+                        let written = (0..len.min(bytes.len() as u16))
+                            .all(|i| poke(cpu, addr.wrapping_add(i), bytes[i as usize]));
+                        if written {
+                            String::from("OK")
+                        } else {
+                            String::from("E01")
+                        }
+                    }
+                    None => String::from("E01"),
+                },
+                None => String::from("E01"),
+            },
+            "c" | "s" => {
+                if !body.is_empty() {
+                    if let Ok(addr) = u16::from_str_radix(body, 16) {
+                        set_pc(cpu, addr);
+                    }
+                }
+                self.stepping = cmd == "s";
+                self.resuming = true;
+                return None;
+            }
+            "Z" | "z" => {
+                // Z0 (ソフトウェア) と Z1 (ハードウェア) のブレークポイントだけ対応
+                let parts: Vec<&str> = body.split(',').collect();
+                match (parts.first(), parts.get(1)) {
+                    (Some(&"0"), Some(addr)) | (Some(&"1"), Some(addr)) => {
+                        match u16::from_str_radix(addr, 16) {
+                            Ok(addr) => {
+                                if cmd == "Z" {
+                                    self.breakpoints.insert(addr);
+                                } else {
+                                    self.breakpoints.remove(&addr);
+                                }
+                                String::from("OK")
+                            }
+                            Err(_) => String::from("E01"),
+                        }
+                    }
+                    _ => String::new(),
+                }
+            }
+            "H" => String::from("OK"),
+            "k" => std::process::exit(0),
+            "D" => {
+                self.send_packet("OK");
+                self.detach();
+                return None;
+            }
+            "q" => query(body),
+            _ => String::new(), //未対応のパケットには空で返す
+        };
+        Some(reply)
+    }
+
+    fn detach(&mut self) {
+        self.stream = None;
+        self.breakpoints.clear();
+        self.stepping = false;
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut buf = [0; 1];
+        match self.stream.as_mut()?.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => self.read_byte(),
+            _ => None,
+        }
+    }
+
+    // $packet-data#checksum の形式
+    fn read_packet(&mut self) -> Option<String> {
+        loop {
+            // '$'まで読み飛ばす (ack '+' '-' や止まっているときのCtrl-C)
+            while self.read_byte()? != b'$' {}
+
+            let mut data = vec![];
+            loop {
+                let b = self.read_byte()?;
+                if b == b'#' {
+                    break;
+                }
+                data.push(b);
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).ok()?, 16).ok();
+
+            let sum = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            if checksum == Some(sum) {
+                self.write_raw(b"+");
+                return Some(String::from_utf8_lossy(&data).to_string());
+            }
+            self.write_raw(b"-");
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) {
+        debug!("gdb -> {}", data);
+        let sum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, sum);
+        self.write_raw(packet.as_bytes());
+    }
+
+    fn write_raw(&mut self, data: &[u8]) {
+        if let Some(stream) = self.stream.as_mut() {
+            if stream.write_all(data).is_err() {
+                self.stream = None;
+            }
+        }
+    }
+}
+
+fn query(body: &str) -> String {
+    if body.starts_with("Supported") {
+        return String::from("PacketSize=1000;qXfer:features:read+");
+    }
+    if body == "Attached" {
+        return String::from("1");
+    }
+    if let Some(rest) = body.strip_prefix("Xfer:features:read:target.xml:") {
+        // offset,length の分だけ返す 残りがあれば'm'、最後なら'l'
+        if let Some((offset, len)) = rest.split_once(',') {
+            let offset = usize::from_str_radix(offset, 16).unwrap_or(0);
+            let len = usize::from_str_radix(len, 16).unwrap_or(0);
+            let xml = TARGET_XML.as_bytes();
+            if offset >= xml.len() {
+                return String::from("l");
+            }
+            let end = (offset + len).min(xml.len());
+            let prefix = if end == xml.len() { "l" } else { "m" };
+            return format!("{}{}", prefix, String::from_utf8_lossy(&xml[offset..end]));
+        }
+        return String::from("E01");
+    }
+    String::new()
+}
+
+fn reg_len(n: usize) -> usize {
+    if n == 5 {
+        2
+    } else {
+        1
+    }
+}
+
+fn read_registers(cpu: &CPU) -> String {
+    let pc = cpu.program_counter.wrapping_sub(1);
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer,
+        pc & 0xFF,
+        pc >> 8
+    )
+}
+
+fn write_registers(cpu: &mut CPU, data: &str) {
+    let bytes = decode_hex(data);
+    if bytes.len() < 7 {
+        return;
+    }
+    cpu.register_a = bytes[0];
+    cpu.register_x = bytes[1];
+    cpu.register_y = bytes[2];
+    cpu.status = bytes[3];
+    cpu.stack_pointer = bytes[4];
+    set_pc(cpu, (bytes[6] as u16) << 8 | bytes[5] as u16);
+}
+
+fn write_register(cpu: &mut CPU, n: usize, value: &str) -> bool {
+    let bytes = decode_hex(value);
+    if bytes.len() < reg_len(n.min(5)) {
+        return false;
+    }
+    match n {
+        0 => cpu.register_a = bytes[0],
+        1 => cpu.register_x = bytes[0],
+        2 => cpu.register_y = bytes[0],
+        3 => cpu.status = bytes[0],
+        4 => cpu.stack_pointer = bytes[0],
+        5 => set_pc(cpu, (bytes[1] as u16) << 8 | bytes[0] as u16),
+        _ => return false,
+    }
+    true
+}
+
+// 外から見えるPCは実行中の命令のアドレスなので、変わったときだけ書き換える
+// callbackの中でPCを変えるとCPUは新しいPCから実行し直す
+fn set_pc(cpu: &mut CPU, pc: u16) {
+    if pc != cpu.program_counter.wrapping_sub(1) {
+        cpu.set_program_counter(pc);
+    }
+}
+
+fn parse_addr_len(s: &str) -> Option<(u16, u16)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u16::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// パケットはUTF-8とは限らないので、バイトの2つずつで読む
+fn decode_hex(s: &str) -> Vec<u8> {
+    s.as_bytes()
+        .chunks_exact(2)
+        .filter_map(|pair| {
+            let hi = (pair[0] as char).to_digit(16)?;
+            let lo = (pair[1] as char).to_digit(16)?;
+            Some((hi << 4 | lo) as u8)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00a9ff"), vec![0x00, 0xA9, 0xFF]);
+        assert_eq!(decode_hex("A9F"), vec![0xA9]);
+    }
+
+    #[test]
+    fn test_decode_hex_non_ascii() {
+        // 壊れたパケットでもpanicしない
+        let packet = String::from_utf8_lossy(&[0x31, 0x32, 0xFF, 0x33, 0x34]).to_string();
+        assert_eq!(decode_hex(&packet), vec![0x12]);
+    }
+
+    #[test]
+    fn test_parse_addr_len() {
+        assert_eq!(parse_addr_len("8000,10"), Some((0x8000, 0x10)));
+        assert_eq!(parse_addr_len("8000"), None);
+    }
+}
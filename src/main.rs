@@ -2,23 +2,34 @@
 mod apu;
 mod bus;
 mod cartrige;
+mod cdl;
 mod cpu;
+mod debugger;
 mod frame;
+mod gdb;
 mod joypad;
 mod mapper;
 mod opscodes;
 mod palette;
 mod ppu;
+mod ppu_bus;
+mod profiler;
 mod render;
 mod rom;
+mod symbols;
+mod trace_logger;
 
-use crate::cpu::IN_TRACE;
+use crate::cdl::cdl;
 use crate::rom::Rom;
+use crate::symbols::symbols;
 
 use log::{debug, info, trace};
 use once_cell::sync::Lazy;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
@@ -30,10 +41,14 @@ use self::bus::{Bus, Mem};
 use self::cpu::CPU;
 use apu::NesAPU;
 use cartrige::load_rom;
+use debugger::Debugger;
+use gdb::GdbStub;
 use joypad::Joypad;
 
 use frame::Frame;
 use ppu::NesPPU;
+use profiler::Profiler;
+use trace_logger::TraceLogger;
 // initialize SDL
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
@@ -47,11 +62,7 @@ fn main() {
     env_logger::builder()
         .format(|buf, record| {
             let style = buf.style();
-            if unsafe { IN_TRACE } {
-                writeln!(buf, "[TRACE] {}", style.value(record.args()))
-            } else {
-                writeln!(buf, "        {}", style.value(record.args()))
-            }
+            writeln!(buf, "{}", style.value(record.args()))
         })
         .format_timestamp(None)
         .init();
@@ -86,32 +97,84 @@ fn main() {
     key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
 
     //mapper0
-    // let rom = load_rom("rom/mario.nes");
+    // let rom_path = "rom/mario.nes";
     //mapper1
-    let rom = load_rom("rom/dragon_quest4.nes");
+    let rom_path = "rom/dragon_quest4.nes";
     //mapper2
-    // let rom = load_rom("rom/dragon_quest2.nes");
+    // let rom_path = "rom/dragon_quest2.nes";
     //mapper3
-    // let rom = load_rom("rom/dragon_quest1.nes");
+    // let rom_path = "rom/dragon_quest1.nes";
     //mapper4
-    // let rom = load_rom("rom/finalfantasy3.nes");
+    // let rom_path = "rom/finalfantasy3.nes";
+    let rom = load_rom(rom_path);
 
     info!(
         "ROM: mapper={}, mirroring={:?}, chr_ram={}",
         rom.mapper, rom.screen_mirroring, rom.is_chr_ram
     );
 
+    // --sym FILE でシンボルファイル(.nl, .dbg, label=addr)を読む 何回でも指定できる
+    // ROMの隣にあるFCEUXの.nlファイルは指定しなくても読む
+    unsafe {
+        symbols().load_nl_files(rom_path);
+        let mut args = std::env::args();
+        while let Some(arg) = args.next() {
+            if arg == "--sym" {
+                let path = args.next().expect("--sym needs a file");
+                symbols().load(&path).unwrap();
+            }
+        }
+    }
+
+    // --cdl を付けると<rom>.cdlにCode/Data Logを記録する
+    if std::env::args().any(|a| a == "--cdl") {
+        unsafe { cdl().start(rom_path, &rom) };
+    }
+
+    let mapper = rom.mapper;
     unsafe {
         *MAPPER = create_mapper(rom);
     }
 
+    // Escapeで終わるときは、命令の区切りで記録を書き出してから終わる
+    let quit_request = Rc::new(Cell::new(false));
+    let quit = quit_request.clone();
+
+    // --debugを付けるとターミナルのデバッガで止まった状態から始める
+    let mut debugger = if std::env::args().any(|a| a == "--debug") {
+        Some(Debugger::new(quit_request.clone()))
+    } else {
+        None
+    };
+    let break_request = debugger.as_ref().map(|d| d.break_request());
+
+    // --gdb PORT を付けるとGDBのクライアントが接続してくるまで待つ
+    let gdb_port = std::env::args()
+        .skip_while(|a| a != "--gdb")
+        .nth(1)
+        .map(|p| p.parse::<u16>().expect("--gdb needs a port number"));
+    let mut gdb = gdb_port.map(GdbStub::listen);
+
+    // --trace FILE / --trace-ring N で実行した命令を記録する (オプションはtrace_logger.rsを参照)
+    let args: Vec<String> = std::env::args().collect();
+    let mut trace_logger = TraceLogger::from_args(&args);
+    // --profile PREFIX でサブルーチンごとのサイクル数を数える (オプションはprofiler.rsを参照)
+    let mut profiler = Profiler::from_args(&args);
+    // --no-sprite-limit で1ライン8個の制限を外す (F9で切り替え)
+    let mut sprite_limit = !args.iter().any(|a| a == "--no-sprite-limit");
+    // --palette FILE / --palette ntsc で色を変える (オプションはpalette.rsを参照 F10で切り替え)
+    let (palettes, mut palette_idx) = palette::palettes_from_args(&args);
+    unsafe { *palette::palette() = palettes[palette_idx].1.clone() };
+
     let mut now = Instant::now();
     let interval = 1000 * 1000 * 1000 / 60; //60fps per frame
+    let mut frames: usize = 0;
 
-    let apu = NesAPU::new(&sdl_context);
+    let mut apu = NesAPU::new(&sdl_context);
+    apu.set_expansion(&sdl_context, mapper);
     let bus = Bus::new(
         apu,
-        move |ppu: &NesPPU, joypad1: &mut Joypad, frame: &Frame| {
+        move |ppu: &mut NesPPU, joypad1: &mut Joypad, frame: &Frame| {
             //frameのデータをテクスチャに更新します。このテクスチャはゲーム画面を表現します。
             texture.update(None, &frame.data, 256 * 3).unwrap();
 
@@ -127,7 +190,36 @@ fn main() {
                     | Event::KeyDown {
                         keycode: Some(Keycode::Escape),
                         ..
-                    } => std::process::exit(0),
+                    } => quit_request.set(true),
+
+                    // F12でデバッガに入る
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F12),
+                        ..
+                    } => {
+                        if let Some(b) = &break_request {
+                            b.set(true);
+                        }
+                    }
+
+                    // F9でスプライトの制限を切り替える
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F9),
+                        ..
+                    } => {
+                        sprite_limit = !sprite_limit;
+                        info!("sprite limit: {}", sprite_limit);
+                    }
+
+                    // F10でパレットを切り替える
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F10),
+                        ..
+                    } => {
+                        palette_idx = (palette_idx + 1) % palettes.len();
+                        unsafe { *palette::palette() = palettes[palette_idx].1.clone() };
+                        info!("palette: {}", palettes[palette_idx].0);
+                    }
 
                     Event::KeyDown { keycode, .. } => {
                         if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
@@ -143,6 +235,15 @@ fn main() {
                     _ => { /* do nothing */ }
                 }
             }
+
+            ppu.sprite_limit = sprite_limit;
+
+            // 落ちても記録が残るように10秒ごとに保存する
+            frames += 1;
+            if frames % (60 * 10) == 0 {
+                unsafe { cdl().save() };
+            }
+
             let time = now.elapsed().as_nanos();
             if time < interval {
                 sleep(Duration::from_nanos((interval - time) as u64));
@@ -155,12 +256,46 @@ fn main() {
     // これにより、CPUがエミュレーションされ、NESのプログラムを実行できます。
     let mut cpu = CPU::new(bus);
     cpu.reset();
-    cpu.run_with_callback(move |_| {});
-    // cpu.run_with_callback(move |cpu| {
-    //     if log_enabled!(Level::Trace) {
-    //         trace(cpu);
-    //     }
-    // });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        cpu.run_with_callback(|cpu| {
+            if quit.get() {
+                if let Some(trace_logger) = trace_logger.as_mut() {
+                    trace_logger.flush();
+                }
+                if let Some(profiler) = profiler.as_ref() {
+                    profiler.finish();
+                }
+                unsafe {
+                    crate::mapper::mapper().flush();
+                    cdl().save();
+                }
+                std::process::exit(0);
+            }
+            if let Some(trace_logger) = trace_logger.as_mut() {
+                trace_logger.on_instruction(cpu);
+            }
+            if let Some(profiler) = profiler.as_mut() {
+                profiler.on_instruction(cpu);
+            }
+            if let Some(debugger) = debugger.as_mut() {
+                debugger.on_instruction(cpu);
+            }
+            if let Some(gdb) = gdb.as_mut() {
+                gdb.on_instruction(cpu);
+            }
+        });
+    }));
+
+    // panicしたときは直前に実行した命令を出してから終わる
+    if let Err(e) = result {
+        if let Some(trace_logger) = trace_logger.as_mut() {
+            trace_logger.dump();
+        }
+        if let Some(profiler) = profiler.as_ref() {
+            profiler.write();
+        }
+        panic::resume_unwind(e);
+    }
 }
 
 fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
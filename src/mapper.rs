@@ -1,6 +1,11 @@
+use crate::ppu_bus::{PpuBus, PpuFetch};
 use crate::rom::{Mirroring, Rom};
-use log::{debug, info, trace};
-use std::{fs::File, io::Write};
+use log::{debug, info, trace, warn};
+use once_cell::sync::Lazy;
+use std::{fs::File, io::Write, ptr::addr_of_mut, sync::Mutex};
+
+mod mmc5;
+use self::mmc5::Mapper5;
 
 pub fn create_mapper(rom: Rom) -> Box<dyn Mapper> {
     let mut mapper: Box<dyn Mapper> = match rom.mapper {
@@ -9,6 +14,15 @@ pub fn create_mapper(rom: Rom) -> Box<dyn Mapper> {
         2 => Box::new(Mapper2::new()),
         3 => Box::new(Mapper3::new()),
         4 => Box::new(Mapper4::new()),
+        5 => Box::new(Mapper5::new()),
+        7 => Box::new(Mapper7::new()),
+        9 => Box::new(Mapper9::new()),
+        10 => Box::new(Mapper10::new()),
+        19 => Box::new(Mapper19::new()),
+        21 | 22 | 23 | 25 => Box::new(MapperVrc::new()),
+        24 | 26 => Box::new(MapperVrc6::new()),
+        69 => Box::new(Mapper69::new()),
+        11 | 66 | 71 | 79 | 94 | 140 | 180 | 185 => Box::new(MapperDiscrete::new()),
         _ => panic!("Not support mapper"),
     };
 
@@ -16,7 +30,7 @@ pub fn create_mapper(rom: Rom) -> Box<dyn Mapper> {
     return mapper;
 }
 
-pub trait Mapper: Send {
+pub trait Mapper: Send + PpuBus {
     //インターフェースだけを定義
     fn set_rom(&mut self, rom: Rom);
     fn is_chr_ram(&mut self) -> bool;
@@ -24,28 +38,158 @@ pub trait Mapper: Send {
     fn write(&mut self, addr: u16, data: u8);
     fn mirroring(&self) -> Mirroring;
 
+    // $4020~$5FFFのマッパーのレジスタ (MMC5など) なければオープンバス
+    fn read_register(&mut self, addr: u16) -> u8 {
+        (addr >> 8) as u8
+    }
+    fn write_register(&mut self, _addr: u16, _data: u8) {}
+    // PPUのレジスタ($2000~$2007)への書き込みを覗く MMC5は$2000で8x16モードを知る
+    fn snoop_ppu_register(&mut self, _addr: u16, _data: u8) {}
+
     fn write_prg_ram(&mut self, addr: u16, data: u8);
     fn read_prg_ram(&self, addr: u16) -> u8;
     fn load_prg_ram(&mut self, raw: &Vec<u8>);
+    // デバッガから書き換えるとき 書き込み禁止やレジスタを無視して直接書く PRG-RAMがなければfalse
+    fn poke_prg_ram(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
 
     fn read_prg_rom(&self, addr: u16) -> u8;
     fn write_chr_rom(&mut self, addr: u16, value: u8);
     fn read_chr_rom(&self, addr: u16) -> u8;
 
-    fn scanline(&mut self, scanline: usize, show_background: bool);
+    //CPU/PPUのアドレスが今のバンクでROM上のどこにあたるか
+    fn prg_rom_addr(&self, addr: u16) -> usize;
+    fn chr_rom_addr(&self, addr: u16) -> usize;
+    // PRG-ROMの切り替えの単位と固定されているバンク (逆アセンブラが使う)
+    #[allow(dead_code)]
+    fn prg_layout(&self) -> PrgLayout;
+
+    // CPUの1サイクル(M2)ごとに呼ばれる
+    fn cpu_clock(&mut self) {}
+    // 終了するときに呼ばれる まとめて保存しているものを書き出す
+    fn flush(&mut self) {}
     fn is_irq(&mut self) -> bool;
 }
 
+#[allow(dead_code)]
+pub struct PrgLayout {
+    pub bank_size: usize,
+    pub fixed: Vec<(usize, u16)>, //固定されているバンクの番号と、CPUから見えるアドレス
+    pub switch_base: u16,         //切り替えるバンクが見える一番前のアドレス
+    pub mirrored: bool,           //NROM-128のように16kBが$8000と$C000の両方に見える
+}
+
+#[allow(dead_code)]
+impl PrgLayout {
+    // fixed_tailは後ろのバンクから固定される位置 (最後のバンクがfixed_tailの最後)
+    pub fn new(prg_len: usize, bank_size: usize, fixed_tail: &[u16]) -> Self {
+        let count = (prg_len / bank_size).max(1);
+        let fixed = fixed_tail
+            .iter()
+            .rev()
+            .take(count)
+            .enumerate()
+            .map(|(i, base)| (count - 1 - i, *base))
+            .collect();
+        PrgLayout {
+            bank_size,
+            fixed,
+            switch_base: 0x8000,
+            mirrored: false,
+        }
+    }
+
+    // PRGの切り替えがない 16kBなら$C000に置いて$8000はミラー
+    pub fn nrom(prg_len: usize) -> Self {
+        if prg_len <= 0x4000 {
+            PrgLayout {
+                mirrored: true,
+                ..PrgLayout::new(prg_len, 0x4000, &[0xC000])
+            }
+        } else {
+            PrgLayout::new(prg_len, 0x8000, &[0x8000])
+        }
+    }
+}
+
+// 各binのMAPPER static mutへの参照はここでだけ作る
+pub unsafe fn mapper() -> &'static mut dyn Mapper {
+    &mut ***addr_of_mut!(crate::MAPPER)
+}
+
+// バンク番号からROMの中の位置を出す 単純な基板はみんなこれを使う
+// 番号がROMより大きいときは折り返し、ROMがバンクより小さいときはミラーになる
+fn bank_addr(rom_len: usize, bank_size: usize, bank: usize, addr: u16) -> usize {
+    (bank * bank_size + (addr as usize & (bank_size - 1))) % rom_len.max(1)
+}
+
+// バッテリーバックアップのあるPRG-RAMの保存
+// 書き込みのたびにファイルを作り直すと重いので、書き込まれてから1秒たったらまとめて書き出す
+const SAVE_INTERVAL: usize = 1_789_773; //CPUのクロックで1秒
+
+pub struct BatterySave {
+    dirty: bool,
+    cycles: usize,
+}
+
+impl BatterySave {
+    pub fn new() -> Self {
+        BatterySave {
+            dirty: false,
+            cycles: 0,
+        }
+    }
+
+    // PRG-RAMに書き込まれた バッテリーがなければ保存しない
+    pub fn mark_dirty(&mut self, rom: &Rom) {
+        self.dirty |= rom.battery;
+    }
+
+    // cpu_clockから呼ぶ 書き出す時間になったらtrue
+    pub fn clock(&mut self) -> bool {
+        if !self.dirty {
+            return false;
+        }
+        self.cycles += 1;
+        self.cycles >= SAVE_INTERVAL
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn save(&mut self, rom: &Rom, data: &[u8]) {
+        let mut file = File::create(rom.save_data_file.as_str()).unwrap();
+        file.write_all(data).unwrap();
+        file.flush().unwrap();
+        self.dirty = false;
+        self.cycles = 0;
+    }
+}
+
+// バスコンフリクト
+// ROMの上にレジスタがある基板では、書き込むときにROMも同じアドレスの値を出すので、ANDになった値が書かれる
+fn bus_conflict(mapper: &dyn Mapper, addr: u16, data: u8) -> u8 {
+    data & mapper.read_prg_rom(addr)
+}
+
 pub struct Mapper0 {
     pub rom: Rom,
+    prg_ram: Vec<u8>,
 }
 
 impl Mapper0 {
     pub fn new() -> Self {
-        Mapper0 { rom: Rom::empty() }
+        Mapper0 {
+            rom: Rom::empty(),
+            prg_ram: vec![0; 8192], //8kiB テストROMが結果の書き込みに使う
+        }
     }
 }
 
+impl PpuBus for Mapper0 {}
+
 impl Mapper for Mapper0 {
     fn is_chr_ram(&mut self) -> bool {
         self.rom.is_chr_ram
@@ -59,32 +203,54 @@ impl Mapper for Mapper0 {
     fn mirroring(&self) -> Mirroring {
         self.rom.screen_mirroring
     }
-    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
-    fn read_prg_ram(&self, _addr: u16) -> u8 {
-        0
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        self.prg_ram[addr as usize - 0x6000] = data;
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
     }
     fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
 
     fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
         let mut mirror_addr = addr - 0x8000;
         //programのromは16kB刻み prg_rom.len() == 0x4000は16kB
         if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
             //mirror if needed
             mirror_addr = addr % 0x4000;
         }
-        self.rom.prg_rom[mirror_addr as usize]
+        mirror_addr as usize
     }
-
-    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
-    fn read_chr_rom(&self, addr: u16) -> u8 {
-        self.rom.chr_rom[addr as usize]
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        addr as usize
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::nrom(self.rom.prg_rom.len())
     }
-    fn scanline(&mut self, _scanline: usize, _show_background: bool) {}
     fn is_irq(&mut self) -> bool {
         false
     }
 }
 
+// MMC1 (SxROM)
+// iNESのヘッダには基板の種類がないので、ROMとRAMの大きさから決める
+//  SNROM: CHR-RAM 8kB           CHRレジスタのbit4でPRG-RAMを無効にする
+//  SOROM: PRG-RAM 16kB          CHRレジスタのbit3でPRG-RAMのバンク
+//  SUROM: PRG-ROM 512kB         CHRレジスタのbit4でPRG-ROMの256kB単位のバンク
+//  SXROM: SUROM + PRG-RAM 32kB  CHRレジスタのbit2,3でPRG-RAMのバンク
+// CHRが4kBモードのときは、PPUが今読んでいる側(A12)のCHRレジスタが使われる
 pub struct Mapper1 {
     pub rom: Rom,
     prg_ram: Vec<u8>,
@@ -96,9 +262,16 @@ pub struct Mapper1 {
     chr_bank0: u8, //内部レジスタ A000~BFFF
     chr_bank1: u8, //内部レジスタ C000~DFFF
     prg_bank: u8,  //内部レジスタ E000~FFFF
+
+    m2_cycles: usize,
+    last_write: Option<usize>, //シリアルポートに最後に書き込んだM2サイクル
+    ppu_a12: bool,
+    battery: BatterySave,
 }
 
-//SUROM
+const MMC1_PRG_RAM_BANK_SIZE: usize = 8 * 1024; //8kB
+const MMC1_PRG_OUTER_BANK_SIZE: usize = 256 * 1024; //256kB
+
 impl Mapper1 {
     pub fn new() -> Self {
         Mapper1 {
@@ -110,6 +283,10 @@ impl Mapper1 {
             chr_bank0: 0,
             chr_bank1: 0,
             prg_bank: 0,
+            m2_cycles: 0,
+            last_write: None,
+            ppu_a12: false,
+            battery: BatterySave::new(),
         }
     }
 
@@ -118,37 +295,53 @@ impl Mapper1 {
         self.shift_count = 0;
     }
 
-    fn chr_rom_addr(&self, addr: u16) -> usize {
-        addr as usize
+    // SxROMでPRGのバンクに使うCHRレジスタ
+    fn outer_register(&self) -> u8 {
+        if self.control & 0x10 != 0 && self.ppu_a12 {
+            self.chr_bank1
+        } else {
+            self.chr_bank0
+        }
+    }
+
+    fn is_snrom(&self) -> bool {
+        self.rom.is_chr_ram && self.rom.prg_rom.len() <= MMC1_PRG_OUTER_BANK_SIZE
+    }
+
+    // 無効になっているときはNone
+    fn prg_ram_addr(&self, addr: u16) -> Option<usize> {
+        let register = self.outer_register();
+        // MMC1B以降はPRGレジスタのbit4でPRG-RAMを無効にできる
+        if self.prg_bank & 0x10 != 0 || (self.is_snrom() && register & 0x10 != 0) {
+            return None;
+        }
+        let bank = match self.prg_ram.len() / MMC1_PRG_RAM_BANK_SIZE {
+            2 => (register >> 3) & 0x01, //SOROM
+            4 => (register >> 2) & 0x03, //SXROM
+            _ => 0,
+        };
+        Some(bank as usize * MMC1_PRG_RAM_BANK_SIZE + (addr as usize - 0x6000))
+    }
+}
 
-        // SUROM以外には必要
-        // let bank_size = 4 * 1024 as usize; //4kB
-        // match (self.control & 0x10) >> 4 {
-        //     0 => {
-        //         //一度に8kBを切り替える
-        //         let bank = self.chr_bank0 & 0x1F;
-        //         addr as usize + bank_size * bank as usize
-        //     }
-        //     1 => match addr {
-        //         0x0000..=0x0FFF => {
-        //             let bank = self.chr_bank0 & 0x1F;
-        //             addr as usize + bank_size * bank as usize
-        //         }
-        //         0x1000..=0x1FFF => {
-        //             let bank = self.chr_bank1 & 0x1F;
-        //             (addr as usize - 0x1000) + bank_size * bank as usize
-        //         }
-        //         _ => panic!("cant be"),
-        //     },
-        //     _ => panic!("cant be"),
-        // }
+impl PpuBus for Mapper1 {
+    fn ppu_fetch(&mut self, addr: u16, _kind: PpuFetch) {
+        if addr < 0x2000 {
+            self.ppu_a12 = addr & 0x1000 != 0;
+        }
     }
 }
+
 impl Mapper for Mapper1 {
     fn is_chr_ram(&mut self) -> bool {
         self.rom.is_chr_ram
     }
     fn set_rom(&mut self, rom: Rom) {
+        let prg_ram_size = match rom.prg_ram_size {
+            0 => MMC1_PRG_RAM_BANK_SIZE,
+            size => size,
+        };
+        self.prg_ram = vec![0xFF; prg_ram_size];
         self.load_prg_ram(&rom.save_data);
         self.rom = rom;
     }
@@ -158,8 +351,19 @@ impl Mapper for Mapper1 {
         // sta $XXXX    000edcba->a1000
         // lsa a        0000edcb  a1000
 
+        // INCなどで続けて2回書き込まれたときは2回目を無視する
+        let consecutive = self
+            .last_write
+            .is_some_and(|last| self.m2_cycles.wrapping_sub(last) < 2);
+        self.last_write = Some(self.m2_cycles);
+        if consecutive {
+            return;
+        }
+
         if data & 0x80 != 0 {
             self.reset();
+            //リセットすると$C000~が最後のバンクに固定される
+            self.control |= 0x0C;
             return;
         }
 
@@ -183,8 +387,8 @@ impl Mapper for Mapper1 {
 
     fn mirroring(&self) -> Mirroring {
         match self.control & 0x03 {
-            0 => Mirroring::VERTICAL,   // ? one-screen, lower bank
-            1 => Mirroring::HORIZONTAL, // ? onescreen, upper bank
+            0 => Mirroring::SINGLE_SCREEN_A,
+            1 => Mirroring::SINGLE_SCREEN_B,
             2 => Mirroring::VERTICAL,
             3 => Mirroring::HORIZONTAL,
             _ => panic!("not support mirroring mode."),
@@ -192,113 +396,120 @@ impl Mapper for Mapper1 {
     }
 
     fn write_prg_ram(&mut self, addr: u16, data: u8) {
-        // prg_ramは6000から始まる
-        self.prg_ram[addr as usize - 0x6000] = data;
-
-        //保存処理
-        let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
-        file.write_all(&self.prg_ram).unwrap();
-        file.flush().unwrap();
+        let addr = match self.prg_ram_addr(addr) {
+            Some(a) => a,
+            None => return,
+        };
+        self.prg_ram[addr] = data;
+        self.battery.mark_dirty(&self.rom);
     }
 
     fn read_prg_ram(&self, addr: u16) -> u8 {
-        // フラグ設定
-        match addr {
-            // 気球入手済み
-            0x628E => {
-                return 0x02;
-            }
-            // 勇者のステータス最強
-            0x6007..=0x600B => {
-                return 0xFF;
-            }
-            // 敵一体目のHPが0
-            0x727E..=0x727F => {
-                return 0x00;
+        match self.prg_ram_addr(addr) {
+            Some(a) => self.prg_ram[a],
+            None => (addr >> 8) as u8, //オープンバス
+        }
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        match self.prg_ram_addr(addr) {
+            Some(a) => {
+                self.prg_ram[a] = data;
+                true
             }
-            _ => {}
+            None => false,
         }
-        self.prg_ram[addr as usize - 0x6000]
     }
 
     fn load_prg_ram(&mut self, raw: &Vec<u8>) {
         if raw.is_empty() {
             return;
         }
-        self.prg_ram = raw.to_vec()
+        let size = self.prg_ram.len();
+        self.prg_ram = raw.to_vec();
+        self.prg_ram.resize(size, 0xFF);
     }
 
     fn read_prg_rom(&self, addr: u16) -> u8 {
-        // デバッグモード
-        if addr == 0xC000 {
-            return 0x01;
+        let addr = self.prg_rom_addr(addr);
+        self.rom.prg_rom[addr]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn cpu_clock(&mut self) {
+        self.m2_cycles += 1;
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
         }
+    }
 
+    fn prg_rom_addr(&self, addr: u16) -> usize {
         let bank_size = 16 * 1024 as usize; //16kB
         let bank_max = self.rom.prg_rom.len() / bank_size;
-        let mut bank = self.prg_bank & 0x0F;
-        let mut first_bank = 0x00;
-        let mut last_bank = bank_max - 1;
-
-        //chr_bankの5ビット目が立っていない場合
-        if self.chr_bank0 & 0x10 != 0 {
-            bank = bank | 0x10;
-            first_bank = first_bank | 0x10;
-            last_bank = last_bank | 0x10;
+
+        // SUROM/SXROMは256kBごとにCHRレジスタのbit4で切り替える
+        let outer = if self.rom.prg_rom.len() > MMC1_PRG_OUTER_BANK_SIZE {
+            (self.outer_register() & 0x10) as usize
         } else {
-            bank = bank & 0x0F;
-            first_bank = first_bank & 0x0F;
-            last_bank = last_bank & 0x0F;
-        }
+            0
+        };
+        let bank = (outer | (self.prg_bank & 0x0F) as usize) % bank_max;
+        let first_bank = outer % bank_max;
+        let last_bank = (outer | 0x0F) % bank_max;
 
         match (self.control & 0x0C) >> 2 {
             0 | 1 => {
                 // バンク番号の下位ビットを無視して32kBを$8000に切り替える
-                self.rom.prg_rom
-                    [((addr as usize - 0x8000) + bank_size * (bank & 0x1E) as usize) as usize]
+                (addr as usize - 0x8000) + bank_size * (bank & !0x01)
             }
-
             2 => {
                 //最初のバンクを$8000に固定し16kBバンクを$C000に切り替える
                 match addr {
-                    0x8000..=0xBFFF => {
-                        self.rom.prg_rom[addr as usize - 0x8000 + bank_size * first_bank]
-                    }
-                    0xC000..=0xFFFF => {
-                        self.rom.prg_rom
-                            [((addr as usize - 0xC000) + bank_size * bank as usize) as usize]
-                    }
+                    0x8000..=0xBFFF => addr as usize - 0x8000 + bank_size * first_bank,
+                    0xC000..=0xFFFF => (addr as usize - 0xC000) + bank_size * bank,
                     _ => panic!("cant be"),
                 }
             }
             3 => {
-                //最初のバンクを$C000に固定し16kBバンクを$8000に切り替える
+                //最後のバンクを$C000に固定し16kBバンクを$8000に切り替える
                 match addr {
-                    0x8000..=0xBFFF => {
-                        self.rom.prg_rom
-                            [((addr as usize - 0x8000) + bank_size * bank as usize) as usize]
-                    }
-                    0xC000..=0xFFFF => {
-                        self.rom.prg_rom[addr as usize - 0xC000 + bank_size * last_bank]
-                    }
-                    _ => {
-                        println!("addr: {:04X}", addr);
-                        panic!("cant be")
-                    }
+                    0x8000..=0xBFFF => (addr as usize - 0x8000) + bank_size * bank,
+                    0xC000..=0xFFFF => addr as usize - 0xC000 + bank_size * last_bank,
+                    _ => panic!("cant be"),
                 }
             }
             _ => panic!("cant be"),
         }
     }
 
-    fn write_chr_rom(&mut self, addr: u16, value: u8) {
-        self.rom.chr_rom[addr as usize] = value;
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank = match (self.control & 0x10) >> 4 {
+            //一度に8kBを切り替える 下位ビットは無視
+            0 => (self.chr_bank0 & 0x1E) as usize + (addr as usize >> 12),
+            _ => match addr {
+                0x0000..=0x0FFF => self.chr_bank0 as usize,
+                0x1000..=0x1FFF => self.chr_bank1 as usize,
+                _ => panic!("cant be"),
+            },
+        };
+        // CHR-RAMの8kBではレジスタの上位ビットはPRG用なので、大きさで折り返す
+        bank_addr(self.rom.chr_rom.len(), 4 * 1024, bank, addr)
     }
-
-    fn read_chr_rom(&self, addr: u16) -> u8 {
-        self.rom.chr_rom[addr as usize]
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 16 * 1024, &[0xC000])
     }
-    fn scanline(&mut self, _scanline: usize, _show_background: bool) {}
     fn is_irq(&mut self) -> bool {
         false
     }
@@ -318,6 +529,8 @@ impl Mapper2 {
     }
 }
 
+impl PpuBus for Mapper2 {}
+
 impl Mapper for Mapper2 {
     fn is_chr_ram(&mut self) -> bool {
         self.rom.is_chr_ram
@@ -325,8 +538,13 @@ impl Mapper for Mapper2 {
     fn set_rom(&mut self, rom: Rom) {
         self.rom = rom;
     }
-    fn write(&mut self, _addr: u16, data: u8) {
-        self.bank_select = data;
+    fn write(&mut self, addr: u16, data: u8) {
+        // サブマッパー2はバスコンフリクトあり
+        self.bank_select = if self.rom.submapper == 2 {
+            bus_conflict(self, addr, data)
+        } else {
+            data
+        };
     }
 
     fn mirroring(&self) -> Mirroring {
@@ -339,21 +557,7 @@ impl Mapper for Mapper2 {
     fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
 
     fn read_prg_rom(&self, addr: u16) -> u8 {
-        let bank_size = 16 * 1024 as usize; //16kB
-        let bank_max = self.rom.prg_rom.len() / bank_size;
-        match addr {
-            0x8000..=0xBFFF => {
-                //bank select
-                //addr - 0x8000で0からのスタートにする
-                let bank = self.bank_select & 0x0F; //下位4ビットを取ってくる
-                self.rom.prg_rom[((addr as usize - 0x8000) + bank_size * bank as usize) as usize]
-            }
-            0xC000..=0xFFFF => {
-                // fix last bank
-                self.rom.prg_rom[((addr as usize - 0xC000) + bank_size * (bank_max - 1)) as usize]
-            }
-            _ => panic!("cant be"),
-        }
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
     }
 
     fn write_chr_rom(&mut self, addr: u16, value: u8) {
@@ -362,7 +566,23 @@ impl Mapper for Mapper2 {
     fn read_chr_rom(&self, addr: u16) -> u8 {
         self.rom.chr_rom[addr as usize]
     }
-    fn scanline(&mut self, _scanline: usize, _show_background: bool) {}
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 16 * 1024 as usize; //16kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match addr {
+            0x8000..=0xBFFF => (self.bank_select & 0x0F) as usize, //下位4ビットを取ってくる
+            0xC000..=0xFFFF => bank_max - 1,                       // fix last bank
+            _ => panic!("cant be"),
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        addr as usize
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 16 * 1024, &[0xC000])
+    }
     fn is_irq(&mut self) -> bool {
         false
     }
@@ -382,6 +602,8 @@ impl Mapper3 {
     }
 }
 
+impl PpuBus for Mapper3 {}
+
 impl Mapper for Mapper3 {
     fn is_chr_ram(&mut self) -> bool {
         self.rom.is_chr_ram
@@ -389,8 +611,13 @@ impl Mapper for Mapper3 {
     fn set_rom(&mut self, rom: Rom) {
         self.rom = rom;
     }
-    fn write(&mut self, _addr: u16, data: u8) {
-        self.bank_select = data;
+    fn write(&mut self, addr: u16, data: u8) {
+        // サブマッパー2はバスコンフリクトあり
+        self.bank_select = if self.rom.submapper == 2 {
+            bus_conflict(self, addr, data)
+        } else {
+            data
+        };
     }
 
     fn mirroring(&self) -> Mirroring {
@@ -403,23 +630,43 @@ impl Mapper for Mapper3 {
     fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
 
     fn read_prg_rom(&self, addr: u16) -> u8 {
-        self.rom.prg_rom[addr as usize - 0x8000]
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
     }
 
     fn write_chr_rom(&mut self, addr: u16, value: u8) {
         self.rom.chr_rom[addr as usize] = value;
     }
     fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        addr as usize - 0x8000
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
         let bank_size = 8 * 1024 as usize; //8kiB
         let bank = self.bank_select & 0x03; //最下位2bit
-        self.rom.chr_rom[(addr as usize + bank_size * bank as usize) as usize]
+        bank_addr(self.rom.chr_rom.len(), bank_size, bank as usize, addr)
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::nrom(self.rom.prg_rom.len())
     }
-    fn scanline(&mut self, _scanline: usize, _show_background: bool) {}
     fn is_irq(&mut self) -> bool {
         false
     }
 }
 
+// MMC3 IRQのカウンターの違い NES 2.0のサブマッパーで選ぶ
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mmc3Irq {
+    New, //MMC3B(Sharp)/MMC3C カウンターが0になったクロックでは毎回IRQ (ラッチが0なら毎ライン)
+    Old, //MMC3A/MMC3B(NEC) デクリメントで0になったか、$C001の後のリロードで0になったときだけ
+}
+
+// A12がこのM2サイクル数以上Lowだったときだけ立ち上がりとして数える
+// スプライトのフェッチの間のネームテーブルの読み込みでは数えないようにするため
+const MMC3_A12_FILTER: usize = 3;
+
 pub struct Mapper4 {
     pub rom: Rom,
     prg_ram: Vec<u8>,
@@ -432,6 +679,12 @@ pub struct Mapper4 {
     irq_reload: bool,
     irq_enable: bool,
     is_irq: bool,
+    irq_revision: Mmc3Irq,
+
+    m2_cycles: usize,
+    a12: bool,
+    a12_low_since: usize, //A12がLowになったときのM2サイクル
+    battery: BatterySave,
 }
 
 impl Mapper4 {
@@ -448,47 +701,48 @@ impl Mapper4 {
             irq_reload: false,
             irq_enable: false,
             is_irq: false,
+            irq_revision: Mmc3Irq::New,
+            m2_cycles: 0,
+            a12: false,
+            a12_low_since: 0,
+            battery: BatterySave::new(),
         }
     }
-    fn get_chr_rom_addr(&self, addr: u16) -> usize {
-        let bank_size = 1 * 1024 as usize; //1kiB
 
-        let mode = self.bank_select & 0x80;
-        let r0_bank = self.bank_data[0] as usize;
-        let r1_bank = self.bank_data[1] as usize;
-        let r2_bank = self.bank_data[2] as usize;
-        let r3_bank = self.bank_data[3] as usize;
-        let r4_bank = self.bank_data[4] as usize;
-        let r5_bank = self.bank_data[5] as usize;
+    // A12の立ち上がりでスキャンラインカウンターを進める
+    fn clock_irq_counter(&mut self) {
+        let reloaded = self.irq_reload;
+        let decremented = if self.irq_latch_counter == 0 || self.irq_reload {
+            self.irq_latch_counter = self.irq_latch;
+            self.irq_reload = false;
+            false
+        } else {
+            self.irq_latch_counter -= 1;
+            true
+        };
+
+        let fire = match self.irq_revision {
+            Mmc3Irq::New => self.irq_latch_counter == 0,
+            Mmc3Irq::Old => self.irq_latch_counter == 0 && (decremented || reloaded),
+        };
+        if fire && self.irq_enable {
+            self.is_irq = true;
+        }
+    }
+}
 
-        match mode {
-            0 => match addr {
-                // 0x0000..=0x03FF
-                // 0x0400..=0x07FF
-                0x0000..=0x07FF => addr as usize + r0_bank * bank_size,
-                // 0x0800..=0x0BFF
-                // 0x0C00..=0x0FFF
-                0x0800..=0x0FFF => (addr as usize - 0x0800) + r1_bank * bank_size,
-                0x1000..=0x13FF => (addr as usize - 0x1000) + r2_bank * bank_size,
-                0x1400..=0x17FF => (addr as usize - 0x1400) + r3_bank * bank_size,
-                0x1800..=0x1BFF => (addr as usize - 0x1800) + r4_bank * bank_size,
-                0x1C00..=0x1FFF => (addr as usize - 0x1C00) + r5_bank * bank_size,
-                _ => panic!("cant be"),
-            },
-            _ => match addr {
-                0x0000..=0x03FF => addr as usize + r2_bank * bank_size,
-                0x0400..=0x07FF => (addr as usize - 0x0400) + r3_bank * bank_size,
-                0x0800..=0x0BFF => (addr as usize - 0x0800) + r4_bank * bank_size,
-                0x0C00..=0x0FFF => (addr as usize - 0x0C00) + r5_bank * bank_size,
-                // 0x1000..=0x13FF
-                // 0x1400..=0x17FF
-                0x1000..=0x17FF => (addr as usize - 0x1000) + r0_bank * bank_size,
-                // 0x1800..=0x1BFF
-                // 0x1C00..=0x1FFF
-                0x1800..=0x1FFF => (addr as usize - 0x1800) + r1_bank * bank_size,
-                _ => panic!("cant be"),
-            },
+impl PpuBus for Mapper4 {
+    // 背景とスプライトのパターンテーブルが別なら、1ラインに1回A12が立ち上がる
+    // (8x16のスプライトではタイル番号によって変わる)
+    fn ppu_fetch(&mut self, addr: u16, _kind: PpuFetch) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 && self.m2_cycles - self.a12_low_since >= MMC3_A12_FILTER {
+            self.clock_irq_counter();
         }
+        if !a12 && self.a12 {
+            self.a12_low_since = self.m2_cycles;
+        }
+        self.a12 = a12;
     }
 }
 
@@ -498,6 +752,10 @@ impl Mapper for Mapper4 {
     }
     fn set_rom(&mut self, rom: Rom) {
         self.load_prg_ram(&rom.save_data);
+        self.irq_revision = match rom.submapper {
+            4 => Mmc3Irq::Old,
+            _ => Mmc3Irq::New,
+        };
         self.rom = rom;
     }
     fn write(&mut self, addr: u16, data: u8) {
@@ -550,6 +808,10 @@ impl Mapper for Mapper4 {
     }
 
     fn mirroring(&self) -> Mirroring {
+        // 4画面のカートリッジではミラーリングのレジスタは効かない
+        if self.rom.screen_mirroring == Mirroring::FOUR_SCREEN {
+            return Mirroring::FOUR_SCREEN;
+        }
         if self.mirroring & 0x01 == 0 {
             Mirroring::VERTICAL
         } else {
@@ -559,16 +821,16 @@ impl Mapper for Mapper4 {
     fn write_prg_ram(&mut self, addr: u16, data: u8) {
         // prg_ramは6000から始まる
         self.prg_ram[addr as usize - 0x6000] = data;
-
-        //保存処理
-        let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
-        file.write_all(&self.prg_ram).unwrap();
-        file.flush().unwrap();
+        self.battery.mark_dirty(&self.rom);
     }
 
     fn read_prg_ram(&self, addr: u16) -> u8 {
         self.prg_ram[addr as usize - 0x6000]
     }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
+    }
 
     fn load_prg_ram(&mut self, raw: &Vec<u8>) {
         if raw.is_empty() {
@@ -578,6 +840,19 @@ impl Mapper for Mapper4 {
     }
 
     fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let mirror_addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[mirror_addr] = value;
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
         let bank_size = 8 * 1024 as usize; //8kiB
         let bank_max = (self.rom.prg_rom.len() / bank_size) as usize;
 
@@ -590,66 +865,2256 @@ impl Mapper for Mapper4 {
         match mode {
             0 => match addr {
                 // R6, R7, (-2), (-1)
-                0x8000..=0x9FFF => {
-                    self.rom.prg_rom[((addr - 0x8000) as usize + r6_bank * bank_size) as usize]
-                }
-                0xA000..=0xBFFF => {
-                    self.rom.prg_rom[((addr - 0xA000) as usize + r7_bank * bank_size) as usize]
-                }
-                0xC000..=0xDFFF => {
-                    self.rom.prg_rom[((addr - 0xC000) as usize + last_bank2 * bank_size) as usize]
-                }
-                0xE000..=0xFFFF => {
-                    self.rom.prg_rom[((addr - 0xE000) as usize + last_bank * bank_size) as usize]
-                }
+                0x8000..=0x9FFF => (addr - 0x8000) as usize + r6_bank * bank_size,
+                0xA000..=0xBFFF => (addr - 0xA000) as usize + r7_bank * bank_size,
+                0xC000..=0xDFFF => (addr - 0xC000) as usize + last_bank2 * bank_size,
+                0xE000..=0xFFFF => (addr - 0xE000) as usize + last_bank * bank_size,
                 _ => panic!("cant be"),
             },
             _ => match addr {
                 // (-2), R7, R6, (-1)
-                0x8000..=0x9FFF => {
-                    self.rom.prg_rom[((addr - 0x8000) as usize + last_bank2 * bank_size) as usize]
-                }
-                0xA000..=0xBFFF => {
-                    self.rom.prg_rom[((addr - 0xA000) as usize + r7_bank * bank_size) as usize]
-                }
-                0xC000..=0xDFFF => {
-                    self.rom.prg_rom[((addr - 0xC000) as usize + r6_bank * bank_size) as usize]
-                }
-                0xE000..=0xFFFF => {
-                    self.rom.prg_rom[((addr - 0xE000) as usize + last_bank * bank_size) as usize]
-                }
+                0x8000..=0x9FFF => (addr - 0x8000) as usize + last_bank2 * bank_size,
+                0xA000..=0xBFFF => (addr - 0xA000) as usize + r7_bank * bank_size,
+                0xC000..=0xDFFF => (addr - 0xC000) as usize + r6_bank * bank_size,
+                0xE000..=0xFFFF => (addr - 0xE000) as usize + last_bank * bank_size,
                 _ => panic!("cant be"),
             },
         }
     }
 
-    fn write_chr_rom(&mut self, addr: u16, value: u8) {
-        let mirror_addr = self.get_chr_rom_addr(addr) as usize;
-        self.rom.chr_rom[mirror_addr] = value;
-    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 1024; //1kiB
 
-    fn read_chr_rom(&self, addr: u16) -> u8 {
-        self.rom.chr_rom[self.get_chr_rom_addr(addr) as usize]
+        let mode = self.bank_select & 0x80;
+        let r0_bank = self.bank_data[0] as usize;
+        let r1_bank = self.bank_data[1] as usize;
+        let r2_bank = self.bank_data[2] as usize;
+        let r3_bank = self.bank_data[3] as usize;
+        let r4_bank = self.bank_data[4] as usize;
+        let r5_bank = self.bank_data[5] as usize;
+
+        match mode {
+            0 => match addr {
+                // 0x0000..=0x03FF
+                // 0x0400..=0x07FF
+                0x0000..=0x07FF => addr as usize + r0_bank * bank_size,
+                // 0x0800..=0x0BFF
+                // 0x0C00..=0x0FFF
+                0x0800..=0x0FFF => (addr as usize - 0x0800) + r1_bank * bank_size,
+                0x1000..=0x13FF => (addr as usize - 0x1000) + r2_bank * bank_size,
+                0x1400..=0x17FF => (addr as usize - 0x1400) + r3_bank * bank_size,
+                0x1800..=0x1BFF => (addr as usize - 0x1800) + r4_bank * bank_size,
+                0x1C00..=0x1FFF => (addr as usize - 0x1C00) + r5_bank * bank_size,
+                _ => panic!("cant be"),
+            },
+            _ => match addr {
+                0x0000..=0x03FF => addr as usize + r2_bank * bank_size,
+                0x0400..=0x07FF => (addr as usize - 0x0400) + r3_bank * bank_size,
+                0x0800..=0x0BFF => (addr as usize - 0x0800) + r4_bank * bank_size,
+                0x0C00..=0x0FFF => (addr as usize - 0x0C00) + r5_bank * bank_size,
+                // 0x1000..=0x13FF
+                // 0x1400..=0x17FF
+                0x1000..=0x17FF => (addr as usize - 0x1000) + r0_bank * bank_size,
+                // 0x1800..=0x1BFF
+                // 0x1C00..=0x1FFF
+                0x1800..=0x1FFF => (addr as usize - 0x1800) + r1_bank * bank_size,
+                _ => panic!("cant be"),
+            },
+        }
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xC000, 0xE000])
     }
 
-    fn scanline(&mut self, scanline: usize, show_bkgd_or_sprt: bool) {
-        if scanline <= 240 && show_bkgd_or_sprt {
-            if self.irq_latch_counter == 0 || self.irq_reload {
-                self.irq_latch_counter = self.irq_latch;
-                self.irq_reload = false;
-            } else {
-                self.irq_latch_counter -= 1;
-            }
+    fn cpu_clock(&mut self) {
+        self.m2_cycles += 1;
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+
+    // $E000に書き込まれるまでIRQを出し続ける
+    fn is_irq(&mut self) -> bool {
+        self.is_irq
+    }
+}
+
+// AxROM
+// $8000~$FFFFへの書き込みで32kBのPRG-ROMと1画面ミラーリングのページを切り替える
+pub struct Mapper7 {
+    pub rom: Rom,
+    bank_select: u8,
+}
+
+impl Mapper7 {
+    pub fn new() -> Self {
+        Mapper7 {
+            rom: Rom::empty(),
+            bank_select: 0,
+        }
+    }
+}
+
+impl PpuBus for Mapper7 {}
+
+impl Mapper for Mapper7 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        // bit0~2: PRGバンク bit4: ネームテーブルのページ
+        // サブマッパー2(AMROM)はバスコンフリクトあり
+        self.bank_select = if self.rom.submapper == 2 {
+            bus_conflict(self, addr, data)
+        } else {
+            data
+        };
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank_select & 0x10 == 0 {
+            Mirroring::SINGLE_SCREEN_A
+        } else {
+            Mirroring::SINGLE_SCREEN_B
+        }
+    }
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        self.rom.chr_rom[addr as usize] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[addr as usize]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 32 * 1024; //32kB
+        let bank = (self.bank_select & 0x07) as usize;
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        addr as usize
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 32 * 1024, &[])
+    }
+    fn is_irq(&mut self) -> bool {
+        false
+    }
+}
+
+// MMC2/MMC4のCHRラッチ
+// PPUがタイル$FD/$FEの上位プレーン($xFD8~$xFDF/$xFE8~$xFEF)を読むと、そのパターンテーブルのCHRバンクが切り替わる
+// MMC2の$0000側だけは1行目の$0FD8/$0FE8ちょうどのときだけ
+// 切り替わるのは読んだ後なので、$FD/$FEのタイル自体は前のバンクで描かれる
+struct ChrLatch {
+    latch: [u8; 2],      //$0000~, $1000~ それぞれ$FDか$FE
+    banks: [[u8; 2]; 2], //[パターンテーブル][$FDのとき, $FEのとき] 4kBバンク
+    exact_low: bool,     //MMC2は$0000側が$0FD8/$0FE8ちょうどのときだけ切り替わる
+}
+
+impl ChrLatch {
+    fn new(exact_low: bool) -> Self {
+        ChrLatch {
+            latch: [0xFE, 0xFE],
+            banks: [[0, 0], [0, 0]],
+            exact_low,
+        }
+    }
+
+    fn fetch(&mut self, addr: u16) {
+        let table = (addr >> 12) as usize & 0x01;
+        let row = if table == 0 && self.exact_low {
+            addr & 0x0FFF
+        } else {
+            addr & 0x0FF8
+        };
+        match row {
+            0x0FD8 => self.latch[table] = 0xFD,
+            0x0FE8 => self.latch[table] = 0xFE,
+            _ => {}
+        }
+    }
+
+    fn chr_rom_addr(&self, addr: u16, chr_size: usize) -> usize {
+        let table = (addr >> 12) as usize & 0x01;
+        let bank = match self.latch[table] {
+            0xFD => self.banks[table][0],
+            _ => self.banks[table][1],
+        };
+        bank_addr(chr_size, 4 * 1024, bank as usize, addr)
+    }
+
+    // $B000~$EFFFへの書き込み
+    fn write(&mut self, addr: u16, data: u8) {
+        let bank = data & 0x1F;
+        match addr {
+            0xB000..=0xBFFF => self.banks[0][0] = bank,
+            0xC000..=0xCFFF => self.banks[0][1] = bank,
+            0xD000..=0xDFFF => self.banks[1][0] = bank,
+            0xE000..=0xEFFF => self.banks[1][1] = bank,
+            _ => {}
+        }
+    }
+}
+
+// MMC2 (PxROM) パンチアウト!!
+// $8000の8kBだけ切り替え $A000~は最後の3バンクに固定
+pub struct Mapper9 {
+    pub rom: Rom,
+    prg_bank: u8,
+    chr: ChrLatch,
+    mirroring: u8,
+}
+
+impl Mapper9 {
+    pub fn new() -> Self {
+        Mapper9 {
+            rom: Rom::empty(),
+            prg_bank: 0,
+            chr: ChrLatch::new(true),
+            mirroring: 0,
+        }
+    }
+}
+
+impl PpuBus for Mapper9 {
+    fn ppu_fetch(&mut self, addr: u16, _kind: PpuFetch) {
+        if addr < 0x2000 {
+            self.chr.fetch(addr);
+        }
+    }
+}
+
+impl Mapper for Mapper9 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xA000..=0xAFFF => self.prg_bank = data & 0x0F,
+            0xB000..=0xEFFF => self.chr.write(addr, data),
+            0xF000..=0xFFFF => self.mirroring = data,
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.mirroring & 0x01 == 0 {
+            Mirroring::VERTICAL
+        } else {
+            Mirroring::HORIZONTAL
+        }
+    }
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 8 * 1024; //8kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match addr {
+            0x8000..=0x9FFF => self.prg_bank as usize,
+            0xA000..=0xBFFF => bank_max - 3,
+            0xC000..=0xDFFF => bank_max - 2,
+            0xE000..=0xFFFF => bank_max - 1,
+            _ => panic!("cant be"),
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        self.chr.chr_rom_addr(addr, self.rom.chr_rom.len())
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xA000, 0xC000, 0xE000])
+    }
+    fn is_irq(&mut self) -> bool {
+        false
+    }
+}
+
+// MMC4 (FxROM) ファイアーエムブレム
+// $8000の16kBだけ切り替え $C000~は最後のバンクに固定 PRG-RAM 8kB (バッテリーバックアップ)
+pub struct Mapper10 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    prg_bank: u8,
+    chr: ChrLatch,
+    mirroring: u8,
+    battery: BatterySave,
+}
+
+impl Mapper10 {
+    pub fn new() -> Self {
+        Mapper10 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192], //8kiB
+            prg_bank: 0,
+            chr: ChrLatch::new(false),
+            mirroring: 0,
+            battery: BatterySave::new(),
+        }
+    }
+}
+
+impl PpuBus for Mapper10 {
+    fn ppu_fetch(&mut self, addr: u16, _kind: PpuFetch) {
+        if addr < 0x2000 {
+            self.chr.fetch(addr);
+        }
+    }
+}
+
+impl Mapper for Mapper10 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xA000..=0xAFFF => self.prg_bank = data & 0x0F,
+            0xB000..=0xEFFF => self.chr.write(addr, data),
+            0xF000..=0xFFFF => self.mirroring = data,
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.mirroring & 0x01 == 0 {
+            Mirroring::VERTICAL
+        } else {
+            Mirroring::HORIZONTAL
+        }
+    }
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        // prg_ramは6000から始まる
+        self.prg_ram[addr as usize - 0x6000] = data;
+        self.battery.mark_dirty(&self.rom);
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        if raw.is_empty() {
+            return;
+        }
+        self.prg_ram = raw.to_vec()
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 16 * 1024; //16kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank as usize,
+            0xC000..=0xFFFF => bank_max - 1,
+            _ => panic!("cant be"),
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        self.chr.chr_rom_addr(addr, self.rom.chr_rom.len())
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 16 * 1024, &[0xC000])
+    }
+    fn cpu_clock(&mut self) {
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn is_irq(&mut self) -> bool {
+        false
+    }
+}
+
+// VRC2/VRC4 (マッパー21,22,23,25)
+// 基板によってレジスタのA0,A1につながるCPUのアドレス線が違う
+// NES 2.0のサブマッパーでわかるときはそれを使い、わからないときは両方の配線をORする
+pub struct MapperVrc {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    vrc2: bool,      //VRC2はIRQとPRGの入れ替えがない
+    chr_shift: bool, //VRC2aはCHRバンクの最下位bitを使わない
+    a0_mask: u16,    //レジスタのA0につながるアドレス線
+    a1_mask: u16,    //レジスタのA1につながるアドレス線
+
+    prg_bank: [u8; 2],
+    prg_swap: bool,
+    chr_bank: [u16; 8],
+    mirroring: u8,
+
+    irq: VrcIrq,
+    battery: BatterySave,
+}
+
+impl MapperVrc {
+    pub fn new() -> Self {
+        MapperVrc {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192], //8kiB
+            vrc2: false,
+            chr_shift: false,
+            a0_mask: 0x01,
+            a1_mask: 0x02,
+            prg_bank: [0, 0],
+            prg_swap: false,
+            chr_bank: [0; 8],
+            mirroring: 0,
+            irq: VrcIrq::new(),
+            battery: BatterySave::new(),
+        }
+    }
+
+    // アドレスを$x000~$x003にそろえる
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = (addr & self.a0_mask != 0) as u16;
+        let a1 = (addr & self.a1_mask != 0) as u16;
+        (addr & 0xF000) | a1 << 1 | a0
+    }
+}
+
+impl PpuBus for MapperVrc {}
+
+impl Mapper for MapperVrc {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        // (A0, A1)
+        // サブマッパーが0(iNES 1.0のヘッダなど)のときは両方の配線をORする わかっている問題:
+        //  - 23,25のVRC2b/VRC2cもVRC4として動かすので、$9002のPRGの入れ替えと$F000~のIRQが効いてしまう
+        //    VRC2のゲームがそこに書かなければ違いは出ない
+        //  - 片方の配線では使わないアドレス線を立てて書くと、もう片方の配線では別のレジスタになる
+        //    (VRC4eの基板で$B001に書くと、VRC4fのA0と見てCHRの上位4bitになる)
+        // CRCから基板を引く表は持っていないので、動かないゲームはNES 2.0のヘッダでサブマッパーを指定する
+        let (a0, a1) = match (rom.mapper, rom.submapper) {
+            (21, 1) => (0x02, 0x04),               //VRC4a
+            (21, 2) => (0x40, 0x80),               //VRC4c
+            (21, _) => (0x02 | 0x40, 0x04 | 0x80), //VRC4a + VRC4c
+            (22, _) => (0x02, 0x01),               //VRC2a
+            (23, 2) => (0x04, 0x08),               //VRC4e
+            (23, 1 | 3) => (0x01, 0x02),           //VRC4f, VRC2b
+            (23, _) => (0x01 | 0x04, 0x02 | 0x08), //VRC4f + VRC4e
+            (25, 2) => (0x08, 0x04),               //VRC4d
+            (25, 1 | 3) => (0x02, 0x01),           //VRC4b, VRC2c
+            (25, _) => (0x02 | 0x08, 0x01 | 0x04), //VRC4b + VRC4d
+            _ => panic!("not vrc2/vrc4"),
+        };
+        self.a0_mask = a0;
+        self.a1_mask = a1;
+        self.vrc2 = rom.mapper == 22 || (rom.mapper != 21 && rom.submapper == 3);
+        self.chr_shift = rom.mapper == 22;
+
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        let addr = self.register(addr);
+        match addr {
+            0x8000..=0x8003 => self.prg_bank[0] = data & 0x1F,
+            0x9000..=0x9003 if self.vrc2 => self.mirroring = data & 0x01,
+            0x9000..=0x9001 => self.mirroring = data & 0x03,
+            0x9002..=0x9003 => self.prg_swap = data & 0x02 != 0,
+            0xA000..=0xA003 => self.prg_bank[1] = data & 0x1F,
+            0xB000..=0xE003 => {
+                // 1つのバンク番号を下位4bitと上位5bitの2回に分けて書く
+                let index = ((addr >> 12) as usize - 0xB) * 2 + ((addr as usize & 0x02) >> 1);
+                let bank = self.chr_bank[index];
+                self.chr_bank[index] = if addr & 0x01 == 0 {
+                    (bank & 0x1F0) | (data as u16 & 0x0F)
+                } else {
+                    (bank & 0x00F) | (data as u16 & 0x1F) << 4
+                };
+            }
+            _ if self.vrc2 => {}
+            0xF000 => self.irq.latch = (self.irq.latch & 0xF0) | (data & 0x0F),
+            0xF001 => self.irq.latch = (self.irq.latch & 0x0F) | (data & 0x0F) << 4,
+            0xF002 => self.irq.write_control(data),
+            0xF003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SINGLE_SCREEN_A,
+            _ => Mirroring::SINGLE_SCREEN_B,
+        }
+    }
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        // prg_ramは6000から始まる
+        self.prg_ram[addr as usize - 0x6000] = data;
+        self.battery.mark_dirty(&self.rom);
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        if raw.is_empty() {
+            return;
+        }
+        self.prg_ram = raw.to_vec();
+        self.prg_ram.resize(8192, 0xFF);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 8 * 1024; //8kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match (addr, self.prg_swap) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.prg_bank[0] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => bank_max - 2,
+            (0xA000..=0xBFFF, _) => self.prg_bank[1] as usize,
+            (0xE000..=0xFFFF, _) => bank_max - 1,
+            _ => panic!("cant be"),
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 1024; //1kB
+        let mut bank = self.chr_bank[(addr as usize >> 10) & 0x07] as usize;
+        if self.chr_shift {
+            bank >>= 1;
+        }
+        bank_addr(self.rom.chr_rom.len(), bank_size, bank, addr)
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xC000, 0xE000])
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn is_irq(&mut self) -> bool {
+        self.irq.is_irq
+    }
+}
+
+// スキャンラインモードではCPU 1サイクルごとに3引いて341サイクル(1ライン)ごとに数える
+const VRC_IRQ_PRESCALER: i16 = 341;
+
+// VRC4とVRC6で共通のIRQカウンタ
+// $FFを超えたらラッチから読み直して割り込む
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enable: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    is_irq: bool,
+}
+
+impl VrcIrq {
+    fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: VRC_IRQ_PRESCALER,
+            enable: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            is_irq: false,
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enable = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        if self.enable {
+            self.counter = self.latch;
+            self.prescaler = VRC_IRQ_PRESCALER;
+        }
+        self.is_irq = false;
+    }
+
+    fn acknowledge(&mut self) {
+        self.is_irq = false;
+        self.enable = self.enable_after_ack;
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.enable {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+            return;
+        }
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+            self.prescaler += VRC_IRQ_PRESCALER;
+            self.clock_counter();
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.is_irq = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+// VRC6 (マッパー24,26) 悪魔城伝説、エスパードリーム2
+// 26はレジスタのA0とA1が入れ替わっている
+pub struct MapperVrc6 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    swap_a0_a1: bool,
+
+    prg_bank_16k: u8, //$8000~$BFFF
+    prg_bank_8k: u8,  //$C000~$DFFF
+    chr_bank: [u8; 8],
+    // $B003 bit7: PRG-RAMを使う bit5: 2kBのバンクのA10 bit4: ネームテーブルをCHRから
+    //       bit2,3: ミラーリング bit0,1: CHRのモード
+    banking_mode: u8,
+
+    irq: VrcIrq,
+    battery: BatterySave,
+}
+
+impl MapperVrc6 {
+    pub fn new() -> Self {
+        MapperVrc6 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192], //8kiB
+            swap_a0_a1: false,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_bank: [0; 8],
+            banking_mode: 0,
+            irq: VrcIrq::new(),
+            battery: BatterySave::new(),
+        }
+    }
+
+    // アドレスを$x000~$x003にそろえる
+    fn register(&self, addr: u16) -> u16 {
+        if self.swap_a0_a1 {
+            (addr & 0xF000) | (addr & 0x01) << 1 | (addr & 0x02) >> 1
+        } else {
+            addr & 0xF003
+        }
+    }
+}
+
+impl PpuBus for MapperVrc6 {}
+
+impl Mapper for MapperVrc6 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.swap_a0_a1 = rom.mapper == 26;
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    // $9000~$B002は拡張音源 (apu/vrc6.rs)
+    fn write(&mut self, addr: u16, data: u8) {
+        match self.register(addr) {
+            0x8000..=0x8003 => self.prg_bank_16k = data & 0x0F,
+            0xB003 => {
+                if data & 0x10 != 0 && self.banking_mode & 0x10 == 0 {
+                    warn!("VRC6: ネームテーブルをCHR-ROMから読むモードには対応していない ($B003={:02X})", data);
+                }
+                self.banking_mode = data;
+            }
+            0xC000..=0xC003 => self.prg_bank_8k = data & 0x1F,
+            addr @ (0xD000..=0xD003 | 0xE000..=0xE003) => {
+                let index = ((addr >> 12) as usize - 0xD) * 4 + (addr as usize & 0x03);
+                self.chr_bank[index] = data;
+            }
+            0xF000 => self.irq.latch = data,
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    // $B003のbit2,3 ネームテーブルはいつもCIRAM
+    // 本当はCHRのモードが1~3のときはR4~R7でページを選ぶが、そのモードでもbit2,3で決めている
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking_mode >> 2) & 0x03 {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SINGLE_SCREEN_A,
+            _ => Mirroring::SINGLE_SCREEN_B,
+        }
+    }
+    // $B003のbit7が立っていないと読み書きできない
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        if self.banking_mode & 0x80 == 0 {
+            return;
+        }
+        // prg_ramは6000から始まる
+        self.prg_ram[addr as usize - 0x6000] = data;
+        self.battery.mark_dirty(&self.rom);
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        if self.banking_mode & 0x80 == 0 {
+            return (addr >> 8) as u8; //オープンバス
+        }
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        if raw.is_empty() {
+            return;
+        }
+        self.prg_ram = raw.to_vec();
+        self.prg_ram.resize(8192, 0xFF);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 8 * 1024; //8kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank_16k as usize * 2 + ((addr as usize >> 13) & 0x01),
+            0xC000..=0xDFFF => self.prg_bank_8k as usize,
+            0xE000..=0xFFFF => bank_max - 1,
+            _ => panic!("cant be"),
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    // $B003のbit0,1
+    //  0: 1kBが8つ R0~R7
+    //  1: 2kBが4つ R0~R3
+    //  2,3: $0000~$0FFFは1kBが4つ R0~R3、$1000~$1FFFは2kBが2つ R4,R5
+    // 2kBのところはbit5が立っていればレジスタのbit0の代わりにPPUのA10を使い、立っていなければ同じ1kBが2回続く
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let slot = (addr as usize >> 10) & 0x07;
+        let (register, two_kb) = match (self.banking_mode & 0x03, slot) {
+            (0, s) => (s, false),
+            (1, s) => (s / 2, true),
+            (_, s @ 0..=3) => (s, false),
+            (_, s) => (4 + (s - 4) / 2, true),
+        };
+        let mut bank = self.chr_bank[register] as usize;
+        if two_kb && self.banking_mode & 0x20 != 0 {
+            bank = (bank & !0x01) | (slot & 0x01);
+        }
+        bank_addr(self.rom.chr_rom.len(), 1024, bank, addr)
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xE000])
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn is_irq(&mut self) -> bool {
+        self.irq.is_irq
+    }
+}
+
+// Sunsoft FME-7 (5A/5B) ギミック!など
+// $8000にコマンド番号、$A000にその値を書く 5Bの拡張音源は$C000~ (apu/sunsoft5b.rs)
+pub struct Mapper69 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    command: u8,
+    chr_bank: [u8; 8],
+    prg_bank: [u8; 4], //$6000, $8000, $A000, $C000
+    mirroring: u8,
+
+    irq_enable: bool,
+    irq_counter_enable: bool,
+    irq_counter: u16,
+    is_irq: bool,
+    battery: BatterySave,
+}
+
+impl Mapper69 {
+    pub fn new() -> Self {
+        Mapper69 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192], //8kiB
+            command: 0,
+            chr_bank: [0; 8],
+            prg_bank: [0; 4],
+            mirroring: 0,
+            irq_enable: false,
+            irq_counter_enable: false,
+            irq_counter: 0,
+            is_irq: false,
+            battery: BatterySave::new(),
+        }
+    }
+
+    // $6000~$7FFFのbit6が立っているとRAM、そうでなければROM
+    fn is_prg_ram_selected(&self) -> bool {
+        self.prg_bank[0] & 0x40 != 0
+    }
+}
+
+impl PpuBus for Mapper69 {}
+
+impl Mapper for Mapper69 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => match self.command {
+                0x0..=0x7 => self.chr_bank[self.command as usize] = data,
+                0x8..=0xB => self.prg_bank[self.command as usize - 0x8] = data,
+                0xC => self.mirroring = data & 0x03,
+                0xD => {
+                    self.irq_enable = data & 0x01 != 0;
+                    self.irq_counter_enable = data & 0x80 != 0;
+                    self.is_irq = false;
+                }
+                0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+                _ => self.irq_counter = (self.irq_counter & 0x00FF) | (data as u16) << 8,
+            },
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SINGLE_SCREEN_A,
+            _ => Mirroring::SINGLE_SCREEN_B,
+        }
+    }
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        // bit7が立っていないと書けない
+        if !self.is_prg_ram_selected() || self.prg_bank[0] & 0x80 == 0 {
+            return;
+        }
+        self.prg_ram[addr as usize - 0x6000] = data;
+        self.battery.mark_dirty(&self.rom);
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        if !self.is_prg_ram_selected() {
+            return self.rom.prg_rom[self.prg_rom_addr(addr)];
+        }
+        if self.prg_bank[0] & 0x80 == 0 {
+            return (addr >> 8) as u8; //オープンバス
+        }
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        if !self.is_prg_ram_selected() {
+            return false;
+        }
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        if raw.is_empty() {
+            return;
+        }
+        self.prg_ram = raw.to_vec();
+        self.prg_ram.resize(8192, 0xFF);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    // $6000~$7FFFにROMが割り当てられているときも使う
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 8 * 1024; //8kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match addr {
+            0x6000..=0xDFFF => {
+                (self.prg_bank[(addr as usize - 0x6000) / bank_size] & 0x3F) as usize
+            }
+            _ => bank_max - 1,
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 1024; //1kB
+        let bank = self.chr_bank[(addr as usize >> 10) & 0x07] as usize;
+        bank_addr(self.rom.chr_rom.len(), bank_size, bank, addr)
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xE000])
+    }
+
+    // 16bitのカウンタがCPUの1サイクルごとに減り、0から$FFFFになったら割り込む
+    fn cpu_clock(&mut self) {
+        if self.irq_counter_enable {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enable {
+                self.is_irq = true;
+            }
+        }
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn is_irq(&mut self) -> bool {
+        self.is_irq
+    }
+}
+
+// Namco 163の内部RAM 音源(apu/namco163.rs)が波形を読んで位相を書き戻すので、音声のスレッドと共有する
+pub static N163_RAM: Lazy<Mutex<[u8; 128]>> = Lazy::new(|| Mutex::new([0; 128]));
+
+// Namco 163 (マッパー19)
+// ネームテーブルにCHR-ROMを割り当てられる 128byteの内部RAMは音源(apu/namco163.rs)と共用
+pub struct Mapper19 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    ram_addr: u8, //$F800 bit7が立っていると読み書きのたびに+1
+    ram_protect: u8,
+
+    prg_bank: [u8; 3],  //$E000, $E800, $F000
+    chr_bank: [u8; 12], //$8000~$D800 0~7はパターンテーブル、8~11はネームテーブル
+
+    irq_counter: u16, //15bit
+    irq_enable: bool,
+    is_irq: bool,
+
+    battery: BatterySave, //内部RAMには音源のレジスタもあって書き込みが多い
+}
+
+impl Mapper19 {
+    pub fn new() -> Self {
+        Mapper19 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192], //8kiB
+            ram_addr: 0,
+            ram_protect: 0,
+            prg_bank: [0; 3],
+            chr_bank: [0; 12],
+            irq_counter: 0,
+            irq_enable: false,
+            is_irq: false,
+            battery: BatterySave::new(),
+        }
+    }
+
+    fn next_ram_addr(&mut self) -> usize {
+        let addr = self.ram_addr & 0x7F;
+        if self.ram_addr & 0x80 != 0 {
+            self.ram_addr = 0x80 | (addr + 1) & 0x7F;
+        }
+        addr as usize
+    }
+
+    // $C000~$D800に$E0以上を書くとCIRAM、それ以外はCHR-ROMの1kBバンク
+    fn nametable_bank(&self, addr: u16) -> Option<usize> {
+        let bank = self.chr_bank[8 + ((addr as usize >> 10) & 0x03)];
+        if bank >= 0xE0 {
+            None
+        } else {
+            Some(bank as usize)
+        }
+    }
+
+    // PRG-RAMの後ろに、バッテリーがあれば内部RAMを続けて保存する
+    fn save(&mut self) {
+        let mut data = self.prg_ram.clone();
+        if self.rom.battery {
+            data.extend_from_slice(&*N163_RAM.lock().unwrap());
+        }
+        self.battery.save(&self.rom, &data);
+    }
+}
+
+impl PpuBus for Mapper19 {
+    fn read_nametable(&mut self, addr: u16, _kind: PpuFetch) -> Option<u8> {
+        let bank = self.nametable_bank(addr)?;
+        Some(self.rom.chr_rom[bank_addr(self.rom.chr_rom.len(), 1024, bank, addr)])
+    }
+
+    // CHR-ROMのときは書けない
+    fn write_nametable(&mut self, addr: u16, _value: u8) -> bool {
+        self.nametable_bank(addr).is_some()
+    }
+}
+
+impl Mapper for Mapper19 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom;
+        let save_data = self.rom.save_data.clone();
+        self.load_prg_ram(&save_data);
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0xDFFF => self.chr_bank[(addr as usize - 0x8000) / 0x800] = data,
+            // bit6,7は音源の無効化とCHR-RAMの設定 音源はapu側で見る
+            0xE000..=0xF7FF => self.prg_bank[(addr as usize - 0xE000) / 0x800] = data & 0x3F,
+            0xF800..=0xFFFF => {
+                self.ram_addr = data;
+                self.ram_protect = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => {
+                let addr = self.next_ram_addr();
+                N163_RAM.lock().unwrap()[addr]
+            }
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enable as u8) << 7,
+            _ => (addr >> 8) as u8, //オープンバス
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => {
+                let addr = self.next_ram_addr();
+                N163_RAM.lock().unwrap()[addr] = data;
+                self.battery.mark_dirty(&self.rom);
+            }
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.is_irq = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (data as u16 & 0x7F) << 8;
+                self.irq_enable = data & 0x80 != 0;
+                self.is_irq = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // CHR-ROMのときはread_nametableで横取りするのでページはどれでもいい
+        let mut pages = [0; 4];
+        for (i, page) in pages.iter_mut().enumerate() {
+            *page = self.chr_bank[8 + i] & 0x01;
+        }
+        Mirroring::QUADRANTS(pages)
+    }
 
-            if self.irq_latch_counter == 0 && self.irq_enable {
+    // $F800の上位4bitが0100のとき、下位4bitが0の2kBだけ書ける
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        let region = (addr as usize - 0x6000) / 0x800;
+        if self.ram_protect & 0xF0 != 0x40 || self.ram_protect & (1 << region) != 0 {
+            return;
+        }
+        self.prg_ram[addr as usize - 0x6000] = data;
+        self.battery.mark_dirty(&self.rom);
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        self.prg_ram[addr as usize - 0x6000] = data;
+        true
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        if raw.is_empty() {
+            return;
+        }
+        let size = self.prg_ram.len();
+        self.prg_ram = raw.iter().take(size).cloned().collect();
+        self.prg_ram.resize(size, 0xFF);
+        if self.rom.battery && raw.len() >= size + 128 {
+            N163_RAM
+                .lock()
+                .unwrap()
+                .copy_from_slice(&raw[size..size + 128]);
+        }
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 8 * 1024; //8kB
+        let bank_max = self.rom.prg_rom.len() / bank_size;
+        let bank = match addr {
+            0x8000..=0xDFFF => self.prg_bank[(addr as usize - 0x8000) / bank_size] as usize,
+            _ => bank_max - 1,
+        };
+        bank_addr(self.rom.prg_rom.len(), bank_size, bank, addr)
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 1024; //1kB
+        let bank = self.chr_bank[(addr as usize >> 10) & 0x07] as usize;
+        bank_addr(self.rom.chr_rom.len(), bank_size, bank, addr)
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xE000])
+    }
+
+    // 15bitのカウンタがCPUの1サイクルごとに増え、$7FFFで止まって割り込む
+    fn cpu_clock(&mut self) {
+        if self.irq_enable && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
                 self.is_irq = true;
             }
         }
+
+        if self.battery.clock() {
+            self.save();
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.save();
+        }
+    }
+    fn is_irq(&mut self) -> bool {
+        self.is_irq
+    }
+}
+
+// ロジックICだけの単純な基板 ラッチ1つでバンクを切り替える
+//  11: Color Dreams     $8000~  bit0,1: PRG 32kB  bit4~7: CHR 8kB
+//  66: GxROM            $8000~  bit4,5: PRG 32kB  bit0,1: CHR 8kB
+//  71: Camerica         $C000~  bit0~3: $8000のPRG 16kB  $9000~ bit4: 1画面ミラーリング(Fire Hawk)
+//  79: NINA-03/06       $4100~  bit3: PRG 32kB    bit0~2: CHR 8kB
+//  94: UN1ROM           $8000~  bit2~4: $8000のPRG 16kB
+// 140: Jaleco JF-11/14  $6000~  bit4,5: PRG 32kB  bit0~3: CHR 8kB
+// 180: UNROM(逆)        $8000~  bit0~2: $C000のPRG 16kB $8000は最初のバンクで固定
+// 185: CNROM+コピープロテクト  書いた値でCHRが読めるかどうかが決まる
+pub struct MapperDiscrete {
+    pub rom: Rom,
+    latch: u8,
+    single_screen: Option<Mirroring>, //71
+}
+
+impl MapperDiscrete {
+    pub fn new() -> Self {
+        MapperDiscrete {
+            rom: Rom::empty(),
+            latch: 0,
+            single_screen: None,
+        }
+    }
+
+    // ROMの上にレジスタがある基板は、実機ではバスコンフリクトが起きる
+    fn has_bus_conflict(&self) -> bool {
+        matches!(self.rom.mapper, 11 | 66 | 94 | 180 | 185)
+    }
+
+    fn prg_bank(&self) -> usize {
+        let bank = match self.rom.mapper {
+            11 => self.latch & 0x03,
+            66 | 140 => (self.latch >> 4) & 0x03,
+            71 => self.latch & 0x0F,
+            79 => (self.latch >> 3) & 0x01,
+            94 => (self.latch >> 2) & 0x07,
+            180 => self.latch & 0x07,
+            _ => 0,
+        };
+        bank as usize
+    }
+
+    fn chr_bank(&self) -> usize {
+        let bank = match self.rom.mapper {
+            11 => self.latch >> 4,
+            66 => self.latch & 0x03,
+            79 => self.latch & 0x07,
+            140 => self.latch & 0x0F,
+            _ => 0,
+        };
+        bank as usize
+    }
+
+    // 185はダイオードの付け方で、読めるようになる値が基板ごとに違う
+    // サブマッパー4~7は下位2bitが0~3のとき サブマッパーがなければよくある組み合わせで判断する
+    fn chr_enabled(&self) -> bool {
+        if self.rom.mapper != 185 {
+            return true;
+        }
+        match self.rom.submapper {
+            4..=7 => self.latch & 0x03 == self.rom.submapper - 4,
+            _ => self.latch & 0x03 != 0 && self.latch != 0x13,
+        }
+    }
+}
+
+impl PpuBus for MapperDiscrete {}
+
+impl Mapper for MapperDiscrete {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        let data = if self.has_bus_conflict() {
+            bus_conflict(self, addr, data)
+        } else {
+            data
+        };
+        match (self.rom.mapper, addr) {
+            // Fire Hawkの基板だけにある サブマッパー1でなくても$9000~に書かれたら使う
+            (71, 0x8000..=0x9FFF) if addr >= 0x9000 || self.rom.submapper == 1 => {
+                self.single_screen = Some(if data & 0x10 == 0 {
+                    Mirroring::SINGLE_SCREEN_A
+                } else {
+                    Mirroring::SINGLE_SCREEN_B
+                });
+            }
+            (71, 0xC000..=0xFFFF) => self.latch = data,
+            (11 | 66 | 94 | 180 | 185, _) => self.latch = data,
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.single_screen.unwrap_or(self.rom.screen_mirroring)
+    }
+
+    // NINA-03/06は$4100~$5FFFのうちA8が立っているところ
+    fn write_register(&mut self, addr: u16, data: u8) {
+        if self.rom.mapper == 79 && addr & 0x4100 == 0x4100 {
+            self.latch = data;
+        }
+    }
+
+    // JF-11/14はPRG-RAMの代わりに$6000~$7FFFにレジスタがある
+    fn write_prg_ram(&mut self, _addr: u16, data: u8) {
+        if self.rom.mapper == 140 {
+            self.latch = data;
+        }
+    }
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.rom.prg_rom[self.prg_rom_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        if self.rom.is_chr_ram {
+            let addr = self.chr_rom_addr(addr);
+            self.rom.chr_rom[addr] = value;
+        }
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        // 読めないときはプルアップされていて$FFになる
+        if !self.chr_enabled() {
+            return 0xFF;
+        }
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
     }
 
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let len = self.rom.prg_rom.len();
+        match self.rom.mapper {
+            71 | 94 | 180 => {
+                let bank_size = 16 * 1024; //16kB
+                let bank_max = (len / bank_size).max(1);
+                let bank = match (self.rom.mapper, addr) {
+                    (180, 0x8000..=0xBFFF) => 0,
+                    (180, _) => self.prg_bank(),
+                    (_, 0x8000..=0xBFFF) => self.prg_bank(),
+                    _ => bank_max - 1,
+                };
+                bank_addr(len, bank_size, bank, addr)
+            }
+            _ => {
+                let bank_size = 32 * 1024; //32kB
+                bank_addr(len, bank_size, self.prg_bank(), addr)
+            }
+        }
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = 8 * 1024; //8kB
+        bank_addr(self.rom.chr_rom.len(), bank_size, self.chr_bank(), addr)
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        let len = self.rom.prg_rom.len();
+        match self.rom.mapper {
+            71 | 94 => PrgLayout::new(len, 16 * 1024, &[0xC000]),
+            180 => PrgLayout {
+                fixed: vec![(0, 0x8000)], //最初のバンクが固定
+                switch_base: 0xC000,
+                ..PrgLayout::new(len, 16 * 1024, &[])
+            },
+            185 => PrgLayout::nrom(len),
+            _ => PrgLayout::new(len, 32 * 1024, &[]),
+        }
+    }
     fn is_irq(&mut self) -> bool {
-        let res = self.is_irq;
-        self.is_irq = false;
-        res
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prg_layout_fixed_tail() {
+        // MMC3 128kB: 16バンクのうち最後の2つが$C000, $E000
+        let layout = PrgLayout::new(128 * 1024, 8 * 1024, &[0xC000, 0xE000]);
+        assert_eq!(layout.fixed, vec![(15, 0xE000), (14, 0xC000)]);
+
+        // バンクが足りないときは後ろから入るだけ
+        let layout = PrgLayout::new(16 * 1024, 8 * 1024, &[0xA000, 0xC000, 0xE000]);
+        assert_eq!(layout.fixed, vec![(1, 0xE000), (0, 0xC000)]);
+    }
+
+    #[test]
+    fn test_prg_layout_nrom() {
+        let layout = PrgLayout::nrom(16 * 1024);
+        assert_eq!(layout.fixed, vec![(0, 0xC000)]);
+        assert!(layout.mirrored);
+
+        let layout = PrgLayout::nrom(32 * 1024);
+        assert_eq!(layout.fixed, vec![(0, 0x8000)]);
+        assert!(!layout.mirrored);
+    }
+
+    // PRGはバンク番号と同じ値、CHRは$AAで埋めたROM
+    fn discrete(mapper: u8, submapper: u8, prg_banks: usize) -> MapperDiscrete {
+        let mut rom = Rom::empty();
+        rom.mapper = mapper;
+        rom.submapper = submapper;
+        rom.prg_rom = (0..prg_banks * 0x4000)
+            .map(|i| (i / 0x4000) as u8)
+            .collect();
+        rom.chr_rom = vec![0xAA; 0x2000];
+        let mut m = MapperDiscrete::new();
+        m.set_rom(rom);
+        m
+    }
+
+    #[test]
+    fn test_bank_addr() {
+        assert_eq!(bank_addr(0x8000, 0x4000, 1, 0xC123), 0x4123);
+        // ROMより大きいバンク番号は折り返す
+        assert_eq!(bank_addr(0x8000, 0x4000, 3, 0x8123), 0x4123);
+        // 16kBのROMを32kBのバンクで使うと$8000と$C000が同じになる
+        assert_eq!(bank_addr(0x4000, 0x8000, 0, 0x8123), 0x0123);
+        assert_eq!(bank_addr(0x4000, 0x8000, 0, 0xC123), 0x0123);
+        // ROMがなくても落ちない
+        assert_eq!(bank_addr(0, 0x2000, 5, 0x0123), 0);
+    }
+
+    #[test]
+    fn test_bus_conflict() {
+        // UNROM(94)の$8000~はバンク0なので0が読める 書いた値とのANDで0になる
+        let mut m = discrete(94, 0, 8);
+        m.write(0x8000, 0x1C);
+        assert_eq!(m.latch, 0x00);
+        // $C000~は最後のバンク(7)
+        m.write(0xC000, 0x1C);
+        assert_eq!(m.latch, 0x04);
+        assert_eq!(m.read_prg_rom(0x8000), 1);
+
+        // 71はROMがレジスタに応答しないので書いた値のまま
+        let mut m = discrete(71, 0, 8);
+        m.write(0xC000, 0x05);
+        assert_eq!(m.read_prg_rom(0x8000), 5);
+    }
+
+    #[test]
+    fn test_mapper185_chr_enabled() {
+        let mut m = discrete(185, 0, 2);
+        // サブマッパーなし: 下位2bitが0か$13のときは読めない
+        for (latch, enabled) in [(0x00, false), (0x13, false), (0x01, true), (0x21, true)] {
+            m.latch = latch;
+            assert_eq!(m.chr_enabled(), enabled, "latch={:02X}", latch);
+            let value = if enabled { 0xAA } else { 0xFF };
+            assert_eq!(m.read_chr_rom(0x0000), value);
+        }
+
+        // サブマッパー4~7: 下位2bitが0~3のときだけ読める
+        for submapper in 4..=7 {
+            let mut m = discrete(185, submapper, 2);
+            for latch in 0..4 {
+                m.latch = latch;
+                assert_eq!(m.chr_enabled(), latch == submapper - 4);
+            }
+        }
+    }
+
+    fn mmc3(submapper: u8) -> Mapper4 {
+        let mut rom = Rom::empty();
+        rom.mapper = 4;
+        rom.submapper = submapper;
+        rom.prg_rom = vec![0; 128 * 1024];
+        rom.chr_rom = vec![0; 128 * 1024];
+        let mut m = Mapper4::new();
+        m.set_rom(rom);
+        m
+    }
+
+    // A12をlow_cycles M2サイクルの間Lowにしてから立ち上げる
+    fn rise_a12(m: &mut Mapper4, low_cycles: usize) {
+        m.ppu_fetch(0x0000, PpuFetch::SpritePattern);
+        for _ in 0..low_cycles {
+            m.cpu_clock();
+        }
+        m.ppu_fetch(0x1000, PpuFetch::SpritePattern);
+    }
+
+    #[test]
+    fn test_mmc3_a12_filter() {
+        let mut m = mmc3(0);
+        m.write(0xC000, 2); //ラッチ
+        m.write(0xC001, 0); //リロード
+        m.write(0xE001, 0); //IRQ有効
+
+        rise_a12(&mut m, MMC3_A12_FILTER);
+        assert_eq!(m.irq_latch_counter, 2);
+        // Lowが短いとき(8x16スプライトの間のネームテーブルの読み込みなど)は数えない
+        rise_a12(&mut m, MMC3_A12_FILTER - 1);
+        assert_eq!(m.irq_latch_counter, 2);
+        // 立ち上がったままのフェッチも数えない
+        m.ppu_fetch(0x1000, PpuFetch::SpritePattern);
+        assert_eq!(m.irq_latch_counter, 2);
+
+        rise_a12(&mut m, MMC3_A12_FILTER);
+        assert_eq!(m.irq_latch_counter, 1);
+        assert!(!m.is_irq());
+        rise_a12(&mut m, MMC3_A12_FILTER);
+        assert_eq!(m.irq_latch_counter, 0);
+        assert!(m.is_irq());
+
+        // $E000で止めるまで出続ける
+        rise_a12(&mut m, MMC3_A12_FILTER);
+        assert!(m.is_irq());
+        m.write(0xE000, 0);
+        assert!(!m.is_irq());
+    }
+
+    // ラッチが0のとき 新しいMMC3は毎ラインIRQ、古いMMC3はリロードの直後だけ
+    fn irqs_with_zero_latch(submapper: u8) -> Vec<bool> {
+        let mut m = mmc3(submapper);
+        m.write(0xC000, 0);
+        m.write(0xC001, 0);
+        m.write(0xE001, 0);
+        (0..3)
+            .map(|_| {
+                m.clock_irq_counter();
+                let irq = m.is_irq();
+                m.write(0xE000, 0);
+                m.write(0xE001, 0);
+                irq
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mmc3_irq_revision() {
+        assert_eq!(mmc3(0).irq_revision, Mmc3Irq::New);
+        assert_eq!(mmc3(4).irq_revision, Mmc3Irq::Old);
+
+        assert_eq!(irqs_with_zero_latch(0), vec![true, true, true]);
+        assert_eq!(irqs_with_zero_latch(4), vec![true, false, false]);
+    }
+
+    #[test]
+    fn test_mmc3_irq_counts_down_to_zero() {
+        // ラッチが1ならどちらも1ラインおき (リロードで1、デクリメントで0)
+        for submapper in [0, 4] {
+            let mut m = mmc3(submapper);
+            m.write(0xC000, 1);
+            m.write(0xC001, 0);
+            m.write(0xE001, 0);
+            let mut irqs = vec![];
+            for _ in 0..4 {
+                m.clock_irq_counter();
+                irqs.push(m.is_irq());
+                m.write(0xE000, 0);
+                m.write(0xE001, 0);
+            }
+            assert_eq!(
+                irqs,
+                vec![false, true, false, true],
+                "submapper={}",
+                submapper
+            );
+        }
+    }
+
+    #[test]
+    fn test_battery_save_waits_for_interval() {
+        let mut rom = Rom::empty();
+        let mut save = BatterySave::new();
+        // バッテリーがなければ保存しない
+        save.mark_dirty(&rom);
+        assert!(!save.is_dirty());
+        assert!(!save.clock());
+
+        rom.battery = true;
+        save.mark_dirty(&rom);
+        assert!(save.is_dirty());
+        for _ in 1..SAVE_INTERVAL {
+            assert!(!save.clock());
+        }
+        assert!(save.clock());
+    }
+
+    // PRGは8kBごと、CHRは1kBごとにバンク番号で埋めたROM
+    fn banked_rom(mapper: u8, submapper: u8, prg_kb: usize, chr_kb: usize) -> Rom {
+        let mut rom = Rom::empty();
+        rom.mapper = mapper;
+        rom.submapper = submapper;
+        rom.prg_rom = (0..prg_kb * 1024).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = (0..chr_kb * 1024).map(|i| (i / 0x400) as u8).collect();
+        rom
+    }
+
+    fn mmc1(prg_kb: usize) -> Mapper1 {
+        let mut m = Mapper1::new();
+        m.set_rom(banked_rom(1, 0, prg_kb, 128));
+        m
+    }
+
+    // シリアルポートに下位bitから5回書く 続けて書いたことにならないように間をあける
+    fn mmc1_write(m: &mut Mapper1, addr: u16, value: u8) {
+        for i in 0..5 {
+            m.cpu_clock();
+            m.cpu_clock();
+            m.write(addr, (value >> i) & 0x01);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_prg_banks() {
+        let mut m = mmc1(256);
+        // 起動したときは$C000~が最後のバンク
+        assert_eq!(m.read_prg_rom(0xC000), 30);
+        assert_eq!(m.read_prg_rom(0xE000), 31);
+
+        mmc1_write(&mut m, 0xE000, 5);
+        assert_eq!(m.read_prg_rom(0x8000), 10);
+        assert_eq!(m.read_prg_rom(0xA000), 11);
+
+        // $8000を最初のバンクに固定
+        mmc1_write(&mut m, 0x8000, 0x08);
+        assert_eq!(m.read_prg_rom(0x8000), 0);
+        assert_eq!(m.read_prg_rom(0xC000), 10);
+
+        // 32kB 下位bitは無視する
+        mmc1_write(&mut m, 0x8000, 0x00);
+        assert_eq!(m.read_prg_rom(0x8000), 8);
+        assert_eq!(m.read_prg_rom(0xC000), 10);
+    }
+
+    #[test]
+    fn test_mmc1_surom_outer_bank() {
+        let mut m = mmc1(512);
+        // CHRレジスタのbit4で後ろの256kB
+        mmc1_write(&mut m, 0xA000, 0x10);
+        mmc1_write(&mut m, 0xE000, 1);
+        assert_eq!(m.read_prg_rom(0x8000), 34);
+        assert_eq!(m.read_prg_rom(0xC000), 62);
+        mmc1_write(&mut m, 0xA000, 0x00);
+        assert_eq!(m.read_prg_rom(0x8000), 2);
+        assert_eq!(m.read_prg_rom(0xC000), 30);
+    }
+
+    #[test]
+    fn test_mmc1_chr_banks_and_mirroring() {
+        let mut m = mmc1(256);
+        mmc1_write(&mut m, 0xA000, 3);
+        mmc1_write(&mut m, 0xC000, 7);
+
+        // 8kBモードはCHR0の下位bitを無視する
+        mmc1_write(&mut m, 0x8000, 0x0C);
+        assert_eq!(m.read_chr_rom(0x0000), 8);
+        assert_eq!(m.read_chr_rom(0x1000), 12);
+
+        // 4kBモード
+        mmc1_write(&mut m, 0x8000, 0x1C);
+        assert_eq!(m.read_chr_rom(0x0000), 12);
+        assert_eq!(m.read_chr_rom(0x1C00), 31);
+
+        for (control, mirroring) in [
+            (0x00, Mirroring::SINGLE_SCREEN_A),
+            (0x01, Mirroring::SINGLE_SCREEN_B),
+            (0x02, Mirroring::VERTICAL),
+            (0x03, Mirroring::HORIZONTAL),
+        ] {
+            mmc1_write(&mut m, 0x8000, 0x0C | control);
+            assert_eq!(m.mirroring(), mirroring);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_ignores_consecutive_writes() {
+        let mut m = mmc1(256);
+        // INCなどの書き込みが続いたときは2回目を無視する
+        m.cpu_clock();
+        m.write(0xE000, 1);
+        m.cpu_clock();
+        m.write(0xE000, 1);
+        assert_eq!(m.shift_count, 1);
+
+        // 2サイクルあけば数える
+        m.cpu_clock();
+        m.cpu_clock();
+        m.write(0xE000, 1);
+        assert_eq!(m.shift_count, 2);
+
+        // リセットは$C000~を最後のバンクに固定する
+        mmc1_write(&mut m, 0x8000, 0x00);
+        m.cpu_clock();
+        m.cpu_clock();
+        m.write(0x8000, 0x80);
+        assert_eq!(m.shift_count, 0);
+        assert_eq!(m.control & 0x0C, 0x0C);
+    }
+
+    #[test]
+    fn test_mmc1_prg_ram_disable() {
+        let mut m = mmc1(256);
+        m.write_prg_ram(0x6000, 0x12);
+        assert_eq!(m.read_prg_ram(0x6000), 0x12);
+        // PRGレジスタのbit4で無効 オープンバスになって書けない
+        mmc1_write(&mut m, 0xE000, 0x10);
+        m.write_prg_ram(0x6000, 0x34);
+        assert_eq!(m.read_prg_ram(0x6000), 0x60);
+        mmc1_write(&mut m, 0xE000, 0x00);
+        assert_eq!(m.read_prg_ram(0x6000), 0x12);
+    }
+
+    #[test]
+    fn test_axrom_bank_and_single_screen() {
+        let mut m = Mapper7::new();
+        m.set_rom(banked_rom(7, 0, 256, 8));
+        m.write(0x8000, 0x03);
+        assert_eq!(m.read_prg_rom(0x8000), 12);
+        assert_eq!(m.read_prg_rom(0xE000), 15);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_A);
+        m.write(0x8000, 0x10);
+        assert_eq!(m.read_prg_rom(0x8000), 0);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_B);
+
+        // AMROMはバスコンフリクトでROMの値(バンク0の$8000は0)とANDになる
+        let mut m = Mapper7::new();
+        m.set_rom(banked_rom(7, 2, 256, 8));
+        m.write(0x8000, 0x13);
+        assert_eq!(m.read_prg_rom(0x8000), 0);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_A);
+    }
+
+    #[test]
+    fn test_mmc2_chr_latch() {
+        let mut m = Mapper9::new();
+        m.set_rom(banked_rom(9, 0, 128, 128));
+        m.write(0xA000, 2);
+        assert_eq!(m.read_prg_rom(0x8000), 2);
+        // $A000~は最後の3バンク
+        assert_eq!(m.read_prg_rom(0xA000), 13);
+        assert_eq!(m.read_prg_rom(0xE000), 15);
+
+        m.write(0xB000, 1); //$0000 $FD
+        m.write(0xC000, 2); //$0000 $FE
+        m.write(0xD000, 3); //$1000 $FD
+        m.write(0xE000, 4); //$1000 $FE
+                            // 最初は$FE
+        assert_eq!(m.read_chr_rom(0x0000), 8);
+        assert_eq!(m.read_chr_rom(0x1000), 16);
+
+        // $0000側は$0FD8ちょうどでだけ切り替わる
+        m.ppu_fetch(0x0FD9, PpuFetch::BackgroundPattern);
+        assert_eq!(m.read_chr_rom(0x0000), 8);
+        m.ppu_fetch(0x0FD8, PpuFetch::BackgroundPattern);
+        assert_eq!(m.read_chr_rom(0x0000), 4);
+        // $1000側は$1FD8~$1FDFのどこでも
+        m.ppu_fetch(0x1FDF, PpuFetch::SpritePattern);
+        assert_eq!(m.read_chr_rom(0x1000), 12);
+        m.ppu_fetch(0x1FE8, PpuFetch::SpritePattern);
+        assert_eq!(m.read_chr_rom(0x1000), 16);
+        // もう片方のテーブルは変わらない
+        assert_eq!(m.read_chr_rom(0x0000), 4);
+
+        m.write(0xF000, 0);
+        assert_eq!(m.mirroring(), Mirroring::VERTICAL);
+        m.write(0xF000, 1);
+        assert_eq!(m.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_mmc4_chr_latch() {
+        let mut m = Mapper10::new();
+        m.set_rom(banked_rom(10, 0, 128, 128));
+        m.write(0xA000, 3);
+        assert_eq!(m.read_prg_rom(0x8000), 6);
+        assert_eq!(m.read_prg_rom(0xC000), 14);
+
+        m.write(0xB000, 1);
+        m.write(0xC000, 2);
+        // MMC4は$0000側も$0FD8~$0FDFのどこでも切り替わる
+        m.ppu_fetch(0x0FDB, PpuFetch::BackgroundPattern);
+        assert_eq!(m.read_chr_rom(0x0000), 4);
+        m.ppu_fetch(0x0FEF, PpuFetch::BackgroundPattern);
+        assert_eq!(m.read_chr_rom(0x0000), 8);
+    }
+
+    fn vrc(mapper: u8, submapper: u8) -> MapperVrc {
+        let mut m = MapperVrc::new();
+        m.set_rom(banked_rom(mapper, submapper, 128, 128));
+        m
+    }
+
+    #[test]
+    fn test_vrc4_register_lines() {
+        // VRC4a: A1,A2
+        let mut m = vrc(21, 1);
+        m.write(0xB000, 0x05);
+        m.write(0xB002, 0x01);
+        assert_eq!(m.read_chr_rom(0x0000), 0x15);
+        m.write(0xB004, 0x07);
+        assert_eq!(m.read_chr_rom(0x0400), 0x07);
+
+        // VRC4b: A1,A0 A0とA1が入れ替わる
+        let mut m = vrc(25, 1);
+        m.write(0xB000, 0x05);
+        m.write(0xB001, 0x03);
+        assert_eq!(m.read_chr_rom(0x0000), 0x05);
+        assert_eq!(m.read_chr_rom(0x0400), 0x03);
+
+        // サブマッパー0はVRC4aとVRC4cのどちらの配線でも書ける
+        let mut m = vrc(21, 0);
+        m.write(0xB000, 0x02);
+        m.write(0xB002, 0x01);
+        assert_eq!(m.read_chr_rom(0x0000), 0x12);
+        m.write(0xB040, 0x02);
+        assert_eq!(m.read_chr_rom(0x0000), 0x22);
+    }
+
+    #[test]
+    fn test_vrc4_prg_swap_and_mirroring() {
+        let mut m = vrc(23, 1);
+        m.write(0x8000, 3);
+        m.write(0xA000, 5);
+        assert_eq!(m.read_prg_rom(0x8000), 3);
+        assert_eq!(m.read_prg_rom(0xA000), 5);
+        assert_eq!(m.read_prg_rom(0xC000), 14);
+        assert_eq!(m.read_prg_rom(0xE000), 15);
+
+        m.write(0x9002, 0x02);
+        assert_eq!(m.read_prg_rom(0x8000), 14);
+        assert_eq!(m.read_prg_rom(0xC000), 3);
+        assert_eq!(m.read_prg_rom(0xE000), 15);
+
+        m.write(0x9000, 0);
+        assert_eq!(m.mirroring(), Mirroring::VERTICAL);
+        m.write(0x9000, 1);
+        assert_eq!(m.mirroring(), Mirroring::HORIZONTAL);
+        m.write(0x9000, 2);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_A);
+        m.write(0x9000, 3);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_B);
+    }
+
+    #[test]
+    fn test_vrc2() {
+        // VRC2aはCHRバンクの最下位bitを捨てる
+        let mut m = vrc(22, 0);
+        m.write(0xB000, 0x04);
+        m.write(0xB002, 0x01);
+        assert_eq!(m.read_chr_rom(0x0000), 0x0A);
+        m.write(0x9000, 0x03);
+        assert_eq!(m.mirroring(), Mirroring::HORIZONTAL);
+
+        // VRC2bはPRGの入れ替えもIRQもない
+        let mut m = vrc(23, 3);
+        m.write(0x8000, 3);
+        m.write(0x9002, 0x02);
+        assert_eq!(m.read_prg_rom(0x8000), 3);
+        m.write(0xF002, 0x06);
+        for _ in 0..600 {
+            m.cpu_clock();
+        }
+        assert!(!m.is_irq());
+    }
+
+    #[test]
+    fn test_vrc4_irq_prescaler() {
+        let mut m = vrc(23, 1);
+        m.write(0xF000, 0x0E);
+        m.write(0xF001, 0x0F);
+        m.write(0xF002, 0x02);
+        // スキャンラインモードは341/3 CPUサイクルで1つ数える
+        // $FEから2ライン目で$FFを超える
+        for _ in 0..227 {
+            m.cpu_clock();
+        }
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+
+        // 応答するとenable_after_ackが0なので止まる
+        m.write(0xF003, 0);
+        assert!(!m.is_irq());
+        for _ in 0..600 {
+            m.cpu_clock();
+        }
+        assert!(!m.is_irq());
+    }
+
+    #[test]
+    fn test_vrc4_irq_cycle_mode() {
+        let mut m = vrc(23, 1);
+        m.write(0xF000, 0x0E);
+        m.write(0xF001, 0x0F);
+        m.write(0xF002, 0x07);
+        m.cpu_clock();
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+
+        // enable_after_ackが1なら応答しても数え続ける ラッチから読み直している
+        m.write(0xF003, 0);
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+
+        // $F002に書くと割り込みも消える
+        m.write(0xF002, 0x00);
+        assert!(!m.is_irq());
+    }
+
+    fn vrc6(mapper: u8) -> MapperVrc6 {
+        let mut m = MapperVrc6::new();
+        m.set_rom(banked_rom(mapper, 0, 256, 256));
+        m
+    }
+
+    #[test]
+    fn test_vrc6_prg_banks() {
+        let mut m = vrc6(24);
+        m.write(0x8000, 3);
+        m.write(0xC000, 9);
+        assert_eq!(m.read_prg_rom(0x8000), 6);
+        assert_eq!(m.read_prg_rom(0xA000), 7);
+        assert_eq!(m.read_prg_rom(0xC000), 9);
+        assert_eq!(m.read_prg_rom(0xE000), 31);
+    }
+
+    #[test]
+    fn test_vrc6_chr_modes() {
+        let mut m = vrc6(24);
+        for i in 0..4 {
+            m.write(0xD000 + i, 0x10 + i as u8);
+            m.write(0xE000 + i, 0x20 + i as u8);
+        }
+        // 0: 1kBが8つ
+        m.write(0xB003, 0x00);
+        assert_eq!(m.read_chr_rom(0x0400), 0x11);
+        assert_eq!(m.read_chr_rom(0x1C00), 0x23);
+
+        // 1: 2kBが4つ bit5が立っていなければ同じ1kBが2回
+        m.write(0xB003, 0x01);
+        assert_eq!(m.read_chr_rom(0x0000), 0x10);
+        assert_eq!(m.read_chr_rom(0x0400), 0x10);
+        assert_eq!(m.read_chr_rom(0x1800), 0x13);
+        assert_eq!(m.read_chr_rom(0x1C00), 0x13);
+        // bit5が立っていればA10を使う
+        m.write(0xB003, 0x21);
+        assert_eq!(m.read_chr_rom(0x0000), 0x10);
+        assert_eq!(m.read_chr_rom(0x0400), 0x11);
+        assert_eq!(m.read_chr_rom(0x0800), 0x10);
+        assert_eq!(m.read_chr_rom(0x0C00), 0x11);
+
+        // 2,3: $0000~は1kBが4つ、$1000~は2kBが2つ
+        for mode in [0x22, 0x23] {
+            m.write(0xB003, mode);
+            assert_eq!(m.read_chr_rom(0x0C00), 0x13);
+            assert_eq!(m.read_chr_rom(0x1000), 0x20);
+            assert_eq!(m.read_chr_rom(0x1400), 0x21);
+            assert_eq!(m.read_chr_rom(0x1800), 0x20);
+            assert_eq!(m.read_chr_rom(0x1C00), 0x21);
+        }
+    }
+
+    #[test]
+    fn test_vrc6_mapper26_swaps_a0_a1() {
+        let mut m = vrc6(26);
+        m.write(0xD001, 0x05); //R2
+        m.write(0xD002, 0x06); //R1
+        assert_eq!(m.read_chr_rom(0x0400), 0x06);
+        assert_eq!(m.read_chr_rom(0x0800), 0x05);
+
+        // $B003もA0とA1を入れ替えて見る
+        m.write(0xB003, 0x04);
+        assert_eq!(m.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_vrc6_mirroring_and_prg_ram() {
+        let mut m = vrc6(24);
+        m.write(0xB003, 0x00);
+        assert_eq!(m.mirroring(), Mirroring::VERTICAL);
+        m.write(0xB003, 0x04);
+        assert_eq!(m.mirroring(), Mirroring::HORIZONTAL);
+        m.write(0xB003, 0x08);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_A);
+        m.write(0xB003, 0x0C);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_B);
+
+        // bit7が立っていないとPRG-RAMは読み書きできない
+        m.write_prg_ram(0x6000, 0x12);
+        assert_eq!(m.read_prg_ram(0x6000), 0x60);
+        m.write(0xB003, 0x80);
+        assert_eq!(m.read_prg_ram(0x6000), 0xFF);
+        m.write_prg_ram(0x6000, 0x34);
+        assert_eq!(m.read_prg_ram(0x6000), 0x34);
+    }
+
+    #[test]
+    fn test_vrc6_irq() {
+        let mut m = vrc6(24);
+        m.write(0xF000, 0xFE);
+        m.write(0xF001, 0x06);
+        m.cpu_clock();
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+        m.write(0xF002, 0);
+        assert!(!m.is_irq());
+
+        // スキャンラインモード
+        m.write(0xF001, 0x02);
+        for _ in 0..227 {
+            m.cpu_clock();
+        }
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+    }
+
+    fn fme7() -> Mapper69 {
+        let mut m = Mapper69::new();
+        m.set_rom(banked_rom(69, 0, 256, 256));
+        m
+    }
+
+    fn fme7_write(m: &mut Mapper69, command: u8, value: u8) {
+        m.write(0x8000, command);
+        m.write(0xA000, value);
+    }
+
+    #[test]
+    fn test_fme7_banks_and_mirroring() {
+        let mut m = fme7();
+        for i in 0..8 {
+            fme7_write(&mut m, i, 0x40 + i);
+        }
+        assert_eq!(m.read_chr_rom(0x0000), 0x40);
+        assert_eq!(m.read_chr_rom(0x1C00), 0x47);
+
+        fme7_write(&mut m, 0x9, 3);
+        fme7_write(&mut m, 0xA, 0x45); //上位2bitは使わない
+        fme7_write(&mut m, 0xB, 7);
+        assert_eq!(m.read_prg_rom(0x8000), 3);
+        assert_eq!(m.read_prg_rom(0xA000), 5);
+        assert_eq!(m.read_prg_rom(0xC000), 7);
+        assert_eq!(m.read_prg_rom(0xE000), 31);
+
+        fme7_write(&mut m, 0xC, 0);
+        assert_eq!(m.mirroring(), Mirroring::VERTICAL);
+        fme7_write(&mut m, 0xC, 1);
+        assert_eq!(m.mirroring(), Mirroring::HORIZONTAL);
+        fme7_write(&mut m, 0xC, 2);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_A);
+        fme7_write(&mut m, 0xC, 3);
+        assert_eq!(m.mirroring(), Mirroring::SINGLE_SCREEN_B);
+    }
+
+    #[test]
+    fn test_fme7_prg_ram_select() {
+        let mut m = fme7();
+        // bit6が0なら$6000~はROM
+        fme7_write(&mut m, 0x8, 9);
+        assert_eq!(m.read_prg_ram(0x6000), 9);
+        m.write_prg_ram(0x6000, 0x12);
+        assert_eq!(m.read_prg_ram(0x6000), 9);
+        assert!(!m.poke_prg_ram(0x6000, 0x12));
+
+        // RAMでもbit7が立っていないと読み書きできない
+        fme7_write(&mut m, 0x8, 0x40);
+        assert_eq!(m.read_prg_ram(0x6000), 0x60);
+        m.write_prg_ram(0x6000, 0x12);
+        fme7_write(&mut m, 0x8, 0xC0);
+        assert_eq!(m.read_prg_ram(0x6000), 0xFF);
+        m.write_prg_ram(0x6000, 0x34);
+        assert_eq!(m.read_prg_ram(0x6000), 0x34);
+    }
+
+    #[test]
+    fn test_fme7_irq() {
+        let mut m = fme7();
+        fme7_write(&mut m, 0xE, 0x01);
+        fme7_write(&mut m, 0xF, 0x00);
+        // カウンタだけ動かすと割り込まない
+        fme7_write(&mut m, 0xD, 0x80);
+        m.cpu_clock();
+        m.cpu_clock();
+        assert!(!m.is_irq());
+
+        fme7_write(&mut m, 0xE, 0x01);
+        fme7_write(&mut m, 0xF, 0x00);
+        fme7_write(&mut m, 0xD, 0x81);
+        m.cpu_clock();
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+
+        // $Dに書くと割り込みが消える カウンタを止めると減らない
+        fme7_write(&mut m, 0xD, 0x01);
+        assert!(!m.is_irq());
+        fme7_write(&mut m, 0xE, 0x00);
+        fme7_write(&mut m, 0xF, 0x00);
+        m.cpu_clock();
+        assert!(!m.is_irq());
+    }
+
+    #[test]
+    fn test_fme7_battery_save_without_irq() {
+        let path = std::env::temp_dir().join("fme7_battery_test.sav");
+        let mut rom = banked_rom(69, 0, 256, 256);
+        rom.battery = true;
+        rom.save_data_file = path.to_str().unwrap().to_string();
+        let mut m = Mapper69::new();
+        m.set_rom(rom);
+        fme7_write(&mut m, 0x8, 0xC0);
+        m.write_prg_ram(0x6000, 0x56);
+        // IRQのカウンタが止まっていても保存する
+        for _ in 0..SAVE_INTERVAL {
+            m.cpu_clock();
+        }
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved[0], 0x56);
+        assert!(!m.battery.is_dirty());
+    }
+
+    fn n163() -> Mapper19 {
+        let mut m = Mapper19::new();
+        m.set_rom(banked_rom(19, 0, 256, 256));
+        m
+    }
+
+    #[test]
+    fn test_n163_banks() {
+        let mut m = n163();
+        m.write(0xE000, 0xC3); //bit6,7は音源とCHR-RAM
+        m.write(0xE800, 5);
+        m.write(0xF000, 7);
+        assert_eq!(m.read_prg_rom(0x8000), 3);
+        assert_eq!(m.read_prg_rom(0xA000), 5);
+        assert_eq!(m.read_prg_rom(0xC000), 7);
+        assert_eq!(m.read_prg_rom(0xE000), 31);
+
+        for i in 0..8 {
+            m.write(0x8000 + i * 0x800, 0x30 + i as u8);
+        }
+        assert_eq!(m.read_chr_rom(0x0000), 0x30);
+        assert_eq!(m.read_chr_rom(0x1C00), 0x37);
+    }
+
+    #[test]
+    fn test_n163_nametables() {
+        let mut m = n163();
+        // $E0以上はCIRAM bit0でページを選ぶ
+        m.write(0xC000, 0xE0);
+        m.write(0xC800, 0xE1);
+        m.write(0xD000, 0xE1);
+        m.write(0xD800, 0xE0);
+        assert_eq!(m.mirroring(), Mirroring::QUADRANTS([0, 1, 1, 0]));
+        assert_eq!(m.read_nametable(0x2000, PpuFetch::Nametable), None);
+        assert!(!m.write_nametable(0x2400, 0));
+
+        // それ以外はCHR-ROM 書いても捨てる
+        m.write(0xC800, 0x42);
+        assert_eq!(m.read_nametable(0x2400, PpuFetch::Nametable), Some(0x42));
+        assert_eq!(m.read_nametable(0x2C00, PpuFetch::Nametable), None);
+        assert!(m.write_nametable(0x2400, 0));
+    }
+
+    #[test]
+    fn test_n163_irq() {
+        let mut m = n163();
+        m.write_register(0x5000, 0xFD);
+        m.write_register(0x5800, 0xFF);
+        assert_eq!(m.read_register(0x5000), 0xFD);
+        assert_eq!(m.read_register(0x5800), 0xFF);
+        m.cpu_clock();
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert!(m.is_irq());
+        // $7FFFで止まる
+        m.cpu_clock();
+        assert_eq!(m.read_register(0x5000), 0xFF);
+        assert_eq!(m.read_register(0x5800), 0xFF);
+
+        // どちらかに書くと割り込みが消える bit7が0なら数えない
+        m.write_register(0x5800, 0x00);
+        assert!(!m.is_irq());
+        m.cpu_clock();
+        assert_eq!(m.read_register(0x5000), 0xFF);
+        assert_eq!(m.read_register(0x5800), 0x00);
+    }
+
+    #[test]
+    fn test_n163_prg_ram_protect() {
+        let mut m = n163();
+        // 上位4bitが0100でないと書けない
+        m.write(0xF800, 0x00);
+        m.write_prg_ram(0x6000, 0x12);
+        assert_eq!(m.read_prg_ram(0x6000), 0xFF);
+
+        // 下位4bitが立っている2kBは書けない
+        m.write(0xF800, 0x45);
+        m.write_prg_ram(0x6000, 0x12);
+        m.write_prg_ram(0x6800, 0x34);
+        m.write_prg_ram(0x7000, 0x56);
+        m.write_prg_ram(0x7800, 0x78);
+        assert_eq!(m.read_prg_ram(0x6000), 0xFF);
+        assert_eq!(m.read_prg_ram(0x6800), 0x34);
+        assert_eq!(m.read_prg_ram(0x7000), 0xFF);
+        assert_eq!(m.read_prg_ram(0x7800), 0x78);
     }
 }
@@ -0,0 +1,664 @@
+use crate::ppu_bus::{PpuBus, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+use super::{BatterySave, Mapper, PrgLayout};
+
+const PRG_BANK_SIZE: usize = 8 * 1024; //8kB
+const DEFAULT_PRG_RAM_SIZE: usize = 32 * 1024; //光栄のゲームは32kB
+
+// MMC5 (ExROM) 悪魔城伝説、光栄のゲームなど
+// PPUのレジスタは繋がっていないので、PPUが読むアドレスの並びからスキャンラインを数える
+pub struct Mapper5 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    exram: [u8; 1024],
+
+    prg_mode: u8,             //$5100
+    chr_mode: u8,             //$5101
+    prg_ram_protect: [u8; 2], //$5102, $5103
+    exram_mode: u8,           //$5104
+    nametable: u8,            //$5105 2bitずつ 0:CIRAM前半 1:CIRAM後半 2:ExRAM 3:フィルモード
+    fill_tile: u8,            //$5106
+    fill_attr: u8,            //$5107
+    prg_banks: [u8; 5],       //$5113~$5117
+    chr_banks_a: [u16; 8],    //$5120~$5127 スプライト用
+    chr_banks_b: [u16; 4],    //$5128~$512B 8x16モードの背景用
+    chr_upper: u8,            //$5130
+    last_chr_b: bool,         //最後に書いたのが$5128~$512Bか ($2007で使う)
+
+    split_control: u8, //$5200
+    split_scroll: u8,  //$5201
+    split_bank: u8,    //$5202
+
+    irq_compare: u8, //$5203
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8,
+
+    multiplicand: u8, //$5205
+    multiplier: u8,   //$5206
+
+    sprite_8x16: bool, //$2000への書き込みを覗いて知る
+
+    // PPUのフェッチを見てスキャンラインとタイルの位置を数える
+    last_addr: u16,
+    same_count: u8, //同じアドレスが続けて読まれた回数 3回でスキャンラインの始まり
+    idle_cycles: u8,
+    tile_count: u8, //スキャンラインの始まりから何回ネームテーブルを読んだか
+    fetch_split: bool,
+    split_y: u16,
+    split_tile: u8,
+    ext_attr: u8,
+
+    battery: BatterySave,
+}
+
+impl Mapper5 {
+    pub fn new() -> Self {
+        Mapper5 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; DEFAULT_PRG_RAM_SIZE],
+            exram: [0; 1024],
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0, 0],
+            exram_mode: 0,
+            nametable: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks_a: [0; 8],
+            chr_banks_b: [0; 4],
+            chr_upper: 0,
+            last_chr_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            sprite_8x16: false,
+            last_addr: 0,
+            same_count: 0,
+            idle_cycles: 0,
+            tile_count: 0,
+            fetch_split: false,
+            split_y: 0,
+            split_tile: 0,
+            ext_attr: 0,
+            battery: BatterySave::new(),
+        }
+    }
+
+    // $6000~$FFFFがPRG-ROMとPRG-RAMのどこにあたるか (ROMならtrue)
+    fn prg_addr(&self, addr: u16) -> (bool, usize) {
+        let offset = addr as usize & (PRG_BANK_SIZE - 1);
+        let ram_bank = |bank: u8| {
+            let count = (self.prg_ram.len() / PRG_BANK_SIZE).max(1);
+            (bank as usize & 0x07) % count * PRG_BANK_SIZE + offset
+        };
+        if addr < 0x8000 {
+            return (false, ram_bank(self.prg_banks[0]));
+        }
+
+        // 8kBのスロットごとにどのレジスタを使うか 大きいバンクは下位bitを無視する
+        let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+        let (reg, mask) = match (self.prg_mode & 0x03, slot) {
+            (0, _) => (4, 0x03),
+            (1, 0 | 1) | (2, 0 | 1) => (2, 0x01),
+            (1, _) => (4, 0x01),
+            (2, 2) => (3, 0x00),
+            (_, s) => (s + 1, 0x00),
+        };
+        let value = self.prg_banks[reg];
+        let bank = ((value & 0x7F) as usize & !mask) | (slot & mask);
+        // $5117はいつもROM
+        if reg == 4 || value & 0x80 != 0 {
+            let count = self.rom.prg_rom.len() / PRG_BANK_SIZE;
+            (true, bank % count * PRG_BANK_SIZE + offset)
+        } else {
+            (false, ram_bank(bank as u8))
+        }
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+
+    fn write_ram(&mut self, addr: usize, data: u8) {
+        if !self.is_prg_ram_writable() {
+            return;
+        }
+        self.prg_ram[addr] = data;
+        self.battery.mark_dirty(&self.rom);
+    }
+
+    // 8x16モードでは背景とスプライトで別のバンクを使う
+    fn use_chr_b(&self, kind: PpuFetch) -> bool {
+        if !self.sprite_8x16 {
+            return false;
+        }
+        match kind {
+            PpuFetch::SpritePattern => false,
+            PpuFetch::Data => self.last_chr_b,
+            _ => true,
+        }
+    }
+
+    fn chr_addr(&self, addr: u16, chr_b: bool) -> usize {
+        // Bは4つしかないので$1000~にも同じものを並べる
+        let banks = if chr_b {
+            let b = self.chr_banks_b;
+            [b[0], b[1], b[2], b[3], b[0], b[1], b[2], b[3]]
+        } else {
+            self.chr_banks_a
+        };
+        let addr = addr as usize & 0x1FFF;
+        let (bank, size) = match self.chr_mode & 0x03 {
+            0 => (banks[7], 0x2000),
+            1 => (banks[(addr >> 12) * 4 + 3], 0x1000),
+            2 => (banks[(addr >> 11) * 2 + 1], 0x0800),
+            _ => (banks[addr >> 10], 0x0400),
+        };
+        (bank as usize * size + (addr & (size - 1))) % self.rom.chr_rom.len()
+    }
+
+    fn read_chr_4k(&self, bank: usize, offset: usize) -> u8 {
+        self.rom.chr_rom[(bank * 0x1000 + offset) % self.rom.chr_rom.len()]
+    }
+
+    // 同じネームテーブルのアドレスが3回続いたらスキャンラインの始まり
+    fn detect_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending = true;
+            }
+        } else {
+            // 新しいフレームの始まり 前のフレームのIRQは取り消す
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        }
+        self.tile_count = 0;
+    }
+
+    // 背景のネームテーブルの読み込み 次の属性とパターンの読み込みはこのタイルのもの
+    fn start_tile(&mut self, addr: u16) {
+        if addr == self.last_addr && self.same_count >= 2 {
+            self.detect_scanline();
+        } else {
+            self.tile_count = self.tile_count.saturating_add(1);
+        }
+
+        // 見つけたときのタイルは左から3つ目 32はドット257の空読み、33~48はスプライトの空読み
+        // 49~51は次のラインの最初の3つ
+        let (tile, line) = match self.tile_count {
+            n @ 0..=31 => (Some(n + 2), self.scanline as u16),
+            n @ 49..=51 => (Some(n - 49), self.scanline as u16 + 1),
+            _ => (None, 0),
+        };
+
+        self.fetch_split = false;
+        if let Some(tile) = tile {
+            let threshold = self.split_control & 0x1F;
+            let inside = if self.split_control & 0x40 != 0 {
+                tile >= threshold
+            } else {
+                tile < threshold
+            };
+            if self.split_control & 0x80 != 0 && self.exram_mode <= 1 && self.in_frame && inside {
+                self.fetch_split = true;
+                self.split_tile = tile & 0x1F;
+                self.split_y = (self.split_scroll as u16 + line) % 240;
+            }
+        }
+        self.ext_attr = self.exram[addr as usize & 0x3FF];
+    }
+
+    fn read_split(&self, kind: PpuFetch) -> u8 {
+        let row = self.split_y as usize / 8;
+        let col = self.split_tile as usize;
+        match kind {
+            PpuFetch::Nametable => self.exram[row * 32 + col],
+            _ => {
+                let attr = self.exram[0x3C0 + (row / 4) * 8 + col / 4];
+                let shift = ((row / 2) & 0x01) * 4 + ((col / 2) & 0x01) * 2;
+                ((attr >> shift) & 0x03) * 0x55
+            }
+        }
+    }
+}
+
+impl PpuBus for Mapper5 {
+    fn ppu_fetch(&mut self, addr: u16, kind: PpuFetch) {
+        if kind == PpuFetch::Data {
+            return;
+        }
+        self.idle_cycles = 0;
+        if addr == self.last_addr {
+            self.same_count = self.same_count.saturating_add(1);
+        } else {
+            self.last_addr = addr;
+            self.same_count = 1;
+        }
+    }
+
+    fn read_pattern(&mut self, addr: u16, kind: PpuFetch) -> Option<u8> {
+        if kind == PpuFetch::BackgroundPattern {
+            if self.fetch_split {
+                let offset = (addr as usize & 0x0FF8) + (self.split_y as usize & 0x07);
+                return Some(self.read_chr_4k(self.split_bank as usize, offset));
+            }
+            // 拡張属性モードではタイルごとに4kBのバンクを選ぶ
+            if self.exram_mode == 1 {
+                let bank = (self.chr_upper as usize & 0x03) << 6 | (self.ext_attr & 0x3F) as usize;
+                return Some(self.read_chr_4k(bank, addr as usize & 0x0FFF));
+            }
+        }
+        Some(self.rom.chr_rom[self.chr_addr(addr, self.use_chr_b(kind))])
+    }
+
+    fn read_nametable(&mut self, addr: u16, kind: PpuFetch) -> Option<u8> {
+        if kind == PpuFetch::Nametable {
+            self.start_tile(addr);
+        }
+        if kind == PpuFetch::Nametable || kind == PpuFetch::Attribute {
+            if self.fetch_split {
+                return Some(self.read_split(kind));
+            }
+            if kind == PpuFetch::Attribute && self.exram_mode == 1 {
+                return Some((self.ext_attr >> 6) * 0x55);
+            }
+        }
+
+        let quadrant = (addr >> 10) & 0x03;
+        match (self.nametable >> (quadrant * 2)) & 0x03 {
+            2 if self.exram_mode <= 1 => Some(self.exram[addr as usize & 0x3FF]),
+            2 => Some(0),
+            3 if addr & 0x3FF >= 0x3C0 => Some((self.fill_attr & 0x03) * 0x55),
+            3 => Some(self.fill_tile),
+            _ => None,
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) -> bool {
+        let quadrant = (addr >> 10) & 0x03;
+        match (self.nametable >> (quadrant * 2)) & 0x03 {
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[addr as usize & 0x3FF] = value;
+                }
+                true
+            }
+            3 => true,
+            _ => false,
+        }
+    }
+}
+
+impl Mapper for Mapper5 {
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn set_rom(&mut self, rom: Rom) {
+        let prg_ram_size = match rom.prg_ram_size {
+            0 => DEFAULT_PRG_RAM_SIZE,
+            size => size,
+        };
+        self.prg_ram = vec![0xFF; prg_ram_size];
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+
+    // $8000~にRAMが割り当てられているときは書き込める
+    fn write(&mut self, addr: u16, data: u8) {
+        if let (false, addr) = self.prg_addr(addr) {
+            self.write_ram(addr, data);
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5204 => {
+                let mut res = 0;
+                if self.irq_pending {
+                    res |= 0x80;
+                }
+                if self.in_frame {
+                    res |= 0x40;
+                }
+                self.irq_pending = false;
+                res
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[addr as usize - 0x5C00],
+            _ => (addr >> 8) as u8, //オープンバス
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attr = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = data,
+            0x5120..=0x5127 => {
+                let bank = data as u16 | (self.chr_upper as u16 & 0x03) << 8;
+                self.chr_banks_a[addr as usize - 0x5120] = bank;
+                self.last_chr_b = false;
+            }
+            0x5128..=0x512B => {
+                let bank = data as u16 | (self.chr_upper as u16 & 0x03) << 8;
+                self.chr_banks_b[addr as usize - 0x5128] = bank;
+                self.last_chr_b = true;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5C00..=0x5FFF => {
+                // 0,1のときは描画中しか書けない 描画中でなければ0が書かれる
+                let value = match self.exram_mode {
+                    0 | 1 if self.in_frame => data,
+                    0 | 1 => 0,
+                    2 => data,
+                    _ => return,
+                };
+                self.exram[addr as usize - 0x5C00] = value;
+            }
+            _ => {}
+        }
+    }
+
+    fn snoop_ppu_register(&mut self, addr: u16, data: u8) {
+        if addr == 0x2000 {
+            self.sprite_8x16 = data & 0x20 != 0;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // ExRAMとフィルモードはread_nametableで横取りするのでページはどれでもいい
+        let mut pages = [0; 4];
+        for (i, page) in pages.iter_mut().enumerate() {
+            *page = (self.nametable >> (i * 2)) & 0x01;
+        }
+        Mirroring::QUADRANTS(pages)
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        let (_, addr) = self.prg_addr(addr);
+        self.write_ram(addr, data);
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        let (_, addr) = self.prg_addr(addr);
+        self.prg_ram[addr]
+    }
+    fn poke_prg_ram(&mut self, addr: u16, data: u8) -> bool {
+        let (_, addr) = self.prg_addr(addr);
+        self.prg_ram[addr] = data;
+        true
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        if raw.is_empty() {
+            return;
+        }
+        let size = self.prg_ram.len();
+        self.prg_ram = raw.to_vec();
+        self.prg_ram.resize(size, 0xFF);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        match self.prg_addr(addr) {
+            (true, addr) => self.rom.prg_rom[addr],
+            (false, addr) => self.prg_ram[addr],
+        }
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+
+    // RAMが割り当てられているときはROMの外を返す
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        match self.prg_addr(addr) {
+            (true, addr) => addr,
+            (false, addr) => self.rom.prg_rom.len() + addr,
+        }
+    }
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        self.chr_addr(addr, self.use_chr_b(PpuFetch::Data))
+    }
+    fn prg_layout(&self) -> PrgLayout {
+        PrgLayout::new(self.rom.prg_rom.len(), 8 * 1024, &[0xE000])
+    }
+
+    // PPUが3サイクル何も読まなければ描画していない (VBlankに入った)
+    fn cpu_clock(&mut self) {
+        self.idle_cycles = self.idle_cycles.saturating_add(1);
+        if self.idle_cycles >= 3 {
+            if self.in_frame {
+                self.irq_pending = false;
+            }
+            self.in_frame = false;
+            self.same_count = 0;
+        }
+        if self.battery.clock() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn flush(&mut self) {
+        if self.battery.is_dirty() {
+            self.battery.save(&self.rom, &self.prg_ram);
+        }
+    }
+    fn is_irq(&mut self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // PRGは8kBごと、CHRは1kBごとにバンク番号で埋めたROM
+    fn mmc5() -> Mapper5 {
+        let mut rom = Rom::empty();
+        rom.mapper = 5;
+        rom.prg_rom = (0..256 * 1024).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = (0..256 * 1024).map(|i| (i / 0x400) as u8).collect();
+        let mut m = Mapper5::new();
+        m.set_rom(rom);
+        m
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut m = mmc5();
+        // 起動したときはモード3で$E000~は$5117 (0xFF)
+        assert_eq!(m.read_prg_rom(0xE000), 31);
+
+        m.write_register(0x5114, 0x85);
+        m.write_register(0x5115, 0x86);
+        m.write_register(0x5116, 0x87);
+        assert_eq!(m.read_prg_rom(0x8000), 5);
+        assert_eq!(m.read_prg_rom(0xA000), 6);
+        assert_eq!(m.read_prg_rom(0xC000), 7);
+
+        // モード0は$5117で32kB 下位2bitは無視する
+        m.write_register(0x5100, 0);
+        m.write_register(0x5117, 0x87);
+        assert_eq!(m.read_prg_rom(0x8000), 4);
+        assert_eq!(m.read_prg_rom(0xE000), 7);
+
+        // モード1は$5115と$5117で16kBずつ
+        m.write_register(0x5100, 1);
+        m.write_register(0x5115, 0x83);
+        assert_eq!(m.read_prg_rom(0x8000), 2);
+        assert_eq!(m.read_prg_rom(0xA000), 3);
+        assert_eq!(m.read_prg_rom(0xC000), 6);
+        assert_eq!(m.read_prg_rom(0xE000), 7);
+    }
+
+    #[test]
+    fn test_prg_ram_banks_and_protect() {
+        let mut m = mmc5();
+        m.write_register(0x5113, 1);
+        // $5102=2, $5103=1でないと書けない
+        m.write_prg_ram(0x6000, 0x42);
+        assert_eq!(m.read_prg_ram(0x6000), 0xFF);
+        m.write_register(0x5102, 0x02);
+        m.write_register(0x5103, 0x01);
+        m.write_prg_ram(0x6000, 0x42);
+        assert_eq!(m.read_prg_ram(0x6000), 0x42);
+
+        m.write_register(0x5113, 0);
+        assert_eq!(m.read_prg_ram(0x6000), 0xFF);
+
+        // bit7が立っていなければ$8000~にもRAMを置ける
+        m.write_register(0x5114, 0x01);
+        assert_eq!(m.read_prg_rom(0x8000), 0x42);
+        m.write(0x8001, 0x43);
+        m.write_register(0x5113, 1);
+        assert_eq!(m.read_prg_ram(0x6001), 0x43);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut m = mmc5();
+        for i in 0..8 {
+            m.write_register(0x5120 + i, 10 + i as u8);
+        }
+        // 起動したときは8kBモードで$5127だけを使う
+        assert_eq!(m.read_chr_rom(0x0000), 136);
+        assert_eq!(m.read_chr_rom(0x1C00), 143);
+
+        m.write_register(0x5101, 3);
+        assert_eq!(m.read_chr_rom(0x0000), 10);
+        assert_eq!(m.read_chr_rom(0x1C00), 17);
+
+        // 2kBモード
+        m.write_register(0x5101, 2);
+        assert_eq!(m.read_chr_rom(0x0C00), 13 * 2 + 1);
+
+        // 8x16モードでは背景が$5128~$512Bを使う
+        m.write_register(0x5101, 3);
+        for i in 0..4 {
+            m.write_register(0x5128 + i, 40 + i as u8);
+        }
+        m.snoop_ppu_register(0x2000, 0x20);
+        assert_eq!(
+            m.read_pattern(0x1400, PpuFetch::BackgroundPattern),
+            Some(41)
+        );
+        assert_eq!(m.read_pattern(0x1400, PpuFetch::SpritePattern), Some(15));
+        m.snoop_ppu_register(0x2000, 0x00);
+        assert_eq!(
+            m.read_pattern(0x1400, PpuFetch::BackgroundPattern),
+            Some(15)
+        );
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut m = mmc5();
+        // $2000: CIRAM前半 $2400: 後半 $2800: ExRAM $2C00: フィルモード
+        m.write_register(0x5105, 0b11_10_01_00);
+        assert_eq!(m.mirroring(), Mirroring::QUADRANTS([0, 1, 0, 1]));
+        assert_eq!(m.read_nametable(0x2000, PpuFetch::Nametable), None);
+
+        m.write_register(0x5104, 2);
+        m.write_register(0x5C05, 0x77);
+        m.write_register(0x5104, 0);
+        assert_eq!(m.read_nametable(0x2805, PpuFetch::Nametable), Some(0x77));
+
+        m.write_register(0x5106, 0x33);
+        m.write_register(0x5107, 0x02);
+        assert_eq!(m.read_nametable(0x2C10, PpuFetch::Nametable), Some(0x33));
+        assert_eq!(m.read_nametable(0x2FC0, PpuFetch::Attribute), Some(0xAA));
+    }
+
+    // スキャンラインの始まりのように同じネームテーブルのアドレスを3回読む
+    fn start_scanline(m: &mut Mapper5) {
+        for _ in 0..3 {
+            m.read_nametable(0x2000, PpuFetch::Nametable);
+            m.ppu_fetch(0x2000, PpuFetch::Nametable);
+        }
+        m.read_nametable(0x2001, PpuFetch::Nametable);
+        m.ppu_fetch(0x2001, PpuFetch::Nametable);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut m = mmc5();
+        m.write_register(0x5203, 2);
+        m.write_register(0x5204, 0x80);
+
+        start_scanline(&mut m); //フレームの始まり スキャンライン0
+        start_scanline(&mut m);
+        assert!(!m.is_irq());
+        start_scanline(&mut m);
+        assert!(m.is_irq());
+
+        // $5204を読むと取り消される
+        assert_eq!(m.read_register(0x5204), 0xC0);
+        assert!(!m.is_irq());
+        assert_eq!(m.read_register(0x5204), 0x40);
+
+        // PPUが読まなくなったらフレームの外
+        for _ in 0..3 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.read_register(0x5204), 0x00);
+    }
+
+    #[test]
+    fn test_irq_cleared_between_frames() {
+        let mut m = mmc5();
+        m.write_register(0x5203, 1);
+        start_scanline(&mut m);
+        start_scanline(&mut m);
+        // 無効のまま立ったIRQはVBlankで消える
+        assert!(m.irq_pending);
+        for _ in 0..3 {
+            m.cpu_clock();
+        }
+        assert!(!m.irq_pending);
+
+        // 次のフレームの始まりでも消える
+        start_scanline(&mut m);
+        start_scanline(&mut m);
+        m.irq_pending = true;
+        m.in_frame = false;
+        start_scanline(&mut m);
+        assert!(!m.irq_pending);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut m = mmc5();
+        m.write_register(0x5205, 200);
+        m.write_register(0x5206, 3);
+        assert_eq!(m.read_register(0x5205), 0x58);
+        assert_eq!(m.read_register(0x5206), 0x02);
+    }
+}
@@ -1,3 +1,9 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::ptr::addr_of_mut;
+
+use once_cell::sync::Lazy;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +21,222 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// ピクセルは9bit 下位6bitが色、上位3bitが$2001の強調ビット(赤,緑,青)
+// .palファイルの512色と同じ並び
+static mut PALETTE: Lazy<Palette> = Lazy::new(|| Palette::from_colors(&SYSTEM_PALLETE));
+
+pub unsafe fn palette() -> &'static mut Palette {
+    &mut *addr_of_mut!(PALETTE)
+}
+
+// 強調されなかった色はこれくらい暗くなる
+const EMPHASIS_ATTENUATION: f32 = 0.75;
+
+#[derive(Clone)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>, //512色
+}
+
+// NTSCの信号から色を作るときの調整
+pub struct NtscParams {
+    pub hue: f32, //色相のずれ (1で30度)
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 1.0,
+            gamma: 1.8,
+        }
+    }
+}
+
+impl Palette {
+    // 64色から強調ビットの組み合わせごとの色を作る
+    pub fn from_colors(colors: &[(u8, u8, u8)]) -> Self {
+        let mut all = vec![];
+        for emphasis in 0..8 {
+            for (i, &(r, g, b)) in colors.iter().enumerate() {
+                // $xE,$xFの黒は強調しても変わらない
+                if emphasis == 0 || i & 0x0E == 0x0E {
+                    all.push((r, g, b));
+                    continue;
+                }
+                let scale = |c: u8, bit: u8| {
+                    if emphasis & bit != 0 {
+                        c
+                    } else {
+                        (c as f32 * EMPHASIS_ATTENUATION) as u8
+                    }
+                };
+                all.push((scale(r, 0x01), scale(g, 0x02), scale(b, 0x04)));
+            }
+        }
+        Palette { colors: all }
+    }
+
+    // .palファイル RGBの3byteが64色(強調なし)か512色(強調あり)並んでいる
+    pub fn load(path: &str) -> Result<Self, String> {
+        let raw = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let colors: Vec<(u8, u8, u8)> = raw.chunks_exact(3).map(|c| (c[0], c[1], c[2])).collect();
+        match colors.len() {
+            64 => Ok(Palette::from_colors(&colors)),
+            512 => Ok(Palette { colors }),
+            _ => Err(format!("{}: not a 64 or 512 color palette", path)),
+        }
+    }
+
+    // PPUが出すNTSCの信号を1ピクセル分(12クロック)なぞって、テレビと同じようにYIQからRGBにする
+    pub fn ntsc(params: &NtscParams) -> Self {
+        const BLACK: f32 = 0.518;
+        const WHITE: f32 = 1.962;
+        const ATTENUATION: f32 = 0.746;
+        // 信号の電圧 前半4つが低い方、後半4つが高い方
+        const LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+
+        // 色相ごとに位相のずれた矩形波になっている
+        let wave = |phase: usize, color: usize| (color + phase + 8) % 12 < 6;
+        let gamma_fix = |f: f32| {
+            if f <= 0.0 {
+                0.0
+            } else {
+                f.powf(2.2 / params.gamma)
+            }
+        };
+        let to_u8 = |f: f32| (255.95 * gamma_fix(f)).clamp(0.0, 255.0) as u8;
+
+        let mut colors = vec![];
+        for pixel in 0..512 {
+            let color = pixel & 0x0F;
+            let level = if color < 0x0E { (pixel >> 4) & 0x03 } else { 1 };
+            let low = LEVELS[level + if color == 0x00 { 4 } else { 0 }];
+            let high = LEVELS[level + if color < 0x0D { 4 } else { 0 }];
+
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let mut spot = if wave(phase, color) { high } else { low };
+                // 強調ビットは対応する位相の間だけ信号を弱める
+                if (pixel & 0x040 != 0 && wave(phase, 12))
+                    || (pixel & 0x080 != 0 && wave(phase, 4))
+                    || (pixel & 0x100 != 0 && wave(phase, 8))
+                {
+                    spot *= ATTENUATION;
+                }
+                let mut v = (spot - BLACK) / (WHITE - BLACK);
+                v = (v - 0.5) * params.contrast + 0.5;
+                v *= params.brightness / 12.0;
+
+                let angle = PI / 6.0 * (phase as f32 + params.hue);
+                y += v;
+                i += v * angle.cos();
+                q += v * angle.sin();
+            }
+            i *= params.saturation;
+            q *= params.saturation;
+
+            colors.push((
+                to_u8(y + 0.946882 * i + 0.623557 * q),
+                to_u8(y - 0.274788 * i - 0.635691 * q),
+                to_u8(y - 1.108545 * i + 1.709007 * q),
+            ));
+        }
+        Palette { colors }
+    }
+
+    pub fn rgb(&self, pixel: u16) -> (u8, u8, u8) {
+        self.colors[pixel as usize & 0x1FF]
+    }
+}
+
+// 起動オプションから切り替えられるパレットの一覧と、最初に使うものを作る
+//  --palette FILE   .palファイルを使う 何回でも指定できる
+//  --palette ntsc   NTSCの信号から作ったものを使う
+//  --ntsc-hue N / --ntsc-saturation N / --ntsc-contrast N / --ntsc-brightness N / --ntsc-gamma N
+pub fn palettes_from_args(args: &[String]) -> (Vec<(String, Palette)>, usize) {
+    let value = |name: &str| {
+        args.iter()
+            .skip_while(|a| a.as_str() != name)
+            .nth(1)
+            .map(|n| {
+                n.parse::<f32>()
+                    .unwrap_or_else(|_| panic!("{} needs a number", name))
+            })
+    };
+    let default = NtscParams::default();
+    let params = NtscParams {
+        hue: value("--ntsc-hue").unwrap_or(default.hue),
+        saturation: value("--ntsc-saturation").unwrap_or(default.saturation),
+        contrast: value("--ntsc-contrast").unwrap_or(default.contrast),
+        brightness: value("--ntsc-brightness").unwrap_or(default.brightness),
+        gamma: value("--ntsc-gamma").unwrap_or(default.gamma),
+    };
+
+    let mut palettes = vec![
+        (
+            String::from("default"),
+            Palette::from_colors(&SYSTEM_PALLETE),
+        ),
+        (String::from("ntsc"), Palette::ntsc(&params)),
+    ];
+    let mut selected = 0;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--palette" {
+            continue;
+        }
+        let path = args.next().expect("--palette needs a file");
+        if path == "ntsc" {
+            selected = 1;
+            continue;
+        }
+        palettes.push((path.clone(), Palette::load(path).unwrap()));
+        if selected == 0 {
+            selected = palettes.len() - 1;
+        }
+    }
+    (palettes, selected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_pal(name: &str, raw: &[u8]) -> String {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, raw).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_load_64_colors() {
+        let raw: Vec<u8> = (0..64 * 3).map(|i| i as u8).collect();
+        let palette = Palette::load(&write_pal("famicon_test_64.pal", &raw)).unwrap();
+        assert_eq!(palette.rgb(0x01), (3, 4, 5));
+        // 強調なしの色はそのまま、赤を強調すると緑と青が暗くなる
+        assert_eq!(palette.rgb(0x40 | 0x01), (3, 3, 3));
+        // $xE,$xFは強調しても変わらない
+        assert_eq!(palette.rgb(0x40 | 0x0E), palette.rgb(0x0E));
+    }
+
+    #[test]
+    fn test_load_512_colors() {
+        let raw: Vec<u8> = (0..512 * 3).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::load(&write_pal("famicon_test_512.pal", &raw)).unwrap();
+        assert_eq!(palette.rgb(0x141), (0x41, 0x41, 0x41));
+    }
+
+    #[test]
+    fn test_load_wrong_size() {
+        let path = write_pal("famicon_test_bad.pal", &[0; 100]);
+        assert!(Palette::load(&path).is_err());
+    }
+}
@@ -1,33 +1,81 @@
 use crate::frame::Frame;
-use crate::render::render;
-use crate::{rom::Mirroring, MAPPER};
+use crate::ppu_bus::PpuFetch;
+use crate::render::compose_pixel;
+use crate::{cdl::cdl, mapper::mapper};
 use bitflags::bitflags;
-use log::{debug, info, trace};
+use log::debug;
+
+// 1ドットずつ進めるPPU
+// スクロールとアドレスは loopy の v/t/x/w レジスタで管理する
+//  v: 今描画している(または$2007でアクセスする)VRAMアドレス 15bit
+//  t: 次に使うVRAMアドレス 画面の左上のタイル
+//  x: 細かいXスクロール 3bit
+//  w: $2005/$2006の1回目と2回目の書き込みを切り替えるラッチ
+//
+// vとtのビットの並び
+//  yyy NN YYYYY XXXXX
+//  ||| || ||||| +++++-- coarse X (タイルの列)
+//  ||| || +++++-------- coarse Y (タイルの行)
+//  ||| ++-------------- ネームテーブル
+//  +++----------------- fine Y (タイルの中の行)
+
+const DOTS: usize = 341;
+const VISIBLE_SCANLINES: usize = 240;
+const VBLANK_SCANLINE: usize = 241;
+const PRE_RENDER_SCANLINE: usize = 261;
+const SPRITES_PER_LINE: usize = 8;
+
+// 次のラインに描くスプライト セカンダリOAMに入ったものをパターンまで読んだ状態
+#[derive(Clone, Copy)]
+pub struct LineSprite {
+    pub index: usize, //OAMの番号 0ならスプライト0
+    pub x: u8,
+    pub attr: u8,
+    // 左右反転は読んだときに済ませてある 最上位bitが左端
+    pub pattern_lo: u8,
+    pub pattern_hi: u8,
+    addr: u16, //パターンテーブルのこのラインの行
+}
 
 pub struct NesPPU {
     pub palette_table: [u8; 32], //色の情報
-    pub vram: [u8; 2048],
+    pub vram: [u8; 4096],        //前半2kBが本体のCIRAM 後半2kBは4画面のカートリッジが持つVRAM
 
-    cycles: usize,
+    cycles: usize, //スキャンラインの中のドット 0~340
     scanline: usize,
+    odd_frame: bool,
     pub nmi_interrupt: Option<i32>,
     pub clear_nmi_interrupt: bool,
 
     internal_data_buf: u8,
 
-    //描画中にパレットテーブルを書き換えることができるのでその対応
-    //書き込まれた時点でのscanlineとその時のパレットのスナップショットを持っておき
-    //レンダリング時にその履歴を参照して描画することで可能
-    pub scanline_palette_indexes: Vec<usize>,
-    pub scanline_palette_tables: Vec<[u8; 32]>,
-
-    pub ctrl: ControlRegister,  //0x2000 割り込みなどPPUの設定 write
-    mask: MaskRegister,         //0x2001 拝啓enableなどのPPUの設定 write
-    status: StatusRegister,     //0x2002 PPUのステータス read
-    pub oam_addr: u8,           //0x2003 書き込むスプライト領域のアドレス write
-    pub oam_data: [u8; 256],    //0x2004 スプライト領域のデータ read/write
-    pub scroll: ScrollRegister, //0x2005 scroll write
-    addr: AddrRegister, //0x2006(0x2007) 書き込むPPUメモリ領域のアドレス、データ (0x2006 write, 0x2007 read/write)
+    pub ctrl: ControlRegister, //0x2000 割り込みなどPPUの設定 write
+    pub mask: MaskRegister,    //0x2001 拝啓enableなどのPPUの設定 write
+    status: StatusRegister,    //0x2002 PPUのステータス read
+    pub oam_addr: u8,          //0x2003 書き込むスプライト領域のアドレス write
+    pub oam_data: [u8; 256],   //0x2004 スプライト領域のデータ read/write
+
+    // 0x2005/0x2006/0x2007 で共有する
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+
+    // 背景のフェッチ 8ドットかけて次のタイルを読む
+    next_tile_id: u8,
+    next_tile_attr: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
+
+    // 背景のシフトレジスタ 上位8bitが今描画しているタイル、下位8bitが次のタイル
+    bg_pattern_lo: u16,
+    bg_pattern_hi: u16,
+    bg_attr_lo: u16,
+    bg_attr_hi: u16,
+
+    pub line_sprites: Vec<LineSprite>,
+    // falseにすると1ライン9個以上のスプライトも描く (ちらつきがなくなる)
+    pub sprite_limit: bool,
 }
 
 impl NesPPU {
@@ -35,48 +83,68 @@ impl NesPPU {
     pub fn new() -> Self {
         NesPPU {
             palette_table: [0; 32],
-            vram: [0; 2048],
+            vram: [0; 4096],
             oam_addr: 0,
             oam_data: [0; 64 * 4],
-            addr: AddrRegister::new(),
             ctrl: ControlRegister::new(),
             status: StatusRegister::new(),
             mask: MaskRegister::new(),
-            scroll: ScrollRegister::new(),
             internal_data_buf: 0,
             cycles: 0,
             scanline: 0,
+            odd_frame: false,
             nmi_interrupt: None,
             clear_nmi_interrupt: false,
-            scanline_palette_indexes: vec![],
-            scanline_palette_tables: vec![],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            next_tile_id: 0,
+            next_tile_attr: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attr_lo: 0,
+            bg_attr_hi: 0,
+            line_sprites: vec![],
+            sprite_limit: true,
         }
     }
 
+    // 1回目: tの上位6bit 2回目: tの下位8bit、tをvにコピー
     pub fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        if !self.w {
+            self.t = (self.t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | value as u16;
+            self.v = self.t;
+            // 描画していないときはvがそのままアドレスバスに出る (MMC3のA12をこれで動かすゲームもある)
+            if !(self.is_rendering_line() && self.is_rendering_enabled()) {
+                unsafe { mapper().ppu_fetch(self.v & 0x3FFF, PpuFetch::Data) };
+            }
+        }
+        self.w = !self.w;
     }
 
     pub fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        let addr = self.v & 0x3FFF;
         self.increment_vram_addr();
         debug!("WRITE PPU: {:04X} => {:02X}", addr, value);
 
         match addr {
             0..=0x1FFF => unsafe {
                 debug!("write CHR_ROM {:04X} => {:02X}", addr, value);
-                if MAPPER.is_chr_ram() {
-                    MAPPER.write_chr_rom(addr, value);
+                if mapper().is_chr_ram() {
+                    mapper().write_chr_rom(addr, value);
                 }
             },
-            0x2000..=0x2FFF => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
-            }
-            0x3000..=0x3EFF => {
-                // FIXME
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+            0x2000..=0x3EFF => {
+                if !unsafe { mapper().write_nametable(addr, value) } {
+                    self.vram[self.mirror_vram_addr(addr) as usize] = value;
+                }
             }
-            0x3F00..=0x3F1F => {
+            0x3F00..=0x3FFF => {
                 debug!(
                     "WRITE PALATTE {:04X} {:02X} => ({:02X}) SL={}",
                     addr,
@@ -84,63 +152,15 @@ impl NesPPU {
                     value,
                     self.scanline
                 );
-                self.write_palette_table(addr, value)
-            }
-            0x3F20..=0x3FFF => {
-                debug!(
-                    "WRITE PALATTE MIRROR {:04X} {:02X} => ({:02X}) SL={}",
-                    addr,
-                    self.mirror_palette_addr(addr) as usize,
-                    value,
-                    self.scanline
-                );
-                self.write_palette_table(addr, value)
+                self.palette_table[self.mirror_palette_addr(addr) as usize] = value;
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
-    }
-
-    fn write_palette_table(&mut self, addr: u16, value: u8) {
-        let addr = self.mirror_palette_addr(addr) as usize;
-
-        //palette_tableには最新情報を入れておく
-        self.palette_table[addr] = value;
-
-        let scanline = self.scanline;
-        let last_scanline = self.scanline_palette_indexes.last().unwrap_or(&0);
-
-        if *last_scanline != scanline {
-            self.scanline_palette_indexes.push(scanline);
-            self.scanline_palette_tables
-                .push(self.palette_table.clone());
-        } else {
-            self.scanline_palette_tables.pop();
-            self.scanline_palette_tables
-                .push(self.palette_table.clone());
+        if addr < 0x3F00 {
+            unsafe { mapper().ppu_fetch(addr, PpuFetch::Data) };
         }
     }
 
-    fn clear_palette_table_histories(&mut self) {
-        self.scanline_palette_indexes = vec![];
-        self.scanline_palette_tables = vec![];
-    }
-
-    pub fn read_palette_table(&self, scanline: usize) -> &[u8; 32] {
-        if self.scanline_palette_indexes.is_empty() {
-            return &self.palette_table;
-        }
-
-        let mut index = 0;
-        for (i, s) in self.scanline_palette_indexes.iter().enumerate() {
-            if *s > scanline {
-                break;
-            }
-            index = i
-        }
-        let table = &self.scanline_palette_tables[index];
-        table
-    }
-
     fn mirror_palette_addr(&self, addr: u16) -> u16 {
         let addr = addr & 0x1F;
         match addr {
@@ -156,6 +176,8 @@ impl NesPPU {
     pub fn write_to_ctrl(&mut self, value: u8) {
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
+        // ネームテーブルの選択はtに入る
+        self.t = (self.t & 0xF3FF) | ((value as u16 & 0x03) << 10);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
@@ -170,8 +192,8 @@ impl NesPPU {
     }
 
     pub fn read_status(&mut self) -> u8 {
-        self.scroll.reset();
-        self.addr.reset_latch();
+        //一回しか書き込まなかったときのためにラッチをリセットする
+        self.w = false;
         let bits = self.status.bits();
         self.status.reset_vblank_status();
         self.clear_nmi_interrupt = true;
@@ -206,44 +228,73 @@ impl NesPPU {
         self.oam_data = values;
     }
 
+    // 1回目: coarse Xとx 2回目: coarse Yとfine Y
     pub fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.set(value);
+        if !self.w {
+            self.t = (self.t & 0xFFE0) | (value as u16 >> 3);
+            self.x = value & 0x07;
+        } else {
+            self.t =
+                (self.t & 0x8C1F) | ((value as u16 & 0xF8) << 2) | ((value as u16 & 0x07) << 12);
+        }
+        self.w = !self.w;
     }
 
     fn increment_vram_addr(&mut self) {
-        self.addr.increment(self.ctrl.vram_addr_increment());
+        // 描画中に$2007を触るとcoarse XとYが両方進む
+        if self.is_rendering_line() && self.is_rendering_enabled() {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            self.v = self.v.wrapping_add(self.ctrl.vram_addr_increment() as u16) & 0x7FFF;
+        }
     }
 
     pub fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.v & 0x3FFF;
         self.increment_vram_addr();
         debug!("READ PPU: {:04X}", addr);
 
         match addr {
-            0..=0x1FFF => {
-                let result = self.internal_data_buf;
-                self.internal_data_buf = unsafe { MAPPER.read_chr_rom(addr) };
-                result
-            }
-            0x2000..=0x2FFF => {
-                let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
-                result
-            }
-            0x3000..=0x3EFF => {
+            0..=0x3EFF => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf = self.read_bus(addr, PpuFetch::Data);
                 result
             }
             0x3F00..=0x3FFF => {
-                self.internal_data_buf =
-                    self.palette_table[self.mirror_palette_addr(addr) as usize];
-                self.internal_data_buf
+                // パレットはすぐに返る バッファには下にあるネームテーブルが入る
+                self.internal_data_buf = self.read_bus(addr & 0x2FFF, PpuFetch::Data);
+                self.palette_table[self.mirror_palette_addr(addr) as usize]
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
+    // パターンテーブルとネームテーブルの読み込みは全部ここを通してマッパーに見せる
+    fn read_bus(&mut self, addr: u16, kind: PpuFetch) -> u8 {
+        let value = unsafe {
+            match addr {
+                0..=0x1FFF => {
+                    if kind == PpuFetch::Data {
+                        cdl().log_chr_read(addr);
+                    } else {
+                        cdl().log_chr_rendered(addr);
+                    }
+                    match mapper().read_pattern(addr, kind) {
+                        Some(value) => value,
+                        None => mapper().read_chr_rom(addr),
+                    }
+                }
+                _ => match mapper().read_nametable(addr, kind) {
+                    Some(value) => value,
+                    None => self.vram[self.mirror_vram_addr(addr) as usize],
+                },
+            }
+        };
+        unsafe { mapper().ppu_fetch(addr, kind) };
+        value
+    }
+
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         // mirror down 0x3000~0x3eff to 0x2000~0x2eff
         let mirrored_vram = addr & 0b10_1111_1111_1111;
@@ -254,100 +305,371 @@ impl NesPPU {
         // to the name table index
         let name_table = vram_index / 0x400;
 
-        let mirroring = unsafe { MAPPER.mirroring() };
+        // ネームテーブルごとにマッパーが決めたページを使う
+        let page = unsafe { mapper().mirroring() }.pages()[name_table as usize] as u16;
+        page * 0x400 + vram_index % 0x400
+    }
 
-        match (&mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) => vram_index - 0x800,
-            (Mirroring::VERTICAL, 3) => vram_index - 0x800,
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
-            _ => vram_index,
-        }
+    pub fn scanline(&self) -> usize {
+        self.scanline
     }
 
+    // スキャンラインの中の位置 (0~340)
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    fn is_rendering_line(&self) -> bool {
+        self.scanline < VISIBLE_SCANLINES || self.scanline == PRE_RENDER_SCANLINE
+    }
+
+    // フレームの最後まで進んだらtrue
     pub fn tick(&mut self, cycles: u8, frame: &mut Frame) -> bool {
-        self.cycles += cycles as usize;
-        //画面一列で341サイクル
-        if self.cycles >= 341 {
-            if self.is_sprite_zero_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+        let mut frame_end = false;
+        for _ in 0..cycles {
+            frame_end |= self.step(frame);
+        }
+        frame_end
+    }
+
+    fn step(&mut self, frame: &mut Frame) -> bool {
+        let dot = self.cycles;
+        let rendering = self.is_rendering_enabled();
+
+        if self.is_rendering_line() {
+            if self.scanline == PRE_RENDER_SCANLINE && dot == 1 {
+                self.status.reset_vblank_status();
+                self.status.set_sprite_zero_hit(false);
+                self.status.set_sprite_overflow(false);
             }
-            self.cycles = self.cycles - 341;
-            self.scanline += 1;
 
-            // 1タイル分見終わったときここでrenderで描画する
-            // NOTE 描画は1タイルずつ行う
-            // スプライトゼロヒットはh=2から始まり、ピクセル出力はさらに2tick遅れる
-            if self.scanline % 8 == 6 {
-                render(&self, frame, self.scanline + 2);
+            // シフトしてから今のドットの色を決める
+            if rendering {
+                self.fetch_background(dot);
             }
 
-            unsafe {
-                MAPPER.scanline(
-                    self.scanline,
-                    self.mask.show_background() || self.mask.show_sprites(),
-                )
+            if self.scanline < VISIBLE_SCANLINES && (1..=256).contains(&dot) {
+                self.render_pixel(frame, dot - 1);
             }
 
-            //0~262lineのうち241~は画面外
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    // self.status.set_vblank_status(true);
-                    self.nmi_interrupt = Some(1);
+            // 本当は65~256で評価するが、257でまとめて行う
+            // プリレンダーラインでは評価しないので、スキャンライン0にはスプライトが出ない
+            if dot == 257 {
+                if rendering && self.scanline < VISIBLE_SCANLINES {
+                    self.evaluate_sprites();
+                } else {
+                    self.line_sprites.clear();
                 }
             }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
-                self.nmi_interrupt = None;
-                self.clear_palette_table_histories();
-                return true;
+            if rendering {
+                self.fetch_sprites(dot);
             }
 
-            if self.scanline == 257 {
+            if (257..=320).contains(&dot) {
                 // OAMADDR は、プリレンダリングおよび表示可能なスキャンラインのティック
                 // 257 ～ 320 (スプライト タイルの読み込み間隔) のそれぞれの間に 0 に設定されます。
                 self.oam_addr = 0;
             }
         }
-        return false;
+
+        //0~262lineのうち241~は画面外
+        if self.scanline == VBLANK_SCANLINE && dot == 1 {
+            self.status.set_vblank_status(true);
+            if self.ctrl.generate_vblank_nmi() {
+                self.nmi_interrupt = Some(1);
+            }
+        }
+
+        self.cycles += 1;
+        // 奇数フレームはプリレンダーラインの最後のドットを飛ばす
+        if self.scanline == PRE_RENDER_SCANLINE
+            && self.cycles == DOTS - 1
+            && self.odd_frame
+            && rendering
+        {
+            self.cycles += 1;
+        }
+        if self.cycles >= DOTS {
+            self.cycles = 0;
+            self.scanline += 1;
+            if self.scanline > PRE_RENDER_SCANLINE {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                return true;
+            }
+        }
+        false
     }
 
-    fn is_sprite_zero_hit(&self, cycle: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let tile_idx = self.oam_data[1] as u16;
-        let x = self.oam_data[3] as usize;
+    // 背景のフェッチとvの更新
+    fn fetch_background(&mut self, dot: usize) {
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.next_tile_id =
+                        self.read_bus(0x2000 | (self.v & 0x0FFF), PpuFetch::Nametable);
+                }
+                2 => {
+                    let addr = 0x23C0
+                        | (self.v & 0x0C00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    let attr = self.read_bus(addr, PpuFetch::Attribute);
+                    // 属性テーブルの1byteは4x4タイル 2x2タイルごとに2bitずつ
+                    let shift = ((self.v >> 4) & 0x04) | (self.v & 0x02);
+                    self.next_tile_attr = (attr >> shift) & 0x03;
+                }
+                4 => self.next_tile_lo = self.read_pattern(0),
+                6 => self.next_tile_hi = self.read_pattern(8),
+                7 => self.increment_x(),
+                _ => {}
+            }
+        }
+
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                // 水平方向をtから戻す
+                self.v = (self.v & !0x041F) | (self.t & 0x041F);
+            }
+            // 使われないネームテーブルの読み込み
+            338 | 340 => {
+                self.next_tile_id = self.read_bus(0x2000 | (self.v & 0x0FFF), PpuFetch::Nametable)
+            }
+            _ => {}
+        }
 
-        // タイルの取得
-        let bank: u16 = self.ctrl.sprite_pattern_addr();
-        let start = bank + tile_idx * 16;
-        let mut tile: [u8; 16] = [0; 16];
-        for i in 0..=15 {
-            tile[i] = unsafe { MAPPER.read_chr_rom(start + i as u16) };
+        // プリレンダーラインで垂直方向をtから戻す
+        if self.scanline == PRE_RENDER_SCANLINE && (280..=304).contains(&dot) {
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
         }
+    }
 
-        let current = self.scanline as i32 - (y as i32);
-        if 0 <= current && current <= 7 && x <= cycle && self.mask.show_sprites() {
-            let line = self.scanline % 8;
+    fn read_pattern(&mut self, plane: u16) -> u8 {
+        let fine_y = (self.v >> 12) & 0x07;
+        let addr =
+            self.ctrl.background_pattern_addr() + self.next_tile_id as u16 * 16 + plane + fine_y;
+        self.read_bus(addr, PpuFetch::BackgroundPattern)
+    }
 
-            let mut upper = tile[line];
-            let mut lower = tile[line + 8];
-            'ololo: for _x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                match value {
-                    0 => continue 'ololo, //skip coloring the pixel
-                    _ => return true,
-                };
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_lo = (self.bg_pattern_lo & 0xFF00) | self.next_tile_lo as u16;
+        self.bg_pattern_hi = (self.bg_pattern_hi & 0xFF00) | self.next_tile_hi as u16;
+        // 属性は1タイルの間変わらないので8bit全部に広げる
+        let attr_lo = if self.next_tile_attr & 0x01 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        let attr_hi = if self.next_tile_attr & 0x02 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        self.bg_attr_lo = (self.bg_attr_lo & 0xFF00) | attr_lo;
+        self.bg_attr_hi = (self.bg_attr_hi & 0xFF00) | attr_hi;
+    }
+
+    fn shift_background(&mut self) {
+        self.bg_pattern_lo <<= 1;
+        self.bg_pattern_hi <<= 1;
+        self.bg_attr_lo <<= 1;
+        self.bg_attr_hi <<= 1;
+    }
+
+    // coarse Xを進める 32を超えたら隣のネームテーブル
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // fine Yを進める 8を超えたらcoarse Y 30を超えたら下のネームテーブル
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // 属性テーブルの行からは折り返すだけ
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // 背景の1ピクセル 0ならパレットの0番(背景色)
+    pub fn background_pixel(&self) -> u8 {
+        if !self.mask.show_background() {
+            return 0;
+        }
+        let bit = 0x8000 >> self.x;
+        let pixel =
+            ((self.bg_pattern_hi & bit != 0) as u8) << 1 | (self.bg_pattern_lo & bit != 0) as u8;
+        if pixel == 0 {
+            return 0;
+        }
+        let palette =
+            ((self.bg_attr_hi & bit != 0) as u8) << 1 | (self.bg_attr_lo & bit != 0) as u8;
+        palette << 2 | pixel
+    }
+
+    fn render_pixel(&mut self, frame: &mut Frame, x: usize) {
+        let (index, sprite_zero_hit) = compose_pixel(self, x);
+        if sprite_zero_hit {
+            self.status.set_sprite_zero_hit(true);
+        }
+        //64(0x3F)でマスクして255までのindexを64までにする
+        let mut color = self.palette_table[index as usize] & 0x3F;
+        if self.mask.is_grayscale() {
+            color &= 0x30;
+        }
+        let pixel = (self.mask.emphasis() as u16) << 6 | color as u16;
+        frame.set_pixel_index(x, self.scanline, pixel);
+    }
+
+    // 今のスキャンラインにかかるスプライトを探して、次のラインに描くものを決める
+    fn evaluate_sprites(&mut self) {
+        let height = if self.ctrl.is_sprite_8x16_mode() {
+            16
+        } else {
+            8
+        };
+        let in_range = |y: u8| {
+            let row = self.scanline as isize - y as isize;
+            (0..height).contains(&row)
+        };
+
+        let mut found: Vec<usize> = vec![];
+        let mut n = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4]) {
+                found.push(n);
+                if found.len() == SPRITES_PER_LINE {
+                    n += 1;
+                    break;
+                }
             }
+            n += 1;
+        }
+
+        // 9個目を探すとき、ハードウェアのバグでYではなくタイル番号や属性をYとして比べてしまう
+        // 見つからなかったスプライトごとにmも1つずれていく
+        let mut m = 0;
+        while found.len() == SPRITES_PER_LINE && n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.status.set_sprite_overflow(true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+
+        // 制限を外すときは正しいYで残りも探す
+        if !self.sprite_limit && found.len() == SPRITES_PER_LINE {
+            let last = found[SPRITES_PER_LINE - 1];
+            found.extend((last + 1..64).filter(|n| in_range(self.oam_data[n * 4])));
+        }
+
+        self.line_sprites = found.iter().map(|n| self.line_sprite(*n, height)).collect();
+    }
+
+    // 257~320の8ドットごとに1つずつスプライトのパターンを読む
+    // 8個に満たないときもタイル$FFを読むので、マッパーからは毎ライン8回読んだように見える
+    fn fetch_sprites(&mut self, dot: usize) {
+        if !(257..=320).contains(&dot) {
+            return;
+        }
+        let slot = (dot - 257) / 8;
+        match (dot - 257) % 8 {
+            // 使われないネームテーブルの読み込み
+            0 | 2 => {
+                self.read_bus(0x2000 | (self.v & 0x0FFF), PpuFetch::Nametable);
+            }
+            4 => self.fetch_sprite_pattern(slot, 0),
+            6 => self.fetch_sprite_pattern(slot, 8),
+            _ => {}
+        }
+
+        // 制限を外して9個目以降があるときは最後にまとめて読む
+        if dot == 320 {
+            for slot in SPRITES_PER_LINE..self.line_sprites.len() {
+                self.fetch_sprite_pattern(slot, 0);
+                self.fetch_sprite_pattern(slot, 8);
+            }
+        }
+    }
+
+    fn fetch_sprite_pattern(&mut self, slot: usize, plane: u16) {
+        let addr = match self.line_sprites.get(slot) {
+            Some(sprite) => sprite.addr,
+            None if self.ctrl.is_sprite_8x16_mode() => 0x1000 + 0xFE * 16,
+            None => self.ctrl.sprite_pattern_addr() + 0xFF * 16,
+        };
+        let data = self.read_bus(addr + plane, PpuFetch::SpritePattern);
+
+        if let Some(sprite) = self.line_sprites.get_mut(slot) {
+            // 左右反転は読んだときに済ませておく
+            let data = if sprite.attr & 0x40 != 0 {
+                data.reverse_bits()
+            } else {
+                data
+            };
+            if plane == 0 {
+                sprite.pattern_lo = data;
+            } else {
+                sprite.pattern_hi = data;
+            }
+        }
+    }
+
+    fn line_sprite(&self, n: usize, height: isize) -> LineSprite {
+        let y = self.oam_data[n * 4];
+        let tile_idx = self.oam_data[n * 4 + 1] as u16;
+        let attr = self.oam_data[n * 4 + 2];
+        let x = self.oam_data[n * 4 + 3];
+
+        let flip_vertical = (attr >> 7 & 1) == 1;
+
+        let mut row = (self.scanline as isize - y as isize) as u16;
+        if flip_vertical {
+            row = height as u16 - 1 - row;
+        }
+
+        let addr = if height == 16 {
+            // 8x16ではtile_idxの最下位ビットでバンクを選び、上下2つのタイルを使う
+            let bank = if tile_idx & 0x01 == 0 { 0 } else { 0x1000 };
+            let tile = (tile_idx & 0xFE) + row / 8;
+            bank + tile * 16 + row % 8
+        } else {
+            self.ctrl.sprite_pattern_addr() + tile_idx * 16 + row
+        };
+
+        LineSprite {
+            index: n,
+            x,
+            attr,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            addr,
         }
-        return false;
     }
 }
 
@@ -405,18 +727,6 @@ impl ControlRegister {
             0x1000
         }
     }
-
-    pub fn name_table_addr(&self) -> u16 {
-        match (
-            self.contains(ControlRegister::NAMETABLE2),
-            self.contains(ControlRegister::NAMETABLE1),
-        ) {
-            (false, false) => 0x2000,
-            (false, true) => 0x2400,
-            (true, false) => 0x2800,
-            (true, true) => 0x2C00,
-        }
-    }
 }
 
 // 0x2001
@@ -446,6 +756,23 @@ impl MaskRegister {
         self.contains(MaskRegister::SHOW_BACKGROUND)
     }
 
+    pub fn is_grayscale(&self) -> bool {
+        self.contains(MaskRegister::GRAYSCALE)
+    }
+
+    // 赤,緑,青の強調ビットを下位3bitにしたもの
+    pub fn emphasis(&self) -> u8 {
+        self.bits() >> 5
+    }
+
+    pub fn show_sprites_in_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_SPRITES_IN_LEFT)
+    }
+
+    pub fn show_background_in_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_BACKGROUND_IN_LEFT)
+    }
+
     pub fn update(&mut self, data: u8) {
         *self.0.bits_mut() = data;
     }
@@ -486,99 +813,316 @@ impl StatusRegister {
         self.set(StatusRegister::SPRITE_ZERO_HIT, value)
     }
 
+    pub fn set_sprite_overflow(&mut self, value: bool) {
+        self.set(StatusRegister::SPRITE_OVERFLOW, value)
+    }
+
     pub fn update(&mut self, data: u8) {
         *self.0.bits_mut() = data;
     }
 }
 
-// 0x2005
-pub struct ScrollRegister {
-    pub scroll_x: u8,
-    pub scroll_y: u8,
-    write_x: bool,
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scroll_and_addr_latch() {
+        // https://www.nesdev.org/wiki/PPU_scrolling の例と同じ順番で書く
+        let mut ppu = NesPPU::new();
+        ppu.write_to_ctrl(0x00);
+        ppu.read_status();
+        assert!(!ppu.w);
+
+        ppu.write_to_scroll(0x7D);
+        assert_eq!(ppu.t, 0x000F);
+        assert_eq!(ppu.x, 0x05);
+        assert!(ppu.w);
+
+        ppu.write_to_scroll(0x5E);
+        assert_eq!(ppu.t, 0x616F);
+        assert!(!ppu.w);
+
+        // 1回目はbit14を消す
+        ppu.write_to_ppu_addr(0x3D);
+        assert_eq!(ppu.t, 0x3D6F);
+        assert!(ppu.w);
+
+        ppu.write_to_ppu_addr(0xF0);
+        assert_eq!(ppu.t, 0x3DF0);
+        assert_eq!(ppu.v, 0x3DF0);
+        assert!(!ppu.w);
+        // xは$2006では変わらない
+        assert_eq!(ppu.x, 0x05);
+    }
 
-impl ScrollRegister {
-    pub fn new() -> Self {
-        ScrollRegister {
-            scroll_x: 0,
-            scroll_y: 0,
-            write_x: true,
-        }
+    #[test]
+    fn test_ctrl_nametable_goes_to_t() {
+        let mut ppu = NesPPU::new();
+        ppu.t = 0x7FFF;
+        ppu.write_to_ctrl(0x01);
+        assert_eq!(ppu.t, 0x77FF);
     }
 
-    fn set(&mut self, data: u8) {
-        if self.write_x {
-            self.scroll_x = data;
-        } else {
-            self.scroll_y = data;
-        }
-        self.write_x = !self.write_x;
+    #[test]
+    fn test_status_read_resets_latch() {
+        let mut ppu = NesPPU::new();
+        ppu.write_to_scroll(0x08);
+        assert!(ppu.w);
+        ppu.read_status();
+        // もう一度1回目として書かれる
+        ppu.write_to_scroll(0x10);
+        assert_eq!(ppu.t & 0x001F, 0x02);
     }
 
-    //一回しかLDAしなかったときのためにリセットをかける
-    pub fn reset(&mut self) {
-        self.write_x = true;
+    #[test]
+    fn test_increment_x_wraps_to_next_nametable() {
+        let mut ppu = NesPPU::new();
+        ppu.v = 0x001E;
+        ppu.increment_x();
+        assert_eq!(ppu.v, 0x001F);
+        ppu.increment_x();
+        assert_eq!(ppu.v, 0x0400);
+        ppu.v = 0x041F;
+        ppu.increment_x();
+        assert_eq!(ppu.v, 0x0000);
     }
-}
 
-// 0x2006
-pub struct AddrRegister {
-    value: (u8, u8),
-    hi_ptr: bool,
-}
+    #[test]
+    fn test_increment_y() {
+        let mut ppu = NesPPU::new();
+        // fine Yが7まではfine Yだけ
+        ppu.v = 0x6000;
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0x7000);
+        // coarse Y 29の次は下のネームテーブル
+        ppu.v = 0x7000 | (29 << 5);
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0x0800);
+        // 属性テーブルの行(31)からはネームテーブルを変えずに0に戻る
+        ppu.v = 0x7000 | (31 << 5);
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0x0000);
+    }
 
-impl AddrRegister {
-    pub fn new() -> Self {
-        AddrRegister {
-            value: (0, 0),
-            hi_ptr: true,
+    // プリレンダーラインの338ドット目から次のフレームまでのドット数
+    fn dots_to_next_frame(odd_frame: bool, mask: u8) -> usize {
+        let mut ppu = NesPPU::new();
+        let mut frame = Frame::new();
+        ppu.mask.update(mask);
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycles = 338;
+        ppu.odd_frame = odd_frame;
+        let mut dots = 1;
+        while !ppu.tick(1, &mut frame) {
+            dots += 1;
         }
+        assert_eq!((ppu.scanline, ppu.dot()), (0, 0));
+        assert_eq!(ppu.odd_frame, !odd_frame);
+        dots
     }
 
-    fn set(&mut self, data: u16) {
-        self.value.0 = (data >> 8) as u8;
-        self.value.1 = (data & 0xFF) as u8;
+    #[test]
+    fn test_odd_frame_skips_last_dot() {
+        assert_eq!(dots_to_next_frame(false, 0x08), 3);
+        assert_eq!(dots_to_next_frame(true, 0x08), 2);
+        // 描画していなければ飛ばさない
+        assert_eq!(dots_to_next_frame(true, 0x00), 3);
     }
 
-    //LDAで読み込む動作一回分
-    //2回やるとloとhiのどちらにも書き込まれる
-    pub fn update(&mut self, data: u8) {
-        if self.hi_ptr {
-            self.value.0 = data;
-        } else {
-            self.value.1 = data;
+    // 全部のスプライトを画面外(Y=$FF)に置いてから、指定したものだけYとタイルを入れる
+    fn ppu_with_sprites(scanline: usize, sprites: &[(usize, u8, u8)]) -> NesPPU {
+        let mut ppu = NesPPU::new();
+        ppu.oam_data = [0xFF; 256];
+        for &(n, y, tile) in sprites {
+            ppu.oam_data[n * 4] = y;
+            ppu.oam_data[n * 4 + 1] = tile;
+            ppu.oam_data[n * 4 + 2] = 0;
+            ppu.oam_data[n * 4 + 3] = n as u8 * 8;
         }
+        ppu.scanline = scanline;
+        ppu
+    }
 
-        // ミラーリング
-        if self.get() > 0x3FFF {
-            self.set(self.get() & 0b11_1111_1111_1111);
-        }
-        self.hi_ptr = !self.hi_ptr;
+    fn line_indexes(ppu: &NesPPU) -> Vec<usize> {
+        ppu.line_sprites.iter().map(|s| s.index).collect()
     }
 
-    pub fn increment(&mut self, inc: u8) {
-        let lo = self.value.1;
-        self.value.1 = self.value.1.wrapping_add(inc);
+    fn is_overflow(ppu: &NesPPU) -> bool {
+        ppu.status.contains(StatusRegister::SPRITE_OVERFLOW)
+    }
 
-        //桁上がりの処理
-        if lo > self.value.1 {
-            self.value.0 = self.value.0.wrapping_add(1);
-        }
+    #[test]
+    fn test_evaluate_sprites_in_range() {
+        // Y=10の8x8スプライトは10~17ラインにかかる
+        let sprites = [(3, 10, 0), (5, 18, 0), (7, 3, 0)];
+        let mut ppu = ppu_with_sprites(17, &sprites);
+        ppu.evaluate_sprites();
+        assert_eq!(line_indexes(&ppu), vec![3]);
+        assert_eq!(ppu.line_sprites[0].addr, 7);
+
+        // 8x16では25ラインまで 下半分は次のタイル
+        let mut ppu = ppu_with_sprites(25, &[(3, 10, 0x21)]);
+        ppu.write_to_ctrl(0x20);
+        ppu.evaluate_sprites();
+        assert_eq!(line_indexes(&ppu), vec![3]);
+        assert_eq!(ppu.line_sprites[0].addr, 0x1000 + 0x21 * 16 + 7);
+        assert!(!is_overflow(&ppu));
+    }
+
+    #[test]
+    fn test_evaluate_sprites_limit_and_overflow() {
+        let mut sprites: Vec<(usize, u8, u8)> = (0..9).map(|n| (n, 20, 0)).collect();
+        let mut ppu = ppu_with_sprites(20, &sprites);
+        ppu.evaluate_sprites();
+        assert_eq!(line_indexes(&ppu), (0..8).collect::<Vec<_>>());
+        assert!(is_overflow(&ppu));
+
+        // 制限を外すと9個目も描く
+        ppu.sprite_limit = false;
+        ppu.evaluate_sprites();
+        assert_eq!(line_indexes(&ppu), (0..9).collect::<Vec<_>>());
+
+        // 8個ちょうどならオーバーフローしない
+        sprites.pop();
+        let mut ppu = ppu_with_sprites(20, &sprites);
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.line_sprites.len(), 8);
+        assert!(!is_overflow(&ppu));
+    }
+
+    #[test]
+    fn test_sprite_overflow_bug() {
+        let eight: Vec<(usize, u8, u8)> = (0..8).map(|n| (n, 20, 0)).collect();
+
+        // 8番は範囲外なので、9番はYではなくタイル番号と比べられる
+        // タイル番号がたまたま範囲内なら、Yが範囲外でもオーバーフローになる
+        let mut sprites = eight.clone();
+        sprites.push((9, 0xF0, 20));
+        let mut ppu = ppu_with_sprites(20, &sprites);
+        ppu.evaluate_sprites();
+        assert!(is_overflow(&ppu));
+
+        // 逆に9番が範囲内でもタイル番号が範囲外ならオーバーフローにならない
+        let mut sprites = eight;
+        sprites.push((9, 20, 0xF0));
+        let mut ppu = ppu_with_sprites(20, &sprites);
+        ppu.evaluate_sprites();
+        assert!(!is_overflow(&ppu));
+        assert_eq!(ppu.line_sprites.len(), 8);
+    }
 
-        // ミラーリング
-        if self.get() > 0x3FFF {
-            self.set(self.get() & 0b11_1111_1111_1111);
+    fn sprite(index: usize, x: u8, attr: u8, pattern_lo: u8) -> LineSprite {
+        LineSprite {
+            index,
+            x,
+            attr,
+            pattern_lo,
+            pattern_hi: 0,
+            addr: 0,
         }
     }
 
-    //一回しかLDAしなかったときのためにリセットをかける
-    pub fn reset_latch(&mut self) {
-        self.hi_ptr = true;
+    // 背景もスプライトも全部表示 背景は色1(不透明)
+    fn ppu_for_compose(sprites: Vec<LineSprite>) -> NesPPU {
+        let mut ppu = NesPPU::new();
+        ppu.mask.update(0x1E);
+        ppu.bg_pattern_lo = 0xFFFF;
+        ppu.line_sprites = sprites;
+        ppu
+    }
+
+    #[test]
+    fn test_compose_pixel_priority() {
+        // 前のスプライトは背景より上 パレットは0x10~
+        let ppu = ppu_for_compose(vec![sprite(1, 16, 0x01, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 16), (0x15, false));
+        // スプライトの外は背景
+        assert_eq!(compose_pixel(&ppu, 24), (0x01, false));
+
+        // 後ろのスプライトは背景が不透明なら隠れる
+        let mut ppu = ppu_for_compose(vec![sprite(1, 16, 0x20, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 16), (0x01, false));
+        ppu.bg_pattern_lo = 0;
+        assert_eq!(compose_pixel(&ppu, 16), (0x11, false));
+
+        // 番号の小さい後ろのスプライトが、番号の大きい前のスプライトも一緒に隠す
+        let ppu = ppu_for_compose(vec![sprite(1, 16, 0x20, 0xFF), sprite(2, 16, 0x02, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 16), (0x01, false));
+
+        // 透明なピクセルは次のスプライトが見える
+        let ppu = ppu_for_compose(vec![sprite(1, 16, 0x00, 0x0F), sprite(2, 16, 0x02, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 16), (0x19, false));
+        assert_eq!(compose_pixel(&ppu, 20), (0x11, false));
+    }
+
+    #[test]
+    fn test_compose_pixel_left_column() {
+        let mut ppu = ppu_for_compose(vec![sprite(1, 0, 0x00, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 7), (0x11, false));
+
+        // スプライトだけ隠す
+        ppu.mask.update(0x1A);
+        assert_eq!(compose_pixel(&ppu, 7), (0x01, false));
+        // 背景も隠すと背景色
+        ppu.mask.update(0x18);
+        assert_eq!(compose_pixel(&ppu, 7), (0x00, false));
+        // 左端の8ピクセルの外は関係ない
+        ppu.line_sprites = vec![sprite(1, 8, 0x00, 0xFF)];
+        assert_eq!(compose_pixel(&ppu, 8), (0x11, false));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        // 背景の後ろにいても不透明どうしが重なればヒット
+        let mut ppu = ppu_for_compose(vec![sprite(0, 100, 0x20, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 100), (0x01, true));
+        // 背景が透明ならヒットしない
+        ppu.bg_pattern_lo = 0;
+        assert_eq!(compose_pixel(&ppu, 100), (0x11, false));
+
+        // x=255ではヒットしない
+        let ppu = ppu_for_compose(vec![sprite(0, 248, 0x00, 0xFF)]);
+        assert_eq!(compose_pixel(&ppu, 254), (0x11, true));
+        assert_eq!(compose_pixel(&ppu, 255), (0x11, false));
+
+        // 左端を隠しているときもヒットしない
+        let mut ppu = ppu_for_compose(vec![sprite(0, 0, 0x00, 0xFF)]);
+        ppu.mask.update(0x1A);
+        assert_eq!(compose_pixel(&ppu, 0), (0x01, false));
+    }
+
+    #[test]
+    fn test_mask_emphasis_bits() {
+        let mut mask = MaskRegister::new();
+        mask.update(0x20);
+        assert_eq!(mask.emphasis(), 0x01); //赤
+        mask.update(0x80);
+        assert_eq!(mask.emphasis(), 0x04); //青
+        mask.update(0xFF);
+        assert_eq!(mask.emphasis(), 0x07);
+        assert!(mask.is_grayscale());
+    }
+
+    // 背景の色1を左上に描いて、出てきたRGBを返す
+    fn render_first_pixel(mask: u8) -> (u8, u8, u8) {
+        let mut ppu = NesPPU::new();
+        let mut frame = Frame::new();
+        ppu.mask.update(mask);
+        ppu.bg_pattern_lo = 0xFFFF;
+        ppu.palette_table[0x01] = 0x16;
+        ppu.render_pixel(&mut frame, 0);
+        (frame.data[0], frame.data[1], frame.data[2])
     }
 
-    // hiとloを一つにする
-    pub fn get(&self) -> u16 {
-        ((self.value.0 as u16) << 8) | (self.value.1 as u16)
+    #[test]
+    fn test_render_pixel_grayscale_and_emphasis() {
+        let rgb = |pixel: u16| unsafe { crate::palette::palette().rgb(pixel) };
+        assert_eq!(render_first_pixel(0x0A), rgb(0x16));
+        // グレースケールは色の下位4bitを消す
+        assert_eq!(render_first_pixel(0x0B), rgb(0x10));
+        // 強調ビットは9bitのピクセルの上位3bitになる
+        assert_eq!(render_first_pixel(0x2A), rgb(0x40 | 0x16));
+        assert_eq!(render_first_pixel(0xEB), rgb(0x1C0 | 0x10));
     }
 }
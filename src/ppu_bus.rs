@@ -0,0 +1,33 @@
+// PPUのアドレスバス($0000~$3EFF)にマッパーから割り込むためのインターフェース
+// 何もしなければパターンテーブルはCHR-ROM/RAM、ネームテーブルは本体のCIRAM(2kB)を読む
+// マッパーはPPUが読んだアドレスを全部見られるので、A12の立ち上がりやタイルのラッチも作れる
+
+// PPUが何のためにそのアドレスを読んだか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuFetch {
+    Nametable,
+    Attribute,
+    BackgroundPattern,
+    SpritePattern,
+    Data, //$2007からの読み書き
+}
+
+pub trait PpuBus {
+    // PPUがアドレスバスに出したアドレス 読んだ(書いた)後に呼ばれる
+    fn ppu_fetch(&mut self, _addr: u16, _kind: PpuFetch) {}
+
+    // パターンテーブルを差し替えるときはSomeを返す
+    fn read_pattern(&mut self, _addr: u16, _kind: PpuFetch) -> Option<u8> {
+        None
+    }
+
+    // ネームテーブルと属性テーブルを差し替えるときはSomeを返す (CIRAMの無効化、ExRAM、フィルモードなど)
+    fn read_nametable(&mut self, _addr: u16, _kind: PpuFetch) -> Option<u8> {
+        None
+    }
+
+    // 書き込みを横取りしたときはtrueを返す
+    fn write_nametable(&mut self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+}
@@ -0,0 +1,270 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use log::info;
+
+use crate::cpu::{peek, Interrupt, CPU};
+use crate::symbols::symbols;
+
+// PCごと、サブルーチンごとに使ったCPUサイクル数を数える
+// cpu.run_with_callbackのcallbackから毎命令on_instructionを呼び出して使う
+//
+//  --profile PREFIX       PREFIX.txt (多い順の表) と PREFIX.folded (flamegraph.pl などに渡す形式) に出力する
+//  --profile-start N      Nフレーム目から数え始める (デフォルト 0)
+//  --profile-frames N     Nフレーム分数えたら出力する (デフォルト 600)
+//
+// サブルーチンはJSRで入ってRTSでSPが戻ったら抜けたとみなす NMI/IRQも1つのサブルーチンとして扱う
+pub struct Profiler {
+    prefix: String,
+    start_frame: usize,
+    end_frame: usize,
+    frame: usize,
+    last_scanline: usize,
+    done: bool,
+
+    // 前の命令 (次の命令が呼ばれた時点でサイクル数が決まる)
+    last_pc: Option<u16>,
+    last_opscode: u8,
+    last_cycles: usize,
+
+    stack: Vec<Frame>,
+    pc_cycles: HashMap<u16, usize>,
+    routines: HashMap<Routine, RoutineStats>,
+    folded: HashMap<Vec<Routine>, usize>,
+    total_cycles: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Routine {
+    Reset,
+    Nmi(u16),
+    Irq(u16),
+    Subroutine(u16),
+}
+
+struct Frame {
+    routine: Routine,
+    sp: u8, //入ったときのSP
+}
+
+#[derive(Default)]
+struct RoutineStats {
+    calls: usize,
+    self_cycles: usize,
+    total_cycles: usize, //呼び出したサブルーチンの分も含む
+}
+
+const OP_JSR: u8 = 0x20;
+
+impl Profiler {
+    // --profile がなければNone
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        let value = |name: &str| {
+            args.iter()
+                .skip_while(|a| a.as_str() != name)
+                .nth(1)
+                .map(|s| s.as_str())
+        };
+        let prefix = value("--profile")?;
+        let start: usize = value("--profile-start")
+            .map(|n| n.parse().expect("--profile-start needs a number"))
+            .unwrap_or(0);
+        let frames: usize = value("--profile-frames")
+            .map(|n| n.parse().expect("--profile-frames needs a number"))
+            .unwrap_or(600);
+
+        Some(Profiler {
+            prefix: String::from(prefix),
+            start_frame: start,
+            end_frame: start + frames,
+            frame: 0,
+            last_scanline: 0,
+            done: false,
+            last_pc: None,
+            last_opscode: 0,
+            last_cycles: 0,
+            stack: vec![],
+            pc_cycles: HashMap::new(),
+            routines: HashMap::new(),
+            folded: HashMap::new(),
+            total_cycles: 0,
+        })
+    }
+
+    pub fn on_instruction(&mut self, cpu: &mut CPU) {
+        if self.done {
+            return;
+        }
+
+        //callbackが呼ばれた時点でPCはopscodeの次を指している
+        let pc = cpu.program_counter.wrapping_sub(1);
+        let cycles = cpu.bus.cycles();
+
+        // 前の命令のサイクル数を今のスタックに加える (割り込みに入る分も含む)
+        if let Some(last_pc) = self.last_pc {
+            if self.frame >= self.start_frame {
+                self.count(last_pc, cycles - self.last_cycles);
+            }
+        }
+
+        self.update_stack(cpu, pc);
+
+        // スキャンラインが0に戻ったら次のフレーム
+        let scanline = cpu.bus.ppu().scanline();
+        if scanline < self.last_scanline {
+            self.frame += 1;
+            if self.frame == self.end_frame {
+                self.write();
+                self.done = true;
+            }
+        }
+        self.last_scanline = scanline;
+
+        self.last_pc = Some(pc);
+        self.last_opscode = peek(cpu, pc);
+        self.last_cycles = cycles;
+    }
+
+    fn update_stack(&mut self, cpu: &CPU, pc: u16) {
+        // RTS/RTI (やスタックを直接いじるコード) でSPが入ったときより上に戻ったら抜けた
+        while let Some(frame) = self.stack.last() {
+            if cpu.stack_pointer <= frame.sp {
+                break;
+            }
+            self.stack.pop();
+        }
+
+        let routine = match cpu.interrupt {
+            Some(Interrupt::Nmi) => Some(Routine::Nmi(pc)),
+            Some(Interrupt::Irq) => Some(Routine::Irq(pc)),
+            None if self.last_opscode == OP_JSR => Some(Routine::Subroutine(pc)),
+            None => None,
+        };
+        if let Some(routine) = routine {
+            self.stack.push(Frame {
+                routine,
+                sp: cpu.stack_pointer,
+            });
+            if self.frame >= self.start_frame {
+                self.routines.entry(routine).or_default().calls += 1;
+            }
+        }
+    }
+
+    fn count(&mut self, pc: u16, cycles: usize) {
+        self.total_cycles += cycles;
+        *self.pc_cycles.entry(pc).or_default() += cycles;
+
+        let mut stack: Vec<Routine> = vec![Routine::Reset];
+        stack.extend(self.stack.iter().map(|f| f.routine));
+
+        // 再帰しているときに二重に数えないようにする
+        let mut seen: Vec<Routine> = vec![];
+        for routine in stack.iter() {
+            if !seen.contains(routine) {
+                self.routines.entry(*routine).or_default().total_cycles += cycles;
+                seen.push(*routine);
+            }
+        }
+        self.routines
+            .entry(*stack.last().unwrap())
+            .or_default()
+            .self_cycles += cycles;
+
+        *self.folded.entry(stack).or_default() += cycles;
+    }
+
+    // 数え終わる前に終了するときに呼ぶ
+    pub fn finish(&self) {
+        if !self.done {
+            self.write();
+        }
+    }
+
+    // panicしたときにも呼ぶ
+    pub fn write(&self) {
+        if self.done || self.total_cycles == 0 {
+            return;
+        }
+        let frames = self.frame.max(self.start_frame + 1) - self.start_frame;
+        // CPUは止まらないので、全体に対する割合がそのまま1フレームの予算に対する割合になる
+        let total = self.total_cycles as f64;
+        let percent = |c: usize| c as f64 * 100.0 / total;
+
+        let path = format!("{}.txt", self.prefix);
+        let mut report = BufWriter::new(File::create(&path).expect("unable to create profile"));
+        writeln!(
+            report,
+            "frames: {}, cycles: {} ({:.0}/frame)",
+            frames,
+            self.total_cycles,
+            total / frames as f64
+        )
+        .unwrap();
+
+        writeln!(
+            report,
+            "\n{:>10} {:>7} {:>10} {:>7} {:>8}  SUBROUTINE",
+            "TOTAL", "%", "SELF", "%", "CALLS"
+        )
+        .unwrap();
+        let mut routines: Vec<(&Routine, &RoutineStats)> = self.routines.iter().collect();
+        routines.sort_by_key(|(_, stats)| Reverse(stats.total_cycles));
+        for (routine, stats) in routines.iter() {
+            writeln!(
+                report,
+                "{:>10} {:>6.2}% {:>10} {:>6.2}% {:>8}  {}",
+                stats.total_cycles,
+                percent(stats.total_cycles),
+                stats.self_cycles,
+                percent(stats.self_cycles),
+                stats.calls,
+                routine_name(routine)
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "\n{:>10} {:>7}  PC", "CYCLES", "%").unwrap();
+        let mut pcs: Vec<(&u16, &usize)> = self.pc_cycles.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1));
+        for (pc, cycles) in pcs.iter() {
+            writeln!(
+                report,
+                "{:>10} {:>6.2}%  {}",
+                cycles,
+                percent(**cycles),
+                addr_name(**pc)
+            )
+            .unwrap();
+        }
+
+        // flamegraph用 reset;NMI;Sub1;Sub2 123
+        let folded_path = format!("{}.folded", self.prefix);
+        let mut folded =
+            BufWriter::new(File::create(&folded_path).expect("unable to create profile"));
+        for (stack, cycles) in self.folded.iter() {
+            let names: Vec<String> = stack.iter().map(routine_name).collect();
+            writeln!(folded, "{} {}", names.join(";"), cycles).unwrap();
+        }
+
+        info!("profile: {} {}", path, folded_path);
+    }
+}
+
+fn routine_name(routine: &Routine) -> String {
+    match routine {
+        Routine::Reset => String::from("reset"),
+        Routine::Nmi(addr) => format!("NMI {}", addr_name(*addr)),
+        Routine::Irq(addr) => format!("IRQ {}", addr_name(*addr)),
+        Routine::Subroutine(addr) => addr_name(*addr),
+    }
+}
+
+fn addr_name(addr: u16) -> String {
+    match unsafe { symbols().name(addr) } {
+        Some(name) => format!("${:04X} {}", addr, name),
+        None => format!("${:04X}", addr),
+    }
+}
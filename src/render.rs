@@ -1,306 +1,56 @@
-use crate::frame::Frame;
-use crate::ppu::NesPPU;
-use crate::rom::Mirroring;
-use crate::{palette, MAPPER};
-
-const SCREEN_W: usize = 256;
-const SCREEN_H: usize = 240;
-
-struct Rect {
-    x1: usize,
-    y1: usize,
-    x2: usize,
-    y2: usize,
-}
-
-impl Rect {
-    fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self {
-        Rect {
-            x1: x1,
-            y1: y1,
-            x2: x2,
-            y2: y2,
-        }
-    }
-}
-
-pub fn render(ppu: &NesPPU, frame: &mut Frame, scanline: usize) {
-    // 描画範囲
-    // 左上から１行描画し、下に8pixelずつ
-    let draw_rect = Rect::new(0, scanline - 8, SCREEN_W, scanline);
-
-    draw_background(ppu, frame, &draw_rect);
-    draw_sprite(ppu, frame, &draw_rect);
-}
-fn draw_background(ppu: &NesPPU, frame: &mut Frame, draw_rect: &Rect) {
-    // scroll_x, scroll_yは実際の画面に描画するピクセルの座標のこと
-    let scroll_x = (ppu.scroll.scroll_x) as usize;
-    let scroll_y = (ppu.scroll.scroll_y) as usize;
-
-    // mirroringはミラーリングのモード
-    let mirroring = unsafe { MAPPER.mirroring() };
-
-    // vram_a, vram_bはA,B画面に表示する情報のこと
-    let vram_a = &ppu.vram[0x000..0x400];
-    let vram_b = &ppu.vram[0x400..0x800];
-
-    /*
-       vertical     horizontal     screen
-       A  B         A  A           0x2000~0x23FF  0x2400~0x27FF
-       A  B         B  B           0x2800~0x2BFF  0x2C00~0x2FFF
-    */
-    let (top_left, top_right, bottom_left, bottom_right) =
-        match (&mirroring, ppu.ctrl.name_table_addr()) {
-            (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) => {
-                (vram_a, vram_b, vram_a, vram_b)
-            }
-
-            (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) => {
-                (vram_b, vram_a, vram_b, vram_a)
-            }
-
-            (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
-                (vram_a, vram_a, vram_b, vram_b)
-            }
-
-            (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
-                (vram_b, vram_b, vram_a, vram_a)
-            }
-
-            (_, _) => {
-                panic!("Not supported mirroring type {:?}", mirroring);
-            }
-        };
-
-    //画面左上
-    render_name_table(
-        ppu,
-        frame,
-        top_left,
-        Rect::new(scroll_x, scroll_y, SCREEN_W, SCREEN_H),
-        -(scroll_x as isize),
-        -(scroll_y as isize),
-        &draw_rect,
-    );
-
-    //画面右上
-    render_name_table(
-        ppu,
-        frame,
-        top_right,
-        Rect::new(0, scroll_y, scroll_x, SCREEN_H),
-        (SCREEN_W - scroll_x) as isize,
-        -(scroll_y as isize),
-        &draw_rect,
-    );
-
-    //画面左下
-    render_name_table(
-        ppu,
-        frame,
-        bottom_left,
-        Rect::new(scroll_x, 0, SCREEN_W, scroll_y),
-        -(scroll_x as isize),
-        (SCREEN_H - scroll_y) as isize,
-        &draw_rect,
-    );
-
-    //画面右下
-    render_name_table(
-        ppu,
-        frame,
-        bottom_right,
-        Rect::new(0, 0, scroll_x, scroll_y),
-        (SCREEN_W - scroll_x) as isize,
-        (SCREEN_H - scroll_y) as isize,
-        &draw_rect,
-    );
-}
-fn draw_sprite(ppu: &NesPPU, frame: &mut Frame, draw_rect: &Rect) {
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        // OAMメモリからスプライト情報を取得
-        let tile_y = ppu.oam_data[i] as usize;
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let attr = ppu.oam_data[i + 2];
-        let tile_x = ppu.oam_data[i + 3] as usize;
-
-        let bank: u16 = ppu.ctrl.sprite_pattern_addr();
-
-        if ppu.ctrl.is_sprite_8x16_mode() {
-            // 8x16では各スプライトが上下の２つのタイルに分かれている
-
-            // tile_idxの最下位ビットが0なら0番目のタイル、1ならば1番目のタイル
-            let bank = if tile_idx & 0x01 == 0 { 0 } else { 0x1000 };
-
-            // (tile_idx & 0xFE) は偶数番号の場合はそのまま、奇数番号の場合は1引いた偶数
-            // 奇数番号のタイルはその前の偶数番号のタイルと共に同一のスプライトになる
-            let top = bank + (tile_idx & 0xFE) * 16;
-            let bottom = bank + ((tile_idx & 0xFE) + 1) * 16;
-
-            let flip_vertical = (attr >> 7 & 1) == 1;
-            // flip_verticalなら上下反転
-            if flip_vertical {
-                draw_tile(ppu, frame, bottom, tile_x, tile_y, attr, draw_rect);
-                draw_tile(ppu, frame, top, tile_x, tile_y + 8, attr, draw_rect);
-            } else {
-                draw_tile(ppu, frame, top, tile_x, tile_y, attr, draw_rect);
-                draw_tile(ppu, frame, bottom, tile_x, tile_y + 8, attr, draw_rect);
-            }
-        } else {
-            // bank + tile_idx * 16 これが最初の番地
-            // パターンテーブル上でのアドレスを計算している
-            let start = bank + tile_idx * 16;
-
-            draw_tile(ppu, frame, start, tile_x, tile_y, attr, draw_rect);
-        };
-    }
-}
-
-fn draw_tile(
-    ppu: &NesPPU,
-    frame: &mut Frame,
-    start: u16,
-    tile_x: usize,
-    tile_y: usize,
-    attr: u8,
-    draw_rect: &Rect,
-) {
-    // スプライトの属性情報attrから情報を抽出
-    let flip_vertical = (attr >> 7 & 1) == 1;
-    let flip_horizontal = (attr >> 6 & 1) == 1;
-    let palette_idx = attr & 0b11; //スプライトが使用するパレットを指定
-    let sprite_palette = sprite_palette(ppu, tile_y, palette_idx);
-
-    let mut tile: [u8; 16] = [0; 16];
-    for i in 0..=15 {
-        tile[i] = unsafe { MAPPER.read_chr_rom(start + i as u16) };
-    }
-
-    // 描画処理
-    for y in 0..=7 {
-        let mut upper = tile[y];
-        let mut lower = tile[y + 8];
-        'ololo: for x in (0..=7).rev() {
-            let value = (1 & lower) << 1 | (1 & upper);
-            upper = upper >> 1;
-            lower = lower >> 1;
-            let rgb = match value {
-                0 => continue 'ololo, //skip coloring the pixel
-                1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-                _ => panic!("cant be"),
-            };
-
-            let (_x, _y) = match (flip_horizontal, flip_vertical) {
-                (false, false) => (tile_x + x, tile_y + y),
-                (true, false) => (tile_x + 7 - x, tile_y + y),
-                (false, true) => (tile_x + x, tile_y + 7 - y),
-                (true, true) => (tile_x + 7 - x, tile_y + 7 - y),
-            };
-
-            if _x >= draw_rect.x1 && _x < draw_rect.x2 && _y >= draw_rect.y1 && _y < draw_rect.y2 {
-                frame.set_pixel(_x, _y, rgb);
-            }
-        }
-    }
-}
-
-fn bg_pallette(
-    ppu: &NesPPU,
-    attribute_table: &[u8],
-    tile_column: usize,
-    tile_row: usize,
-) -> [u8; 4] {
-    let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = attribute_table[attr_table_idx];
+use crate::ppu::{LineSprite, NesPPU};
+
+// 1ピクセル分の背景とスプライトを合成する
+// 返り値はパレットテーブルの位置(0x00~0x1F)と、スプライト0ヒットが起きたかどうか
+pub fn compose_pixel(ppu: &NesPPU, x: usize) -> (u8, bool) {
+    // 左端の8ピクセルは隠せる
+    let bg = if x < 8 && !ppu.mask.show_background_in_left() {
+        0
+    } else {
+        ppu.background_pixel()
+    };
+    let sprite = if x < 8 && !ppu.mask.show_sprites_in_left() {
+        None
+    } else {
+        sprite_pixel(ppu, x)
+    };
 
-    let pallet_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
-        (0, 0) => attr_byte & 0b11,
-        (1, 0) => (attr_byte >> 2) & 0b11,
-        (0, 1) => (attr_byte >> 4) & 0b11,
-        (1, 1) => (attr_byte >> 6) & 0b11,
-        _ => panic!("should not happen"),
+    let (sprite, value) = match sprite {
+        Some(s) => s,
+        None => return (bg, false),
     };
 
-    let pallette_start: usize = 1 + (pallet_idx as usize) * 4;
-    let p = ppu.read_palette_table(tile_row * 8);
-    [
-        p[0] & 0x3F, //64(0x3F)でマスクして255までのindexを64までにする
-        p[pallette_start] & 0x3F,
-        p[pallette_start + 1] & 0x3F,
-        p[pallette_start + 2] & 0x3F,
-    ]
-}
+    // 背景が透明でなければ、スプライトが背景の後ろにあってもヒットする (x=255では起きない)
+    let bg_opaque = bg & 0x03 != 0;
+    let sprite_zero_hit = sprite.index == 0 && bg_opaque && x != 255;
 
-pub fn sprite_palette(ppu: &NesPPU, tile_y: usize, palette_idx: u8) -> [u8; 4] {
-    let start = 0x11 + (palette_idx * 4) as usize;
-    let p = ppu.read_palette_table(tile_y);
-    [0, p[start] & 0x3F, p[start + 1] & 0x3F, p[start + 2] & 0x3F]
+    let behind_background = sprite.attr & 0x20 != 0;
+    let index = if behind_background && bg_opaque {
+        bg
+    } else {
+        0x10 | (sprite.attr & 0x03) << 2 | value
+    };
+    (index, sprite_zero_hit)
 }
 
-fn render_name_table(
-    ppu: &NesPPU,
-    frame: &mut Frame,
-    name_table: &[u8],
-    view_port: Rect,
-    shift_x: isize,
-    shift_y: isize,
-    draw_rect: &Rect,
-) {
-    let bank = ppu.ctrl.background_pattern_addr();
-    let attribute_table = &name_table[0x03C0..0x0400];
-
-    for i in 0..0x03C0 {
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile_idx = name_table[i] as u16;
-
-        //bank + tile_idx * 16 これが最初の番地
-        let start = bank + tile_idx * 16;
-        let mut tile: [u8; 16] = [0; 16];
-        for i in 0..=15 {
-            tile[i] = unsafe { MAPPER.read_chr_rom(start + i as u16) };
+// xで不透明なスプライトのうち番号が一番小さいもの
+// 背景の後ろにあるスプライトでも、それより番号の大きいスプライトは隠れる
+fn sprite_pixel(ppu: &NesPPU, x: usize) -> Option<(&LineSprite, u8)> {
+    if !ppu.mask.show_sprites() {
+        return None;
+    }
+    ppu.line_sprites.iter().find_map(|sprite| {
+        let column = x.wrapping_sub(sprite.x as usize);
+        if column >= 8 {
+            return None;
         }
-
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            for x in (0..=7).rev() {
-                //lowerを上位ビット、upperを下位ビット
-                //valueは00,01,10,11のどれか
-                let value = (1 & lower) << 1 | (1 & upper);
-
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[palette[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("cant be"),
-                };
-
-                let pixel_x = tile_column * 8 + x;
-                let pixel_y = tile_row * 8 + y;
-
-                if pixel_x >= view_port.x1
-                    && pixel_x < view_port.x2
-                    && pixel_y >= view_port.y1
-                    && pixel_y < view_port.y2
-                {
-                    let x = (shift_x + pixel_x as isize) as usize;
-                    let y = (shift_y + pixel_y as isize) as usize;
-                    if x >= draw_rect.x1
-                        && x < draw_rect.x2
-                        && y >= draw_rect.y1
-                        && y < draw_rect.y2
-                    {
-                        frame.set_pixel(x, y, rgb)
-                    }
-                }
-            }
+        let bit = 0x80 >> column;
+        let value =
+            ((sprite.pattern_hi & bit != 0) as u8) << 1 | (sprite.pattern_lo & bit != 0) as u8;
+        if value == 0 {
+            None
+        } else {
+            Some((sprite, value))
         }
-    }
+    })
 }
@@ -3,7 +3,26 @@
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
-    FOUR_SCREEN,
+    FOUR_SCREEN,     //カートリッジに2kBのVRAMがあり4画面とも別になる
+    SINGLE_SCREEN_A, //4画面ともCIRAMの前半
+    SINGLE_SCREEN_B, //4画面ともCIRAMの後半
+    // 4画面($2000,$2400,$2800,$2C00)それぞれに1kBのページを割り当てる
+    // 0,1はCIRAM 2,3はカートリッジのVRAM
+    QUADRANTS([u8; 4]),
+}
+
+impl Mirroring {
+    // 4画面それぞれが使う1kBのページ
+    pub fn pages(&self) -> [u8; 4] {
+        match self {
+            Mirroring::VERTICAL => [0, 1, 0, 1],
+            Mirroring::HORIZONTAL => [0, 0, 1, 1],
+            Mirroring::FOUR_SCREEN => [0, 1, 2, 3],
+            Mirroring::SINGLE_SCREEN_A => [0, 0, 0, 0],
+            Mirroring::SINGLE_SCREEN_B => [1, 1, 1, 1],
+            Mirroring::QUADRANTS(pages) => *pages,
+        }
+    }
 }
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; //N E S ^Z
@@ -15,8 +34,11 @@ pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
+    pub submapper: u8, //NES 2.0のヘッダのときだけ 同じマッパーの中の基板やチップの違い
+    pub prg_ram_size: usize, //NES 2.0のヘッダのときだけ 0ならわからない
     pub screen_mirroring: Mirroring,
     pub is_chr_ram: bool,
+    pub battery: bool, //6番目のヘッダのbit1 バッテリーバックアップがある
 
     pub save_data: Vec<u8>,
     pub save_data_file: String,
@@ -35,6 +57,17 @@ impl Rom {
         // raw[6] >> 4 => 0000_1111
         // mapper = 1010_1111
 
+        // NES 2.0では8番目のヘッダの上位4bitがサブマッパー
+        let nes2 = raw[7] & 0b0000_1100 == 0b0000_1000;
+        let submapper = if nes2 { raw[8] >> 4 } else { 0 };
+        // 10番目のヘッダ 下位4bitがPRG-RAM、上位4bitがバッテリーバックアップのPRG-RAM (64 << n バイト)
+        let ram_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+        let prg_ram_size = if nes2 {
+            ram_size(raw[10] & 0x0F) + ram_size(raw[10] >> 4)
+        } else {
+            0
+        };
+
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
@@ -47,6 +80,7 @@ impl Rom {
         let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
 
         let skip_trainer = raw[6] & 0b100 != 0;
+        let battery = raw[6] & 0b10 != 0;
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
@@ -63,8 +97,11 @@ impl Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: chr_rom,
             mapper: mapper,
+            submapper,
+            prg_ram_size,
             screen_mirroring: screen_mirroring,
             is_chr_ram: chr_rom_size == 0,
+            battery,
             save_data: Vec::new(),
             save_data_file: String::from(""),
         })
@@ -75,8 +112,11 @@ impl Rom {
             prg_rom: vec![],
             chr_rom: vec![],
             mapper: 0,
+            submapper: 0,
+            prg_ram_size: 0,
             screen_mirroring: Mirroring::VERTICAL,
             is_chr_ram: false,
+            battery: false,
             save_data: Vec::new(),
             save_data_file: String::from(""),
         };
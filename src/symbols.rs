@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::ptr::addr_of_mut;
+
+use log::info;
+use once_cell::sync::Lazy;
+
+use crate::mapper::mapper;
+
+// トレースやデバッガでアドレスの代わりに表示するラベル
+// 対応している形式
+//  - FCEUXの.nl: <rom>.ram.nl (RAM) と <rom>.N.nl (16kBのPRGバンクN) 行は $C5F5#Label#コメント
+//  - ca65の.dbg: ld65 --dbgfile で出力したもの segのooffs(ファイル上の位置)からバンクを決める
+//  - label=addr: 1行に1つ Label=$C5F5 バンクを指定するときは Label=N:$8123 (Nは.nlと同じ16kB単位)
+//
+// $8000以上のラベルはバンクごとにPRG-ROM上の位置で管理して、そのバンクが見えているときだけ使う
+
+static mut SYMBOLS: Lazy<Symbols> = Lazy::new(Symbols::new);
+
+pub unsafe fn symbols() -> &'static mut Symbols {
+    &mut *addr_of_mut!(SYMBOLS)
+}
+
+const NL_BANK_SIZE: usize = 0x4000; //16kB
+const INES_HEADER_SIZE: usize = 16;
+
+pub struct Symbols {
+    // RAMやI/Oとバンクを指定していないROMのラベル
+    global: HashMap<u16, String>,
+    // バンクを指定したROMのラベル キーはPRG-ROM上の位置
+    banked: HashMap<usize, (u16, String)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            global: HashMap::new(),
+            banked: HashMap::new(),
+        }
+    }
+
+    // 拡張子で形式を決める
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let before = self.global.len() + self.banked.len();
+
+        if path.ends_with(".nl") {
+            self.load_nl(&text, nl_bank(path));
+        } else if path.ends_with(".dbg") {
+            self.load_dbg(&text);
+        } else {
+            self.load_labels(&text);
+        }
+
+        info!(
+            "symbols: {} ({} labels)",
+            path,
+            self.global.len() + self.banked.len() - before
+        );
+        Ok(())
+    }
+
+    // FCEUXと同じようにROMの隣にある.nlファイルを全部読む
+    pub fn load_nl_files(&mut self, rom_path: &str) {
+        let mut paths = vec![format!("{}.ram.nl", rom_path)];
+        for bank in 0..0x100 {
+            paths.push(format!("{}.{:X}.nl", rom_path, bank));
+        }
+        for path in paths.iter() {
+            if Path::new(path.as_str()).is_file() {
+                if let Err(e) = self.load(path) {
+                    info!("{}", e);
+                }
+            }
+        }
+    }
+
+    // 今のバンクで見えているラベル
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        if addr >= 0x8000 && !self.banked.is_empty() {
+            let offset = unsafe { mapper().prg_rom_addr(addr) };
+            if let Some(name) = self.name_at_rom(offset) {
+                return Some(name);
+            }
+        }
+        self.name_global(addr)
+    }
+
+    pub fn name_global(&self, addr: u16) -> Option<&str> {
+        self.global.get(&addr).map(|s| s.as_str())
+    }
+
+    pub fn name_at_rom(&self, offset: usize) -> Option<&str> {
+        self.banked.get(&offset).map(|(_, s)| s.as_str())
+    }
+
+    // ラベルからアドレスを探す バンクを指定したラベルはPRG-ROM上の位置も返す
+    pub fn find(&self, name: &str) -> Option<(u16, Option<usize>)> {
+        if let Some((addr, _)) = self.global.iter().find(|(_, n)| n.as_str() == name) {
+            return Some((*addr, None));
+        }
+        self.banked
+            .iter()
+            .find(|(_, (_, n))| n.as_str() == name)
+            .map(|(offset, (addr, _))| (*addr, Some(*offset)))
+    }
+
+    fn insert(&mut self, addr: u16, offset: Option<usize>, name: &str) {
+        if name.is_empty() {
+            return;
+        }
+        match offset {
+            Some(offset) if addr >= 0x8000 => {
+                self.banked.insert(offset, (addr, String::from(name)));
+            }
+            _ => {
+                self.global.insert(addr, String::from(name));
+            }
+        }
+    }
+
+    // $C5F5#Label#コメント ($0200/10#OAM# のような配列は先頭だけ使う)
+    fn load_nl(&mut self, text: &str, bank: Option<usize>) {
+        for line in text.lines() {
+            let line = match line.strip_prefix('$') {
+                Some(l) => l,
+                None => continue, //コメントの続きなど
+            };
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            let addr = addr.split('/').next().unwrap_or("");
+            if let Some(addr) = parse_hex(addr) {
+                let offset = bank.map(|b| b * NL_BANK_SIZE + (addr as usize & (NL_BANK_SIZE - 1)));
+                self.insert(addr, offset, name);
+            }
+        }
+    }
+
+    // seg id=1,name="CODE",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+    // sym id=0,name="Reset",addrsize=absolute,scope=0,def=1,val=0xC000,seg=1,type=lab
+    fn load_dbg(&mut self, text: &str) {
+        // セグメントのid => (開始アドレス, PRG-ROM上の位置)
+        let mut segments: HashMap<String, (u16, Option<usize>)> = HashMap::new();
+        let mut syms: Vec<HashMap<&str, &str>> = vec![];
+
+        for line in text.lines() {
+            let (kind, rest) = match line.split_once(|c: char| c.is_whitespace()) {
+                Some(x) => x,
+                None => continue,
+            };
+            let fields: HashMap<&str, &str> = rest
+                .split(',')
+                .filter_map(|f| f.split_once('='))
+                .map(|(k, v)| (k.trim(), v.trim().trim_matches('"')))
+                .collect();
+
+            match kind {
+                "seg" => {
+                    let id = fields.get("id").copied().unwrap_or("");
+                    let start = fields.get("start").and_then(|s| parse_hex(s)).unwrap_or(0);
+                    // ooffsはヘッダを含むファイル上の位置
+                    let offset = fields
+                        .get("ooffs")
+                        .and_then(|s| s.parse::<usize>().ok())
+                        .and_then(|o| o.checked_sub(INES_HEADER_SIZE));
+                    segments.insert(String::from(id), (start, offset));
+                }
+                "sym" => syms.push(fields),
+                _ => {}
+            }
+        }
+
+        for fields in syms.iter() {
+            // アドレスのラベルだけ使う (equは定数、impは定義元と重複する)
+            if fields.get("type") != Some(&"lab") {
+                continue;
+            }
+            let name = fields.get("name").copied().unwrap_or("");
+            let addr = match fields.get("val").and_then(|v| parse_hex(v)) {
+                Some(a) => a,
+                None => continue,
+            };
+            let offset = fields
+                .get("seg")
+                .and_then(|id| segments.get(*id))
+                .and_then(|(start, offset)| offset.map(|o| o + addr.wrapping_sub(*start) as usize));
+            self.insert(addr, offset, name);
+        }
+    }
+
+    // Label=$C5F5 または Label=N:$8123
+    fn load_labels(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some(x) => x,
+                None => continue,
+            };
+            let (bank, addr) = match value.split_once(':') {
+                Some((bank, addr)) => (usize::from_str_radix(bank.trim(), 16).ok(), addr),
+                None => (None, value),
+            };
+            if let Some(addr) = parse_hex(addr.trim()) {
+                let offset = bank.map(|b| b * NL_BANK_SIZE + (addr as usize & (NL_BANK_SIZE - 1)));
+                self.insert(addr, offset, name.trim());
+            }
+        }
+    }
+}
+
+// game.nes.3.nl => Some(3), game.nes.ram.nl => None
+fn nl_bank(path: &str) -> Option<usize> {
+    let stem = path.strip_suffix(".nl")?;
+    let (_, bank) = stem.rsplit_once('.')?;
+    usize::from_str_radix(bank, 16).ok()
+}
+
+// $C000, 0xC000, C000 のどれでもよい
+fn parse_hex(s: &str) -> Option<u16> {
+    let hex = s
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    u32::from_str_radix(hex, 16).ok().map(|v| v as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_nl() {
+        let mut symbols = Symbols::new();
+        symbols.load_nl(
+            "$0200/100#OAM#スプライト\n続きのコメント\n$C5F5#Reset#\n",
+            None,
+        );
+        assert_eq!(symbols.name_global(0x0200), Some("OAM"));
+        assert_eq!(symbols.name_global(0xC5F5), Some("Reset"));
+
+        // バンク3の$8123はPRG-ROMの3*16kB+$0123
+        symbols.load_nl("$8123#Banked#\n", Some(3));
+        assert_eq!(symbols.name_at_rom(3 * 0x4000 + 0x0123), Some("Banked"));
+        assert_eq!(symbols.name_global(0x8123), None);
+    }
+
+    #[test]
+    fn test_load_dbg() {
+        let mut symbols = Symbols::new();
+        symbols.load_dbg(
+            "seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0100,type=ro,ooffs=16400\n\
+             sym\tid=0,name=\"Reset\",addrsize=absolute,val=0xC010,seg=1,type=lab\n\
+             sym\tid=1,name=\"PPUCTRL\",addrsize=absolute,val=0x2000,type=equ\n\
+             sym\tid=2,name=\"Reset\",addrsize=absolute,val=0xC010,type=imp\n\
+             sym\tid=3,name=\"counter\",addrsize=zeropage,val=0x10,type=lab\n",
+        );
+        // ooffsはヘッダの16バイトを含む
+        assert_eq!(symbols.name_at_rom(0x4000 + 0x10), Some("Reset"));
+        assert_eq!(symbols.name_global(0x10), Some("counter"));
+        // 定数はラベルにしない
+        assert_eq!(symbols.name_global(0x2000), None);
+    }
+
+    #[test]
+    fn test_load_labels() {
+        let mut symbols = Symbols::new();
+        symbols.load_labels("; コメント\nPlayerX=$0300\nNmi = 1:$8000\n");
+        assert_eq!(symbols.name_global(0x0300), Some("PlayerX"));
+        assert_eq!(symbols.name_at_rom(0x4000), Some("Nmi"));
+        assert_eq!(symbols.find("Nmi"), Some((0x8000, Some(0x4000))));
+    }
+
+    #[test]
+    fn test_nl_bank() {
+        assert_eq!(nl_bank("game.nes.3.nl"), Some(3));
+        assert_eq!(nl_bank("game.nes.1F.nl"), Some(0x1F));
+        assert_eq!(nl_bank("game.nes.ram.nl"), None);
+    }
+}
@@ -0,0 +1,301 @@
+// テストROM(blargg, kevtrisなど)をウィンドウなしで実行して結果を表にする
+// cargo run --bin testrom -- [--frames N] [--record] rom/test1.nes rom/test2.nes ...
+//
+// 結果の判定方法は2種類
+//  - $6000プロトコル: $6001~$6003に$DE $B0 $61が書かれていれば、$6000が結果コード、$6004からASCIIのメッセージ
+//  - 画面: $6000プロトコルに対応していないROMは最後のフレームのハッシュを<rom>.hashと比較する
+//    (--recordを付けると現在のハッシュを<rom>.hashに保存する)
+#![allow(dead_code)]
+
+//モジュールのインポートはメインに書かなきゃいけない
+mod apu;
+mod bus;
+mod cartrige;
+mod cdl;
+mod cpu;
+mod frame;
+mod joypad;
+mod mapper;
+mod opscodes;
+mod palette;
+mod ppu;
+mod ppu_bus;
+mod render;
+mod rom;
+mod symbols;
+
+use std::cell::Cell;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
+
+use once_cell::sync::Lazy;
+
+use crate::mapper::{create_mapper, Mapper};
+use crate::rom::Rom;
+use apu::NesAPU;
+use bus::{Bus, Mem};
+use cartrige::load_rom;
+use cpu::CPU;
+use frame::Frame;
+use joypad::Joypad;
+use ppu::NesPPU;
+
+static mut MAPPER: Lazy<Box<dyn Mapper>> = Lazy::new(|| create_mapper(Rom::empty()));
+
+const DEFAULT_FRAMES: usize = 60 * 30; //30秒
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const MESSAGE_ADDR: u16 = 0x6004;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEED_RESET: u8 = 0x81;
+//$81が書かれてからリセットを押すまで待つフレーム数(100ms以上)
+const RESET_DELAY_FRAMES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Verdict {
+    Pass,
+    Fail,
+    Timeout,
+    Crash,
+    Unknown, //比較するハッシュがない
+}
+
+impl Verdict {
+    fn label(&self) -> &str {
+        match self {
+            Verdict::Pass => "pass",
+            Verdict::Fail => "FAIL",
+            Verdict::Timeout => "TIMEOUT",
+            Verdict::Crash => "CRASH",
+            Verdict::Unknown => "????",
+        }
+    }
+}
+
+struct TestResult {
+    rom: String,
+    verdict: Verdict,
+    frames: usize,
+    message: String,
+}
+
+fn main() {
+    // 音は出さないがNesAPUはSDLのオーディオデバイスを必要とするのでダミーのドライバを使う
+    if std::env::var("SDL_AUDIODRIVER").is_err() {
+        std::env::set_var("SDL_AUDIODRIVER", "dummy");
+    }
+
+    let mut max_frames = DEFAULT_FRAMES;
+    let mut record = false;
+    let mut roms: Vec<String> = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                max_frames = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--frames needs a number");
+            }
+            "--record" => record = true,
+            _ => roms.push(arg),
+        }
+    }
+
+    if roms.is_empty() {
+        eprintln!("usage: testrom [--frames N] [--record] <rom.nes>...");
+        std::process::exit(2);
+    }
+
+    let sdl_context = sdl2::init().unwrap();
+
+    let mut results: Vec<TestResult> = vec![];
+    for rom in roms.iter() {
+        let result = run_test(&sdl_context, rom, max_frames, record);
+        results.push(result);
+    }
+
+    print_table(&results);
+
+    let failed = results
+        .iter()
+        .filter(|r| r.verdict != Verdict::Pass && r.verdict != Verdict::Unknown)
+        .count();
+    if failed != 0 {
+        std::process::exit(1);
+    }
+}
+
+fn run_test(sdl_context: &sdl2::Sdl, path: &str, max_frames: usize, record: bool) -> TestResult {
+    let rom = load_rom(path);
+    let mapper = rom.mapper;
+    unsafe {
+        *MAPPER = create_mapper(rom);
+    }
+
+    let frames = Rc::new(Cell::new(0));
+    let frames_in_bus = frames.clone();
+
+    let mut apu = NesAPU::new(sdl_context);
+    apu.set_expansion(sdl_context, mapper);
+    let bus = Bus::new(
+        apu,
+        move |_ppu: &mut NesPPU, _joypad1: &mut Joypad, _frame: &Frame| {
+            frames_in_bus.set(frames_in_bus.get() + 1);
+        },
+    );
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+
+    // JAMや未実装の非公式命令はpanicするので、クラッシュとして扱う
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_until_done(&mut cpu, &frames, max_frames)
+    }));
+
+    let (verdict, message) = match outcome {
+        Ok(Some((code, message))) => {
+            let verdict = if code == 0 {
+                Verdict::Pass
+            } else {
+                Verdict::Fail
+            };
+            (verdict, format!("${:02X} {}", code, message))
+        }
+        Ok(None) if has_signature(&mut cpu) => (Verdict::Timeout, read_message(&mut cpu)),
+        Ok(None) => check_screen(path, cpu.bus.frame(), record),
+        Err(_) => (Verdict::Crash, String::from("panic")),
+    };
+
+    TestResult {
+        rom: String::from(path),
+        verdict,
+        frames: frames.get(),
+        message,
+    }
+}
+
+// $6000プロトコルで結果が出るまで実行する
+// 結果が出なかった場合はNone
+fn run_until_done(
+    cpu: &mut CPU,
+    frames: &Rc<Cell<usize>>,
+    max_frames: usize,
+) -> Option<(u8, String)> {
+    let mut last_frame = 0;
+    let mut reset_at: Option<usize> = None;
+
+    while frames.get() < max_frames {
+        cpu.step_with_callback(&mut |_| {});
+
+        // 毎命令見るのは重いので1フレームに1回確認する
+        if frames.get() == last_frame {
+            continue;
+        }
+        last_frame = frames.get();
+
+        if !has_signature(cpu) {
+            continue;
+        }
+
+        match cpu.mem_read(STATUS_ADDR) {
+            STATUS_RUNNING => {}
+            STATUS_NEED_RESET => match reset_at {
+                None => reset_at = Some(last_frame + RESET_DELAY_FRAMES),
+                Some(f) if f <= last_frame => {
+                    reset_at = None;
+                    cpu.reset();
+                }
+                Some(_) => {}
+            },
+            code => return Some((code, read_message(cpu))),
+        }
+    }
+    None
+}
+
+fn has_signature(cpu: &mut CPU) -> bool {
+    (0..3).all(|i| cpu.mem_read(SIGNATURE_ADDR + i) == SIGNATURE[i as usize])
+}
+
+fn read_message(cpu: &mut CPU) -> String {
+    let mut message = String::new();
+    let mut addr = MESSAGE_ADDR;
+    while addr < 0x8000 {
+        let c = cpu.mem_read(addr);
+        if c == 0 {
+            break;
+        }
+        message.push(c as char);
+        addr += 1;
+    }
+    // 表に収まるように改行はスペースにする
+    message.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// 画面のハッシュを<rom>.hashと比較する
+fn check_screen(path: &str, frame: &Frame, record: bool) -> (Verdict, String) {
+    let hash = format!("{:016X}", fnv1a(&frame.data));
+    let hash_file = String::from(path) + ".hash";
+
+    if record {
+        fs::write(&hash_file, &hash).expect("unable to write hash file");
+        return (Verdict::Pass, format!("recorded {}", hash));
+    }
+
+    if !Path::new(hash_file.as_str()).is_file() {
+        return (Verdict::Unknown, format!("screen {}", hash));
+    }
+
+    let expected = fs::read_to_string(&hash_file).expect("unable to read hash file");
+    if expected.trim().eq_ignore_ascii_case(&hash) {
+        (Verdict::Pass, format!("screen {}", hash))
+    } else {
+        (
+            Verdict::Fail,
+            format!("screen {} (expected {})", hash, expected.trim()),
+        )
+    }
+}
+
+// FNV-1a 64bit
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+fn print_table(results: &[TestResult]) {
+    let width = results
+        .iter()
+        .map(|r| r.rom.len())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+
+    println!("{:<8}{:>7}  {:<width$}  MESSAGE", "RESULT", "FRAMES", "ROM");
+    for r in results.iter() {
+        println!(
+            "{:<8}{:>7}  {:<width$}  {}",
+            r.verdict.label(),
+            r.frames,
+            r.rom,
+            r.message
+        );
+    }
+
+    let passed = results
+        .iter()
+        .filter(|r| r.verdict == Verdict::Pass)
+        .count();
+    println!("{}/{} passed", passed, results.len());
+}
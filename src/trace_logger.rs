@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use crate::cpu::{trace, Interrupt, CPU};
+use crate::mapper::mapper;
+
+// 実行した命令をnestestのログと同じ形式で記録する
+// cpu.run_with_callbackのcallbackから毎命令on_instructionを呼び出して使う
+//
+//  --trace FILE          ファイルに書き出す
+//  --trace-ring N        直前のN命令だけメモリに持っておき、panicしたときにstderrに出す
+//  --trace-pc ADDR-ADDR  PCがこの範囲のときだけ記録する
+//  --trace-bank N        PRG-ROMのバンクN(.nlと同じ16kB単位)を実行しているときだけ記録する
+//  --trace-nmi           NMIの処理中だけ記録する
+//  --trace-irq           IRQの処理中だけ記録する
+//  --trace-ppu           スキャンラインとドットを付ける
+//  --trace-cycles        CPUのサイクル数を付ける
+pub struct TraceLogger {
+    output: Output,
+    pc_range: Option<(u16, u16)>,
+    bank: Option<usize>,
+    context: Option<Interrupt>,
+    show_ppu: bool,
+    show_cycles: bool,
+
+    // 処理中の割り込みと、割り込みに入ったときのSP
+    interrupts: Vec<(Interrupt, u8)>,
+}
+
+enum Output {
+    File(BufWriter<File>, Instant), //最後に書き出した時刻
+    Ring(VecDeque<String>, usize),
+}
+
+const BANK_SIZE: usize = 0x4000; //16kB
+                                 // 落ちたり止まったりしたときのために、これくらいごとにファイルに書き出す
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl TraceLogger {
+    // --trace か --trace-ring がなければNone
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        let value = |name: &str| {
+            args.iter()
+                .skip_while(|a| a.as_str() != name)
+                .nth(1)
+                .map(|s| s.as_str())
+        };
+        let flag = |name: &str| args.iter().any(|a| a == name);
+
+        let output = if let Some(path) = value("--trace") {
+            let file = File::create(path).expect("unable to create trace file");
+            Output::File(BufWriter::new(file), Instant::now())
+        } else if let Some(n) = value("--trace-ring") {
+            let n: usize = n.parse().expect("--trace-ring needs a number");
+            if n == 0 {
+                panic!("--trace-ring needs a number greater than 0");
+            }
+            Output::Ring(VecDeque::with_capacity(n), n)
+        } else {
+            return None;
+        };
+
+        let pc_range = value("--trace-pc").map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            (parse_hex(start), parse_hex(end))
+        });
+        let bank = value("--trace-bank").map(|n| n.parse().expect("--trace-bank needs a number"));
+        let context = if flag("--trace-nmi") {
+            Some(Interrupt::Nmi)
+        } else if flag("--trace-irq") {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        };
+
+        Some(TraceLogger {
+            output,
+            pc_range,
+            bank,
+            context,
+            show_ppu: flag("--trace-ppu"),
+            show_cycles: flag("--trace-cycles"),
+            interrupts: vec![],
+        })
+    }
+
+    pub fn on_instruction(&mut self, cpu: &mut CPU) {
+        self.update_interrupts(cpu);
+
+        //callbackが呼ばれた時点でPCはopscodeの次を指している
+        let pc = cpu.program_counter.wrapping_sub(1);
+        if !self.is_target(pc) {
+            return;
+        }
+
+        let mut line = trace(cpu);
+        if self.show_ppu {
+            let ppu = cpu.bus.ppu();
+            line += &format!(" PPU:{:>3},{:>3}", ppu.scanline(), ppu.dot());
+        }
+        if self.show_cycles {
+            line += &format!(" CYC:{}", cpu.bus.cycles());
+        }
+
+        match &mut self.output {
+            Output::File(file, flushed) => {
+                writeln!(file, "{}", line).expect("unable to write trace");
+                // NMIを止めて無限ループしているときも残るように、時間で書き出す
+                if flushed.elapsed() >= FLUSH_INTERVAL {
+                    file.flush().unwrap();
+                    *flushed = Instant::now();
+                }
+            }
+            Output::Ring(lines, capacity) => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+        }
+    }
+
+    // 終了するときに呼ぶ
+    pub fn flush(&mut self) {
+        if let Output::File(file, _) = &mut self.output {
+            let _ = file.flush();
+        }
+    }
+
+    // panicしたときに呼ぶ
+    pub fn dump(&mut self) {
+        match &mut self.output {
+            Output::File(..) => self.flush(),
+            Output::Ring(lines, _) => {
+                eprintln!("---- last {} instructions ----", lines.len());
+                for line in lines.iter() {
+                    eprintln!("{}", line);
+                }
+            }
+        }
+    }
+
+    // RTIでSPが割り込みに入ったときより上に戻ったら割り込みの処理は終わり
+    fn update_interrupts(&mut self, cpu: &CPU) {
+        while let Some(&(_, sp)) = self.interrupts.last() {
+            if cpu.stack_pointer <= sp {
+                break;
+            }
+            self.interrupts.pop();
+        }
+        if let Some(interrupt) = cpu.interrupt {
+            self.interrupts.push((interrupt, cpu.stack_pointer));
+        }
+    }
+
+    fn is_target(&self, pc: u16) -> bool {
+        if let Some((start, end)) = self.pc_range {
+            if pc < start || end < pc {
+                return false;
+            }
+        }
+        if let Some(bank) = self.bank {
+            if pc < 0x8000 || unsafe { mapper().prg_rom_addr(pc) } / BANK_SIZE != bank {
+                return false;
+            }
+        }
+        if let Some(context) = self.context {
+            if !self.interrupts.iter().any(|(i, _)| *i == context) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn parse_hex(s: &str) -> u16 {
+    let hex = s.trim().trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).expect("--trace-pc needs ADDR-ADDR")
+}